@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tcp::options::TcpOptions;
+
+// Arbitrary bytes as the options area of a TCP header: decoding must never
+// panic, and whatever it decodes must survive an encode/decode round trip
+fuzz_target!(|data: &[u8]| {
+    let mut options = TcpOptions::parse(data);
+
+    // Only four SACK blocks fit in the option space
+    options.sack_blocks.truncate(4);
+
+    let encoded = options.encode();
+    assert_eq!(encoded.len() % 4, 0);
+    assert_eq!(TcpOptions::parse(&encoded), options);
+});
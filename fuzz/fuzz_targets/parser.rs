@@ -0,0 +1,34 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tcp::icmp;
+use tcp::parser::{self, Ipv4View, TcpView};
+
+// Arbitrary bytes as a received IPv4 datagram: parsing must never panic, and
+// the owned parser and the views must agree on what they accept
+fuzz_target!(|data: &[u8]| {
+    let _ = parser::verify_checksums(data);
+    let _ = icmp::parse_unreachable(data);
+
+    let packet = parser::parser(data);
+
+    let Ok(ip) = Ipv4View::new(data) else {
+        assert!(packet.is_err());
+        return;
+    };
+    assert!(ip.as_bytes().len() <= data.len());
+    assert_eq!(ip.as_bytes().len(), ip.total_len() as usize);
+
+    let Ok(tcp) = TcpView::new(ip.payload()) else {
+        assert!(packet.is_err());
+        return;
+    };
+    let _ = tcp.options();
+
+    if let Ok(packet) = packet {
+        assert_eq!(ip.protocol(), 6);
+        assert_eq!(packet.payload(), tcp.payload());
+        assert_eq!(packet.tcp_header.sequence_number, tcp.sequence_number());
+        assert_eq!(packet.tcp_header.options, tcp.options());
+    }
+});
@@ -0,0 +1,152 @@
+#![no_main]
+
+use std::net::Ipv4Addr;
+use std::task::Waker;
+use std::time::Duration;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use tcp::clock::ManualClock;
+use tcp::options::TcpOptions;
+use tcp::packet_sender::{Frames, SegmentBuilder};
+use tcp::stack::Stack;
+use tcp::tcb::{Quad, Tcb};
+
+const LOCAL: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 1), 80);
+const REMOTE: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 2), 5000);
+
+#[derive(Arbitrary, Debug)]
+enum Step {
+    /// Segment whose sequence and acknowledgment numbers are offsets from
+    /// RCV.NXT and SND.UNA, so most of them land near the windows
+    Segment {
+        seq: i16,
+        ack: i16,
+        flags: u8,
+        window: u16,
+        timestamps: Option<(u32, u32)>,
+        len: u16,
+    },
+    /// Segment with absolute sequence and acknowledgment numbers
+    Raw {
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        window: u16,
+        len: u16,
+    },
+    /// Queue data from the application
+    Write(u16),
+    /// Read received data into a buffer of this size
+    Read(u16),
+    /// Advance the clock by this many milliseconds and run expired timers
+    Timer(u16),
+    Transmit,
+}
+
+// Arbitrary segment sequences against a listening stack: no panics, and the
+// sequence spaces of every connection stay consistent after each step
+fuzz_target!(|steps: Vec<Step>| {
+    let clock = ManualClock::new();
+    let mut stack = Stack::with_clock(clock.clone());
+    stack.listen(LOCAL.1).unwrap();
+    let quad = Quad {
+        src: REMOTE,
+        dst: LOCAL,
+    };
+    let mut frames = Frames::new();
+
+    for step in steps {
+        match step {
+            Step::Segment {
+                seq,
+                ack,
+                flags,
+                window,
+                timestamps,
+                len,
+            } => {
+                let (rcv_nxt, snd_una) = stack
+                    .connections
+                    .get(&quad)
+                    .map_or((0, 0), |tcb| (tcb.rcv.nxt, tcb.snd.una));
+                let options = TcpOptions {
+                    timestamps,
+                    ..TcpOptions::default()
+                };
+                let seq = rcv_nxt.wrapping_add(seq as u32);
+                let ack = snd_una.wrapping_add(ack as u32);
+                stack.process_frame(
+                    &segment(seq, ack, flags, window, &options, len),
+                    &mut frames,
+                );
+            }
+            Step::Raw {
+                seq,
+                ack,
+                flags,
+                window,
+                len,
+            } => {
+                stack.process_frame(
+                    &segment(seq, ack, flags, window, &TcpOptions::default(), len),
+                    &mut frames,
+                );
+            }
+            Step::Write(len) => {
+                let _ = stack.poll_write(quad, &vec![0x5a; len as usize], Waker::noop());
+            }
+            Step::Read(len) => {
+                let _ = stack.poll_read(quad, &mut vec![0; len as usize], Waker::noop());
+            }
+            Step::Timer(millis) => {
+                clock.advance(Duration::from_millis(millis as u64));
+                stack.on_timer(&mut frames);
+            }
+            Step::Transmit => {
+                stack.poll_transmit(&mut frames);
+            }
+        }
+
+        frames.clear();
+
+        for tcb in stack.connections.values() {
+            check_invariants(tcb);
+        }
+    }
+});
+
+/// Frame from the peer carrying `len` bytes of payload, up to the MSS
+fn segment(seq: u32, ack: u32, flags: u8, window: u16, options: &TcpOptions, len: u16) -> Vec<u8> {
+    let payload = [0xa5; 1400];
+    let mut frame = vec![0u8; 1504];
+    frame[2..4].copy_from_slice(&0x0800u16.to_be_bytes());
+
+    let len = SegmentBuilder::new(REMOTE, LOCAL)
+        .seq(seq)
+        .ack(ack)
+        .flags(flags)
+        .window(window)
+        .options(options)
+        .payload(&payload[..len as usize % payload.len()])
+        .write(&mut frame[4..])
+        .unwrap();
+    frame.truncate(4 + len);
+    frame
+}
+
+fn check_invariants(tcb: &Tcb) {
+    // SND.UNA <= SND.NXT, and everything in flight is still buffered (plus
+    // the SYN and FIN, which take a sequence number each)
+    let in_flight = tcb.snd.nxt.wrapping_sub(tcb.snd.una) as usize;
+    assert!(
+        in_flight <= tcb.send_buffer.len() + 2,
+        "SND.UNA {} SND.NXT {} with {} bytes buffered",
+        tcb.snd.una,
+        tcb.snd.nxt,
+        tcb.send_buffer.len()
+    );
+
+    assert!(tcb.recv_buffer.len() <= tcb.recv_buffer.capacity());
+    assert!(tcb.send_buffer.len() <= tcb.send_buffer.capacity());
+}
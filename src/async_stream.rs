@@ -0,0 +1,318 @@
+use std::future::Future;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_io::{Async, Timer};
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_lite::future;
+
+use crate::packet_sender::Frames;
+use crate::stack::{HandleRef, SharedStack};
+use crate::tcb::Quad;
+use crate::trace::{Event, EventKind};
+
+/// Lets async-io register the TUN descriptor with its reactor
+struct TunDevice(tun_tap::Iface);
+
+impl AsFd for TunDevice {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The descriptor stays open for as long as the interface does
+        unsafe { BorrowedFd::borrow_raw(self.0.as_raw_fd()) }
+    }
+}
+
+/// Async driver task: reads frames from the TUN device, runs connection
+/// timers and transmits queued data. Spawn it on any executor; it only
+/// returns if the device fails.
+pub async fn drive(stack: SharedStack, iface: tun_tap::Iface) -> io::Result<()> {
+    let device = Async::new(TunDevice(iface))?;
+    let mut buf = [0u8; 1504];
+    let mut packets = Frames::new();
+
+    loop {
+        let timeout = stack
+            .lock()
+            .unwrap()
+            .next_timeout()
+            .unwrap_or(Duration::from_millis(100)); // Default 100ms if no timers
+        let mut timer = Timer::after(timeout);
+
+        // Wait for a frame, a timer, or work queued by the application
+        future::poll_fn(|cx| {
+            if stack.lock().unwrap().register_driver(cx.waker()) {
+                return Poll::Ready(());
+            }
+            if device.poll_readable(cx).is_ready() {
+                return Poll::Ready(());
+            }
+            Pin::new(&mut timer).poll(cx).map(|_| ())
+        })
+        .await;
+
+        {
+            let mut stack = stack.lock().unwrap();
+
+            loop {
+                match device.get_ref().0.recv(&mut buf[..]) {
+                    Ok(nbytes) => stack.process_frame(&buf[..nbytes], &mut packets),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            stack.on_timer(&mut packets);
+            stack.poll_transmit(&mut packets);
+        }
+
+        for packet in &packets {
+            if let Err(e) = device.get_ref().0.send(packet) {
+                let event = Event::new(EventKind::DeviceError)
+                    .field("operation", "send")
+                    .field("error", e.to_string());
+                stack.lock().unwrap().trace(event);
+            }
+        }
+        packets.clear();
+    }
+}
+
+/// A connection accepted from an `AsyncTcpListener`. Dropping the last clone
+/// closes it like `close` does.
+#[derive(Debug, Clone)]
+pub struct AsyncTcpStream {
+    stack: SharedStack,
+    quad: Quad,
+    _handle: Arc<HandleRef>,
+}
+
+impl AsyncTcpStream {
+    pub fn peer_addr(&self) -> (Ipv4Addr, u16) {
+        self.quad.src
+    }
+
+    pub fn local_addr(&self) -> (Ipv4Addr, u16) {
+        self.quad.dst
+    }
+
+    /// Probe the peer after `idle` without traffic and fail reads and writes
+    /// with `TimedOut` if it stops answering; `None` turns probes off
+    pub fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        self.stack.lock().unwrap().set_keepalive(self.quad, idle)
+    }
+
+    /// Hold at most `size` bytes of written data the peer has not
+    /// acknowledged; writes return `Pending` once that much is outstanding
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.stack
+            .lock()
+            .unwrap()
+            .set_send_buffer_size(self.quad, size)
+    }
+
+    pub(crate) fn quad(&self) -> Quad {
+        self.quad
+    }
+}
+
+impl AsyncRead for AsyncTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.stack
+            .lock()
+            .unwrap()
+            .poll_read(self.quad, buf, cx.waker())
+    }
+}
+
+impl AsyncWrite for AsyncTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.stack
+            .lock()
+            .unwrap()
+            .poll_write(self.quad, buf, cx.waker())
+    }
+
+    /// Written data is handed to the driver immediately, so there is nothing to flush
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Send a FIN once the data already written has gone out; reading goes
+    /// on until the peer closes its side
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.stack.lock().unwrap().close(self.quad))
+    }
+}
+
+/// Accepts connections established on a local port
+#[derive(Debug)]
+pub struct AsyncTcpListener {
+    stack: SharedStack,
+    port: u16,
+}
+
+impl AsyncTcpListener {
+    pub fn bind(stack: SharedStack, port: u16) -> io::Result<Self> {
+        stack.lock().unwrap().listen(port)?;
+        Ok(Self { stack, port })
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<AsyncTcpStream>> {
+        match self
+            .stack
+            .lock()
+            .unwrap()
+            .poll_accept(self.port, cx.waker())
+        {
+            Some(quad) => Poll::Ready(Ok(AsyncTcpStream {
+                stack: self.stack.clone(),
+                quad,
+                _handle: HandleRef::new(self.stack.clone(), quad),
+            })),
+            None => Poll::Pending,
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<AsyncTcpStream> {
+        future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+}
+
+impl Drop for AsyncTcpListener {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            stack.unlisten(self.port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::task::{Wake, Waker};
+
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::stack::tests::{
+        establish, quiet_stack, receive, segment, sent, transmit, LOCAL, REMOTE_ISN,
+    };
+
+    const PORT: u16 = 50000;
+
+    /// A stream accepted over a handshake fed straight into the stack,
+    /// with the stack's ISN
+    fn accept() -> (SharedStack, AsyncTcpStream, u32) {
+        let stack = Arc::new(Mutex::new(quiet_stack()));
+        let listener = AsyncTcpListener::bind(stack.clone(), LOCAL.1).unwrap();
+        let iss = establish(&mut stack.lock().unwrap(), PORT);
+        let stream = future::block_on(listener.accept()).unwrap();
+        (stack, stream, iss)
+    }
+
+    #[test]
+    fn peer_fin_ends_the_stream() {
+        let (stack, mut stream, iss) = accept();
+        let fin = segment(PORT, REMOTE_ISN + 1, iss + 1, 0x19, b"hello");
+        let ack = receive(&mut stack.lock().unwrap(), &fin);
+        assert_eq!(sent(&ack[0]).acknowledge_number(), REMOTE_ISN + 7);
+
+        let mut data = Vec::new();
+        future::block_on(stream.read_to_end(&mut data)).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn close_sends_fin_after_written_data() {
+        let (stack, mut stream, iss) = accept();
+        future::block_on(async {
+            stream.write_all(b"data").await?;
+            stream.close().await
+        })
+        .unwrap();
+
+        let frames = transmit(&mut stack.lock().unwrap());
+        let segments: Vec<_> = frames.iter().map(|frame| sent(frame)).collect();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].payload(), b"data");
+        assert_eq!(segments[1].control_bit(), 0x11);
+        assert_eq!(segments[1].sequence_number(), iss + 5);
+
+        // The peer acknowledges the FIN and closes too; reads see the end
+        let fin = segment(PORT, REMOTE_ISN + 1, iss + 6, 0x11, &[]);
+        receive(&mut stack.lock().unwrap(), &fin);
+        let mut buf = [0u8; 16];
+        assert_eq!(future::block_on(stream.read(&mut buf)).unwrap(), 0);
+    }
+
+    /// Records whether it was woken
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn full_send_buffer_makes_writes_pending_until_acknowledged() {
+        let (stack, mut stream, iss) = accept();
+        stream.set_send_buffer_size(8).unwrap();
+        let flag = Arc::new(Flag::default());
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let written = Pin::new(&mut stream).poll_write(&mut cx, b"0123456789");
+        assert!(matches!(written, Poll::Ready(Ok(8))));
+        assert!(Pin::new(&mut stream)
+            .poll_write(&mut cx, b"89")
+            .is_pending());
+
+        let frames = transmit(&mut stack.lock().unwrap());
+        assert_eq!(sent(&frames[0]).payload(), b"01234567");
+        assert!(!flag.0.load(Ordering::SeqCst));
+        receive(
+            &mut stack.lock().unwrap(),
+            &segment(PORT, REMOTE_ISN + 1, iss + 9, 0x10, &[]),
+        );
+
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert!(matches!(
+            Pin::new(&mut stream).poll_write(&mut cx, b"89"),
+            Poll::Ready(Ok(2))
+        ));
+    }
+
+    #[test]
+    fn dropping_the_last_handle_closes() {
+        let (stack, stream, iss) = accept();
+        let clone = stream.clone();
+
+        drop(stream);
+        assert!(transmit(&mut stack.lock().unwrap()).is_empty());
+
+        drop(clone);
+        let frames = transmit(&mut stack.lock().unwrap());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(sent(&frames[0]).control_bit(), 0x11);
+        assert_eq!(sent(&frames[0]).sequence_number(), iss + 1);
+    }
+}
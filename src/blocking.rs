@@ -0,0 +1,442 @@
+use std::io::{self, Read, Write};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Poll, Wake, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::async_stream;
+use crate::stack::{HandleRef, SharedStack, Stack};
+use crate::tcb::Quad;
+
+/// Run the stack's event loop on a background thread, returning the shared
+/// stack to open listeners on and the thread's handle
+pub fn spawn(iface: tun_tap::Iface) -> io::Result<(SharedStack, JoinHandle<io::Result<()>>)> {
+    let stack = Arc::new(Mutex::new(Stack::new()));
+    let driver_stack = stack.clone();
+
+    let handle = thread::Builder::new()
+        .name("tcp-stack".to_string())
+        .spawn(move || async_io::block_on(async_stream::drive(driver_stack, iface)))?;
+
+    Ok((stack, handle))
+}
+
+/// Waker that signals a condition variable waited on with the stack lock,
+/// so a blocked thread sleeps until its connection's buffers change. Clones
+/// of a handle share one, so the stack keeps a single waker for all the
+/// threads blocked on them and wakes every one.
+#[derive(Debug, Default)]
+struct CondvarWaker(Condvar);
+
+impl Wake for CondvarWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.notify_all();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.notify_all();
+    }
+}
+
+/// Block on `poll` until it is ready or `timeout` passes, sleeping on the
+/// condition variable between attempts. In non-blocking mode `poll` is
+/// tried once and `WouldBlock` returned if it is not ready.
+fn block_on_stack<T>(
+    stack: &Mutex<Stack>,
+    condvar: &Arc<CondvarWaker>,
+    nonblocking: bool,
+    timeout: Option<Duration>,
+    mut poll: impl FnMut(&mut Stack, &Waker) -> Poll<io::Result<T>>,
+) -> io::Result<T> {
+    if nonblocking {
+        return match poll(&mut stack.lock().unwrap(), Waker::noop()) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        };
+    }
+
+    let waker = Waker::from(condvar.clone());
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let mut guard = stack.lock().unwrap();
+    loop {
+        if let Poll::Ready(result) = poll(&mut guard, &waker) {
+            return result;
+        }
+
+        guard = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                condvar.0.wait_timeout(guard, remaining).unwrap().0
+            }
+            None => condvar.0.wait(guard).unwrap(),
+        };
+    }
+}
+
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(())
+}
+
+/// Blocking, thread-safe handle to a connection accepted from a `TcpListener`.
+/// Dropping the last clone closes it like `shutdown` does.
+#[derive(Debug, Clone)]
+pub struct TcpStream {
+    stack: SharedStack,
+    quad: Quad,
+    _handle: Arc<HandleRef>,
+    condvar: Arc<CondvarWaker>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nonblocking: bool,
+}
+
+impl TcpStream {
+    /// Open a connection from `local` to `remote` (address, port) through a
+    /// stack whose event loop runs elsewhere, e.g. started by `spawn`, and
+    /// block until the handshake completes
+    pub fn connect(
+        stack: SharedStack,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+    ) -> io::Result<Self> {
+        let quad = stack.lock().unwrap().open(local, remote)?;
+        let stream = Self::new(stack, quad);
+        block_on_stack(
+            &stream.stack,
+            &stream.condvar,
+            false,
+            None,
+            |stack, waker| stack.poll_connect(quad, waker),
+        )?;
+        Ok(stream)
+    }
+
+    fn new(stack: SharedStack, quad: Quad) -> Self {
+        Self {
+            _handle: HandleRef::new(stack.clone(), quad),
+            stack,
+            quad,
+            condvar: Arc::default(),
+            read_timeout: None,
+            write_timeout: None,
+            nonblocking: false,
+        }
+    }
+
+    pub fn peer_addr(&self) -> (Ipv4Addr, u16) {
+        self.quad.src
+    }
+
+    pub fn local_addr(&self) -> (Ipv4Addr, u16) {
+        self.quad.dst
+    }
+
+    /// Fail reads with `TimedOut` after blocking for `timeout`; `None` blocks forever
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Fail writes with `TimedOut` after blocking for `timeout`; `None` blocks forever
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        self.write_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    /// In non-blocking mode reads and writes fail with `WouldBlock` instead
+    /// of waiting; pair with a `Poller` to learn when to retry
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// Probe the peer after `idle` without traffic and fail reads and writes
+    /// with `TimedOut` if it stops answering; `None` turns probes off
+    pub fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        self.stack.lock().unwrap().set_keepalive(self.quad, idle)
+    }
+
+    /// Hold at most `size` bytes of written data the peer has not
+    /// acknowledged; writes block once that much is outstanding
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.stack
+            .lock()
+            .unwrap()
+            .set_send_buffer_size(self.quad, size)
+    }
+
+    /// Close the write side: data already written is sent, followed by a
+    /// FIN. Reads return what the peer still sends until it closes too.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.stack.lock().unwrap().close(self.quad)
+    }
+
+    pub(crate) fn quad(&self) -> Quad {
+        self.quad
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let quad = self.quad;
+        block_on_stack(
+            &self.stack,
+            &self.condvar,
+            self.nonblocking,
+            self.read_timeout,
+            |stack, waker| stack.poll_read(quad, buf, waker),
+        )
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let quad = self.quad;
+        block_on_stack(
+            &self.stack,
+            &self.condvar,
+            self.nonblocking,
+            self.write_timeout,
+            |stack, waker| stack.poll_write(quad, buf, waker),
+        )
+    }
+
+    /// Written data is handed to the stack thread immediately, so there is nothing to flush
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Blocking listener accepting connections established on a local port
+#[derive(Debug)]
+pub struct TcpListener {
+    stack: SharedStack,
+    port: u16,
+    condvar: Arc<CondvarWaker>,
+    nonblocking: bool,
+}
+
+impl TcpListener {
+    pub fn bind(stack: SharedStack, port: u16) -> io::Result<Self> {
+        stack.lock().unwrap().listen(port)?;
+        Ok(Self {
+            stack,
+            port,
+            condvar: Arc::default(),
+            nonblocking: false,
+        })
+    }
+
+    /// In non-blocking mode `accept` fails with `WouldBlock` instead of waiting
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Block until a connection is established on the port
+    pub fn accept(&self) -> io::Result<TcpStream> {
+        let quad = block_on_stack(
+            &self.stack,
+            &self.condvar,
+            self.nonblocking,
+            None,
+            |stack, waker| match stack.poll_accept(self.port, waker) {
+                Some(quad) => Poll::Ready(Ok(quad)),
+                None => Poll::Pending,
+            },
+        )?;
+
+        Ok(TcpStream::new(self.stack.clone(), quad))
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            stack.unlisten(self.port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack::tests::{
+        establish, quiet_stack, receive, remote, segment, sent, transmit, LOCAL, REMOTE_ISN,
+    };
+
+    const PORT: u16 = 50000;
+
+    /// A stream accepted over a handshake fed straight into the stack,
+    /// with the stack's ISN
+    fn accept() -> (SharedStack, TcpStream, u32) {
+        let stack = Arc::new(Mutex::new(quiet_stack()));
+        let listener = TcpListener::bind(stack.clone(), LOCAL.1).unwrap();
+        let iss = establish(&mut stack.lock().unwrap(), PORT);
+        let stream = listener.accept().unwrap();
+        (stack, stream, iss)
+    }
+
+    #[test]
+    fn blocked_read_wakes_at_peer_fin() {
+        let (stack, mut stream, iss) = accept();
+        let peer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let fin = segment(PORT, REMOTE_ISN + 1, iss + 1, 0x19, b"bye");
+            receive(&mut stack.lock().unwrap(), &fin);
+        });
+
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"bye");
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_sends_fin_and_reads_continue() {
+        let (stack, mut stream, iss) = accept();
+        stream.write_all(b"data").unwrap();
+        stream.shutdown().unwrap();
+        assert_eq!(
+            stream.write(b"more").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+
+        let frames = transmit(&mut stack.lock().unwrap());
+        let fin = sent(frames.last().unwrap());
+        assert_eq!(fin.control_bit(), 0x11);
+        assert_eq!(fin.sequence_number(), iss + 5);
+
+        // FIN-WAIT-2 still delivers the peer's data, then the end of stream
+        let ack = segment(PORT, REMOTE_ISN + 1, iss + 6, 0x10, &[]);
+        receive(&mut stack.lock().unwrap(), &ack);
+        let data = segment(PORT, REMOTE_ISN + 1, iss + 6, 0x19, b"reply");
+        receive(&mut stack.lock().unwrap(), &data);
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"reply");
+    }
+
+    #[test]
+    fn full_send_buffer_blocks_writes_until_acknowledged() {
+        let (stack, mut stream, iss) = accept();
+        stream.set_send_buffer_size(8).unwrap();
+        assert_eq!(stream.write(b"0123456789").unwrap(), 8);
+
+        stream
+            .set_write_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        assert_eq!(
+            stream.write(b"89").unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+        stream.set_write_timeout(None).unwrap();
+
+        let peer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut stack = stack.lock().unwrap();
+            let frames = transmit(&mut stack);
+            assert_eq!(sent(&frames[0]).payload(), b"01234567");
+            receive(
+                &mut stack,
+                &segment(PORT, REMOTE_ISN + 1, iss + 9, 0x10, &[]),
+            );
+        });
+
+        // Blocks until the peer's ACK drains the buffer
+        assert_eq!(stream.write(b"89").unwrap(), 2);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn every_blocked_reader_wakes() {
+        let (stack, stream, iss) = accept();
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let mut stream = stream.clone();
+                thread::spawn(move || {
+                    let mut data = Vec::new();
+                    stream.read_to_end(&mut data).map(|_| data)
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(50));
+        let fin = segment(PORT, REMOTE_ISN + 1, iss + 1, 0x19, b"bye");
+        receive(&mut stack.lock().unwrap(), &fin);
+
+        let mut data = Vec::new();
+        for reader in readers {
+            data.extend(reader.join().unwrap().unwrap());
+        }
+        assert_eq!(data, b"bye");
+    }
+
+    /// The SYN of a connection being opened, once the event loop would send it
+    fn wait_for_syn(stack: &SharedStack) -> u32 {
+        loop {
+            if let Some(frame) = transmit(&mut stack.lock().unwrap()).first() {
+                let syn = sent(frame);
+                assert_eq!(syn.control_bit(), 0x02);
+                return syn.sequence_number();
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn connect_blocks_until_established() {
+        let stack = Arc::new(Mutex::new(quiet_stack()));
+        let connecting = stack.clone();
+        let client = thread::spawn(move || TcpStream::connect(connecting, LOCAL, remote(PORT)));
+
+        let iss = wait_for_syn(&stack);
+        let syn_ack = segment(PORT, REMOTE_ISN, iss + 1, 0x12, &[]);
+        let ack = receive(&mut stack.lock().unwrap(), &syn_ack);
+        assert_eq!(sent(&ack[0]).acknowledge_number(), REMOTE_ISN + 1);
+
+        let mut stream = client.join().unwrap().unwrap();
+        assert_eq!(stream.peer_addr(), remote(PORT));
+        stream.write_all(b"hello").unwrap();
+        let frames = transmit(&mut stack.lock().unwrap());
+        assert_eq!(sent(&frames[0]).payload(), b"hello");
+    }
+
+    #[test]
+    fn refused_connect_fails() {
+        let stack = Arc::new(Mutex::new(quiet_stack()));
+        let connecting = stack.clone();
+        let client = thread::spawn(move || TcpStream::connect(connecting, LOCAL, remote(PORT)));
+
+        let iss = wait_for_syn(&stack);
+        let rst = segment(PORT, 0, iss + 1, 0x14, &[]);
+        receive(&mut stack.lock().unwrap(), &rst);
+
+        let error = client.join().unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
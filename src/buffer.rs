@@ -0,0 +1,169 @@
+/// Fixed-capacity byte ring buffer backing a connection's receive and send queues
+#[derive(Debug, Clone)]
+pub struct RingBuffer {
+    storage: Vec<u8>,
+
+    /// Index of the oldest byte
+    head: usize,
+
+    /// Number of bytes currently stored
+    len: usize,
+}
+
+impl RingBuffer {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            storage: vec![0u8; capacity],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.storage.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Space left before the buffer is full
+    pub fn free(&self) -> usize {
+        self.capacity() - self.len
+    }
+
+    /// Append as much of `data` as fits, returning the number of bytes stored
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.free());
+        let capacity = self.capacity();
+
+        for (i, byte) in data[..n].iter().enumerate() {
+            self.storage[(self.head + self.len + i) % capacity] = *byte;
+        }
+
+        self.len += n;
+        n
+    }
+
+    /// Copy bytes starting `offset` bytes past the head into `buf` without
+    /// consuming them, returning the number of bytes copied
+    pub fn peek(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.len.saturating_sub(offset));
+        let capacity = self.capacity();
+
+        for (i, byte) in buf[..n].iter_mut().enumerate() {
+            *byte = self.storage[(self.head + offset + i) % capacity];
+        }
+
+        n
+    }
+
+    /// Drop up to `n` bytes from the head, returning the number dropped
+    pub fn consume(&mut self, n: usize) -> usize {
+        let n = n.min(self.len);
+        if n > 0 {
+            self.head = (self.head + n) % self.capacity();
+            self.len -= n;
+        }
+        n
+    }
+
+    /// Move bytes from the head into `buf`, returning the number of bytes read
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = self.peek(0, buf);
+        self.consume(n)
+    }
+
+    /// Change the capacity, keeping the contents; the buffer never shrinks
+    /// below what it currently holds
+    pub fn resize(&mut self, capacity: usize) {
+        let capacity = capacity.max(self.len);
+        if capacity == self.capacity() {
+            return;
+        }
+
+        let mut storage = vec![0u8; capacity];
+        self.peek(0, &mut storage[..self.len]);
+        self.storage = storage;
+        self.head = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Buffer of `capacity` whose head sits `offset` bytes in, so writes wrap
+    fn offset_buffer(capacity: usize, offset: usize) -> RingBuffer {
+        let mut buffer = RingBuffer::with_capacity(capacity);
+        buffer.write(&vec![0; offset]);
+        buffer.consume(offset);
+        buffer
+    }
+
+    #[test]
+    fn writes_stop_at_capacity() {
+        let mut buffer = RingBuffer::with_capacity(4);
+        assert_eq!(buffer.write(b"abc"), 3);
+        assert_eq!(buffer.free(), 1);
+        assert_eq!(buffer.write(b"def"), 1);
+        assert_eq!((buffer.len(), buffer.free()), (4, 0));
+        assert_eq!(buffer.write(b"g"), 0);
+
+        let mut out = [0u8; 8];
+        assert_eq!(buffer.read(&mut out), 4);
+        assert_eq!(&out[..4], b"abcd");
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.free(), 4);
+    }
+
+    #[test]
+    fn contents_wrap_around_the_end() {
+        let mut buffer = offset_buffer(8, 6);
+        assert_eq!(buffer.write(b"abcdefgh"), 8);
+
+        let mut out = [0u8; 4];
+        assert_eq!(buffer.peek(1, &mut out), 4);
+        assert_eq!(&out, b"bcde");
+        assert_eq!(buffer.peek(6, &mut out), 2);
+        assert_eq!(&out[..2], b"gh");
+        assert_eq!(buffer.peek(9, &mut out), 0);
+
+        assert_eq!(buffer.consume(3), 3);
+        assert_eq!(buffer.write(b"ijkl"), 3);
+        let mut out = [0u8; 8];
+        assert_eq!(buffer.read(&mut out), 8);
+        assert_eq!(&out, b"defghijk");
+    }
+
+    #[test]
+    fn consume_stops_at_the_stored_bytes() {
+        let mut buffer = offset_buffer(4, 3);
+        buffer.write(b"ab");
+        assert_eq!(buffer.consume(5), 2);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.consume(1), 0);
+    }
+
+    #[test]
+    fn resize_keeps_wrapped_contents() {
+        let mut buffer = offset_buffer(4, 3);
+        buffer.write(b"abc");
+
+        buffer.resize(8);
+        assert_eq!((buffer.capacity(), buffer.free()), (8, 5));
+        buffer.write(b"de");
+
+        // Shrinking stops at what the buffer holds
+        buffer.resize(2);
+        assert_eq!((buffer.capacity(), buffer.len()), (5, 5));
+
+        let mut out = [0u8; 8];
+        assert_eq!(buffer.read(&mut out), 5);
+        assert_eq!(&out[..5], b"abcde");
+    }
+}
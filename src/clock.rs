@@ -0,0 +1,63 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Source of time for a `Stack`: every timer, deadline and timestamp is
+/// measured against it, so swapping it for a `ManualClock` makes timer
+/// behaviour reproducible.
+pub trait Clock: fmt::Debug + Send {
+    /// Current time; it must never go backwards
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic system clock, used in production
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a
+/// test or simulation keeps one while the stack owns another.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    /// Clock stopped at the current monotonic time
+    pub fn new() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    pub fn starting_at(now: Instant) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Move the clock to `now`; earlier times are ignored
+    pub fn set(&self, now: Instant) {
+        let mut current = self.now.lock().unwrap();
+        *current = (*current).max(now);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,54 @@
+use std::net::Ipv4Addr;
+
+use crate::tcb::Quad;
+
+/// ICMP destination unreachable message quoting a TCP segment we sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unreachable {
+    pub code: u8,
+
+    /// Connection the quoted segment belongs to, keyed like received segments
+    pub quad: Quad,
+
+    /// Sequence number of the quoted segment
+    pub seq: u32,
+}
+
+/// Parse an IPv4 packet carrying an ICMP destination unreachable (type 3).
+/// The message quotes the original IP header and at least the first 8 bytes
+/// of its payload, which for TCP hold the ports and sequence number
+/// (RFC 792, RFC 1122 Section 3.2.2).
+pub fn parse_unreachable(buffer: &[u8]) -> Option<Unreachable> {
+    let ihl = ((*buffer.first()? & 0x0F) as usize) * 4;
+    if buffer.len() < 20 || ihl < 20 || buffer[9] != 1 {
+        return None;
+    }
+
+    let icmp = buffer.get(ihl..)?;
+    if icmp.len() < 8 || icmp[0] != 3 {
+        return None;
+    }
+    let code = icmp[1];
+
+    // Quoted header of the packet we sent
+    let quoted = &icmp[8..];
+    let quoted_ihl = ((*quoted.first()? & 0x0F) as usize) * 4;
+    if quoted.len() < 20 || quoted_ihl < 20 || quoted[9] != 6 {
+        return None;
+    }
+    let tcp = quoted.get(quoted_ihl..quoted_ihl + 8)?;
+
+    let local = Ipv4Addr::new(quoted[12], quoted[13], quoted[14], quoted[15]);
+    let remote = Ipv4Addr::new(quoted[16], quoted[17], quoted[18], quoted[19]);
+    let local_port = u16::from_be_bytes([tcp[0], tcp[1]]);
+    let remote_port = u16::from_be_bytes([tcp[2], tcp[3]]);
+
+    Some(Unreachable {
+        code,
+        quad: Quad {
+            src: (remote, remote_port),
+            dst: (local, local_port),
+        },
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+    })
+}
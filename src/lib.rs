@@ -0,0 +1,21 @@
+pub mod async_stream;
+pub mod blocking;
+pub mod buffer;
+pub mod clock;
+pub mod icmp;
+pub mod options;
+pub mod packet_sender;
+pub mod parser;
+pub mod pcap;
+pub mod poller;
+pub mod reactor;
+pub mod replay;
+pub mod script;
+pub mod sim;
+pub mod sniffer;
+pub mod stack;
+pub mod syncookie;
+pub mod tcb;
+pub mod tcp;
+pub mod timer_wheel;
+pub mod trace;
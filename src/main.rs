@@ -1,147 +1,183 @@
-use std::collections::HashMap;
 use std::io;
+use std::os::fd::BorrowedFd;
 use std::os::unix::io::AsRawFd;
-use std::time::{Duration, Instant};
-mod packet_sender;
-mod parser;
-mod sniffer;
-mod tcp;
-mod tcb;
+use std::time::Duration;
 
-use tcb::{Quad, Tcb, RetransmitAction};
+use tcp::packet_sender::Frames;
+use tcp::pcap::{Capture, Reader};
+use tcp::reactor::Reactor;
+use tcp::replay::Replay;
+use tcp::sniffer::{Filter, Sniffer};
+use tcp::stack::Stack;
+use tcp::trace::{Event, EventKind, JsonSink, Level, TextSink, Tracer};
+
+/// Reactor token of the TUN device
+const TUN: u64 = 0;
+
+/// Virtual time a replay runs on after the last packet, for the stack's
+/// retransmissions and timeouts to play out
+const REPLAY_LINGER: Duration = Duration::from_secs(60);
 
 fn main() -> io::Result<()> {
     println!("Hello TCP");
-    
-    let mut connections: HashMap<Quad, Tcb> = HashMap::new();
-    
+
+    // --capture FILE writes all traffic to a pcap, or pcapng if FILE ends in .pcapng;
+    // --replay FILE feeds a recorded capture through the stack instead of tun0;
+    // --filter EXPR selects the packets printed and captured;
+    // --log-level LEVEL and --log-json FILE choose which events are reported and where
+    let mut capture = None;
+    let mut replay = None;
+    let mut filter = None;
+    let mut level = Level::Info;
+    let mut log_json = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--capture", Some(path)) => capture = Some(path),
+            ("--replay", Some(path)) => replay = Some(path),
+            ("--filter", Some(expression)) => match Filter::parse(&expression) {
+                Ok(parsed) => filter = Some(parsed),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    std::process::exit(2);
+                }
+            },
+            ("--log-level", Some(name)) => match name.parse() {
+                Ok(parsed) => level = parsed,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    std::process::exit(2);
+                }
+            },
+            ("--log-json", Some(path)) => log_json = Some(path),
+            _ => {
+                eprintln!("usage: tcp [--capture FILE] [--replay FILE] [--filter EXPR] [--log-level LEVEL] [--log-json FILE]");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let mut sniffer = Sniffer::new();
+    let mut capture = capture.map(Capture::create).transpose()?;
+    if let Some(filter) = filter {
+        sniffer = sniffer.with_filter(filter.clone());
+        capture = capture.map(|capture| capture.with_filter(filter));
+    }
+
+    let tracer = match log_json {
+        Some(path) => Tracer::new(JsonSink::create(path)?, level),
+        None => Tracer::new(TextSink::stdout(), level),
+    };
+
+    if let Some(path) = replay {
+        return run_replay(&mut Reader::open(path)?, tracer, sniffer, capture);
+    }
+
+    let mut stack = Stack::new();
+    stack.set_tracer(tracer);
+    stack.set_sniffer(Some(sniffer));
+    stack.set_capture(capture);
+
     let new_interface = tun_tap::Iface::new("tun0", tun_tap::Mode::Tun)?;
     let mut buf = [0u8; 1504];
-    
+    let mut packets = Frames::new();
+
     // Set the interface to non-blocking mode
     let fd = new_interface.as_raw_fd();
     unsafe {
         let flags = libc::fcntl(fd, libc::F_GETFL, 0);
         libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
     }
-    
-    let mut last_check = Instant::now();
-    
+
+    let reactor = Reactor::new()?;
+    // The descriptor stays open for as long as the interface does
+    reactor.register(unsafe { BorrowedFd::borrow_raw(fd) }, TUN)?;
+    let mut tokens = Vec::new();
+
     loop {
-        // Calculate timeout for next retransmission check
-        let timeout = connections
-            .values()
-            .filter_map(|tcb| tcb.time_until_retransmit())
-            .min()
-            .unwrap_or(Duration::from_millis(100)); // Default 100ms if no timers
-        
-        // Use select to wait for data with timeout
-        let mut read_fds = unsafe {
-            let mut fds: libc::fd_set = std::mem::zeroed();
-            libc::FD_ZERO(&mut fds);
-            libc::FD_SET(fd, &mut fds);
-            fds
-        };
-        
-        let mut timeout_val = libc::timeval {
-            tv_sec: timeout.as_secs() as libc::time_t,
-            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
-        };
-        
-        let result = unsafe {
-            libc::select(
-                fd + 1,
-                &mut read_fds,
-                std::ptr::null_mut(),
-                std::ptr::null_mut(),
-                &mut timeout_val,
-            )
-        };
-        
-        if result > 0 {
-            // Data available to read
-            match new_interface.recv(&mut buf[..]) {
-                Ok(nbytes) => {
-                    let _flags = u16::from_be_bytes([buf[0], buf[1]]);
-                    let proto = u16::from_be_bytes([buf[2], buf[3]]);
-
-                    if proto != 0x0800 {
-                        continue;
-                    }
+        // Sleep until a frame arrives or the earliest connection timer is due.
+        // Interrupted waits already return empty; any other error would only
+        // repeat on the next iteration
+        if let Err(e) = reactor.wait(&mut tokens, stack.next_timeout()) {
+            device_error(&mut stack, "wait", &e);
+            return Err(e);
+        }
 
-                    if let Some(packet) = parser::parser(&buf[4..nbytes]) {
-                        packet.ip_header.sniffer();
-                        
-                        if packet.ip_header.protocol == 6 {
-                            let quad = Quad {
-                                src: (packet.ip_header.source, packet.tcp_header.source_port),
-                                dst: (packet.ip_header.destination, packet.tcp_header.destination_port),
-                            };
-                            
-                            let state = tcp::State::check_state(packet.tcp_header.control_bit);
-                            println!(
-                                "Received {}: SEQ={}, ACK={} from {}:{}",
-                                state,
-                                packet.tcp_header.sequence_number,
-                                packet.tcp_header.acknowledge_number,
-                                packet.ip_header.source,
-                                packet.tcp_header.source_port
-                            );
-
-                            let to_send_packet = tcp::State::tcp_connection(&state, &packet, &mut connections, quad);
-
-                            if to_send_packet[4] != 0 {
-                                match new_interface.send(&to_send_packet) {
-                                    Ok(bytes) => println!("Sent {} bytes", bytes),
-                                    Err(e) => eprintln!("Error sending packet: {}", e),
-                                }
-                            }
-                        }
+        if tokens.contains(&TUN) {
+            // Drain every frame that is ready
+            loop {
+                match new_interface.recv(&mut buf[..]) {
+                    Ok(nbytes) => {
+                        stack.process_frame(&buf[..nbytes], &mut packets);
+                        send(&mut stack, &new_interface, &mut packets);
                     }
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    // This shouldn't happen after select, but handle it anyway
-                }
-                Err(e) => {
-                    eprintln!("Error receiving packet: {}", e);
-                }
-            }
-        } else if result == 0 {
-            // Timeout occurred - check for retransmissions
-            let now = Instant::now();
-            if now.duration_since(last_check) >= Duration::from_millis(10) {
-                last_check = now;
-                
-                let actions = tcp::State::check_retransmissions(&mut connections);
-                
-                for (quad, action) in actions {
-                    match action {
-                        RetransmitAction::Retransmit { seq, flags, data, attempt } => {
-                            println!("⟳ Retransmitting SEQ={} (attempt #{})", seq, attempt);
-                            
-                            if let Some(tcb) = connections.get(&quad) {
-                                let packet = tcp::State::create_retransmit_packet(
-                                    &quad, seq, flags, data, tcb
-                                );
-                                
-                                if packet[4] != 0 {
-                                    match new_interface.send(&packet) {
-                                        Ok(bytes) => println!("Retransmitted {} bytes", bytes),
-                                        Err(e) => eprintln!("Error retransmitting: {}", e),
-                                    }
-                                }
-                            }
-                        }
-                        RetransmitAction::GiveUp { seq, reason } => {
-                            println!("✗ Giving up on SEQ={}: {}", seq, reason);
-                            // Could remove connection here
-                        }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        device_error(&mut stack, "recv", &e);
+                        break;
                     }
                 }
             }
-        } else {
-            // Error occurred
-            eprintln!("Select error: {}", io::Error::last_os_error());
+        }
+
+        // Retransmissions, probes and delayed ACKs that are due
+        stack.on_timer(&mut packets);
+        send(&mut stack, &new_interface, &mut packets);
+
+        // Send whatever queued data the windows now allow
+        stack.poll_transmit(&mut packets);
+        send(&mut stack, &new_interface, &mut packets);
+
+        report_trace_error(&mut stack);
+    }
+}
+
+/// Send `packets` on the device and clear them, reporting frames it refused
+fn send(stack: &mut Stack, iface: &tun_tap::Iface, packets: &mut Frames) {
+    for packet in &*packets {
+        if let Err(e) = iface.send(packet) {
+            device_error(stack, "send", &e);
         }
     }
+    packets.clear();
+}
+
+fn device_error(stack: &mut Stack, operation: &str, error: &io::Error) {
+    stack.trace(
+        Event::new(EventKind::DeviceError)
+            .field("operation", operation)
+            .field("error", error.to_string()),
+    );
+}
+
+/// The stack stops tracing when the log cannot be written; say why
+fn report_trace_error(stack: &mut Stack) {
+    if let Some(error) = stack.take_trace_error() {
+        eprintln!("Stopping trace: {}", error);
+    }
+}
+
+/// Feed a recorded capture through the stack on virtual time, without tun0
+fn run_replay(reader: &mut Reader, tracer: Tracer, sniffer: Sniffer, capture: Option<Capture>) -> io::Result<()> {
+    let mut replay = Replay::new();
+    replay.stack_mut().set_tracer(tracer);
+    replay.stack_mut().set_sniffer(Some(sniffer));
+    if let Some(capture) = capture {
+        replay.set_capture(capture);
+    }
+
+    let result = replay.run(&mut *reader).and_then(|()| replay.finish(REPLAY_LINGER));
+    report_trace_error(replay.stack_mut());
+    result?;
+
+    let statistics = replay.statistics();
+    println!(
+        "Replayed {} datagrams over {:?}: {} ignored, {} skipped, {} frames sent",
+        statistics.replayed,
+        replay.elapsed(),
+        statistics.ignored,
+        reader.skipped(),
+        statistics.sent
+    );
+    Ok(())
 }
@@ -0,0 +1,223 @@
+use std::time::Instant;
+
+// TCP options (RFC 9293 Section 3.2, RFC 7323, RFC 2018)
+//
+//     Kind  Length  Meaning
+//     ----  ------  -------
+//      0      -     End of option list
+//      1      -     No-Operation
+//      2      4     Maximum Segment Size
+//      3      3     Window Scale
+//      4      2     SACK Permitted
+//      5    8n+2    SACK blocks
+//      8     10     Timestamps (TSval, TSecr)
+
+/// Options carried by a TCP segment; unknown options are skipped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+
+    /// Blocks of data received out of order, as (left edge, right edge)
+    pub sack_blocks: Vec<(u32, u32)>,
+
+    /// Timestamps option (TSval, TSecr)
+    pub timestamps: Option<(u32, u32)>,
+}
+
+const END_OF_OPTIONS: u8 = 0;
+const NO_OPERATION: u8 = 1;
+const MSS: u8 = 2;
+const WINDOW_SCALE: u8 = 3;
+const SACK_PERMITTED: u8 = 4;
+const SACK: u8 = 5;
+const TIMESTAMPS: u8 = 8;
+
+impl TcpOptions {
+    /// Encode the options, each aligned with NOPs the way common stacks
+    /// lay them out, padded to a multiple of 4 bytes
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+
+        if let Some(mss) = self.mss {
+            buffer.extend_from_slice(&[MSS, 4]);
+            buffer.extend_from_slice(&mss.to_be_bytes());
+        }
+        if self.sack_permitted {
+            buffer.extend_from_slice(&[NO_OPERATION, NO_OPERATION, SACK_PERMITTED, 2]);
+        }
+        if let Some((tsval, tsecr)) = self.timestamps {
+            buffer.extend_from_slice(&[NO_OPERATION, NO_OPERATION, TIMESTAMPS, 10]);
+            buffer.extend_from_slice(&tsval.to_be_bytes());
+            buffer.extend_from_slice(&tsecr.to_be_bytes());
+        }
+        if let Some(shift) = self.window_scale {
+            buffer.extend_from_slice(&[NO_OPERATION, WINDOW_SCALE, 3, shift]);
+        }
+        if !self.sack_blocks.is_empty() {
+            // At most 4 blocks fit in the 40 bytes of option space
+            let blocks = &self.sack_blocks[..self.sack_blocks.len().min(4)];
+            buffer.extend_from_slice(&[
+                NO_OPERATION,
+                NO_OPERATION,
+                SACK,
+                2 + 8 * blocks.len() as u8,
+            ]);
+            for (left, right) in blocks {
+                buffer.extend_from_slice(&left.to_be_bytes());
+                buffer.extend_from_slice(&right.to_be_bytes());
+            }
+        }
+
+        buffer.resize(buffer.len().next_multiple_of(4), END_OF_OPTIONS);
+        buffer
+    }
+
+    /// Decode the options area of a TCP header (the bytes between the fixed
+    /// header and the data). Decoding stops at the first malformed option,
+    /// keeping what was decoded before it.
+    pub fn parse(mut buffer: &[u8]) -> Self {
+        let mut options = TcpOptions::default();
+
+        while let Some(&kind) = buffer.first() {
+            match kind {
+                END_OF_OPTIONS => break,
+                NO_OPERATION => {
+                    buffer = &buffer[1..];
+                    continue;
+                }
+                _ => {}
+            }
+
+            let Some(&len) = buffer.get(1) else {
+                break;
+            };
+            let len = len as usize;
+            if len < 2 || len > buffer.len() {
+                break;
+            }
+            let value = &buffer[2..len];
+
+            match (kind, value.len()) {
+                (MSS, 2) => options.mss = Some(u16::from_be_bytes([value[0], value[1]])),
+                (WINDOW_SCALE, 1) => options.window_scale = Some(value[0]),
+                (SACK_PERMITTED, 0) => options.sack_permitted = true,
+                (SACK, n) if n.is_multiple_of(8) => {
+                    options.sack_blocks = value
+                        .chunks_exact(8)
+                        .map(|block| {
+                            (
+                                u32::from_be_bytes([block[0], block[1], block[2], block[3]]),
+                                u32::from_be_bytes([block[4], block[5], block[6], block[7]]),
+                            )
+                        })
+                        .collect();
+                }
+                (TIMESTAMPS, 8) => {
+                    options.timestamps = Some((
+                        u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                        u32::from_be_bytes([value[4], value[5], value[6], value[7]]),
+                    ));
+                }
+                _ => {} // Unknown option, or a known one with the wrong length
+            }
+
+            buffer = &buffer[len..];
+        }
+
+        options
+    }
+}
+
+/// Millisecond clock for the timestamps option (RFC 7323 Section 5.4),
+/// counting from `origin`
+pub fn timestamp(origin: Instant, now: Instant) -> u32 {
+    now.saturating_duration_since(origin).as_millis() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_options() -> TcpOptions {
+        TcpOptions {
+            mss: Some(1460),
+            window_scale: Some(7),
+            sack_permitted: true,
+            sack_blocks: vec![(1000, 2000)],
+            timestamps: Some((12345, 678)),
+        }
+    }
+
+    #[test]
+    fn encoded_options_decode_to_themselves() {
+        for options in [TcpOptions::default(), all_options()] {
+            let encoded = options.encode();
+            assert_eq!(encoded.len() % 4, 0);
+            assert_eq!(TcpOptions::parse(&encoded), options);
+        }
+
+        let syn = TcpOptions {
+            mss: Some(536),
+            window_scale: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            syn.encode(),
+            [MSS, 4, 2, 24, NO_OPERATION, WINDOW_SCALE, 3, 0]
+        );
+        assert_eq!(TcpOptions::parse(&syn.encode()), syn);
+    }
+
+    #[test]
+    fn at_most_four_sack_blocks_are_encoded() {
+        let options = TcpOptions {
+            sack_blocks: (0..5).map(|i| (i * 100, i * 100 + 50)).collect(),
+            ..Default::default()
+        };
+        let encoded = options.encode();
+        assert!(encoded.len() <= 40);
+        assert_eq!(
+            TcpOptions::parse(&encoded).sack_blocks,
+            options.sack_blocks[..4]
+        );
+    }
+
+    #[test]
+    fn unknown_and_misshapen_options_are_skipped() {
+        let buffer = [
+            &[30, 4, 0xab, 0xcd][..], // Unknown kind
+            &[MSS, 3, 5],             // MSS with a one-byte value
+            &[WINDOW_SCALE, 3, 9],    // Window scale after them still decodes
+            &[SACK, 6, 0, 0, 0, 1],   // SACK with half a block
+        ]
+        .concat();
+        let options = TcpOptions::parse(&buffer);
+        assert_eq!(options.mss, None);
+        assert_eq!(options.window_scale, Some(9));
+        assert!(options.sack_blocks.is_empty());
+    }
+
+    #[test]
+    fn malformed_lengths_stop_decoding() {
+        // Length below the two bytes of kind and length
+        let options = TcpOptions::parse(&[WINDOW_SCALE, 3, 4, SACK_PERMITTED, 1, MSS, 4, 5, 180]);
+        assert_eq!(options.window_scale, Some(4));
+        assert!(!options.sack_permitted);
+        assert_eq!(options.mss, None);
+
+        // Length running past the end of the options
+        let options = TcpOptions::parse(&[SACK_PERMITTED, 2, TIMESTAMPS, 10, 0, 0, 0, 1]);
+        assert!(options.sack_permitted);
+        assert_eq!(options.timestamps, None);
+
+        // Kind without a length byte
+        let options = TcpOptions::parse(&[NO_OPERATION, MSS]);
+        assert_eq!(options, TcpOptions::default());
+
+        // Nothing is read past the end of options
+        let options = TcpOptions::parse(&[END_OF_OPTIONS, MSS, 4, 5, 180]);
+        assert_eq!(options.mss, None);
+    }
+}
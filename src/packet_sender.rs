@@ -1,124 +1,435 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::ops::Index;
+
+use crate::options::TcpOptions;
 use crate::parser::Packet;
 
 impl Packet {
-    pub fn create_packet(&self) -> [u8; 1504] {
-        let mut packet = [0u8; 1504];
-        let mut offset = 0;
+    /// Serialize into a tun frame at the start of `frame`, returning the
+    /// frame's length. Fails if the frame cannot hold the payload and options.
+    pub fn create_packet(&self, frame: &mut [u8]) -> Result<usize, BufferTooSmall> {
+        let ip = &self.ip_header;
+        let tcp = &self.tcp_header;
+        let builder = SegmentBuilder::new((ip.source, tcp.source_port), (ip.destination, tcp.destination_port))
+            .type_of_service(ip.type_of_service)
+            .identification(ip.identification)
+            .ip_flags(ip.flags)
+            .fragment_offset(ip.fragment_offset)
+            .ttl(ip.ttl)
+            .seq(tcp.sequence_number)
+            .ack(tcp.acknowledge_number)
+            .flags(tcp.control_bit)
+            .window(tcp.window)
+            .urgent_pointer(tcp.urgent_pointer)
+            .options(&tcp.options)
+            .payload(self.payload());
+        if frame.len() < 4 + builder.datagram_len() {
+            return Err(BufferTooSmall {
+                needed: 4 + builder.datagram_len(),
+                available: frame.len(),
+            });
+        }
 
         let flags_bytes = 0x0000u16.to_be_bytes(); // [0x00, 0x00]
         let proto_bytes = 0x0800u16.to_be_bytes(); // [0x08, 0x00]
-        packet[0..2].copy_from_slice(&flags_bytes);
-        packet[2..4].copy_from_slice(&proto_bytes);
-        offset += 4;
-
-        packet[offset] = (self.ip_header.version << 4) | (self.ip_header.ihl & 0x0F);
-        offset += 1;
-        packet[offset] = self.ip_header.type_of_service;
-        offset += 1;
-        packet[offset..offset + 2].copy_from_slice(&self.ip_header.total_len.to_be_bytes());
-        offset += 2;
-        packet[offset..offset + 2].copy_from_slice(&self.ip_header.identification.to_be_bytes());
-        offset += 2;
-
-        let flags_fragment =
-            ((self.ip_header.flags as u16) << 13) | (self.ip_header.fragment_offset & 0x1FFF);
-        packet[offset..offset + 2].copy_from_slice(&flags_fragment.to_be_bytes());
-        offset += 2;
-
-        packet[offset] = self.ip_header.ttl;
-        offset += 1;
-        packet[offset] = self.ip_header.protocol;
-        offset += 1;
-        packet[offset..offset + 2].copy_from_slice(&0u16.to_be_bytes()); // temporary zero for checksum
-        let checksum_pos = offset;
-        offset += 2;
-
-        packet[offset..offset + 4].copy_from_slice(&self.ip_header.source.octets());
-        offset += 4;
-        packet[offset..offset + 4].copy_from_slice(&self.ip_header.destination.octets());
-        offset += 4;
-
-        // Now calculate and insert IP checksum
-        let ip_checksum = Self::calculate_checksum(&packet[4..24]);
-        packet[checksum_pos..checksum_pos + 2].copy_from_slice(&ip_checksum.to_be_bytes());
-
-        // Store TCP header start position
-        let tcp_start = offset;
-
-        packet[offset..offset + 2].copy_from_slice(&self.tcp_header.source_port.to_be_bytes());
-        offset += 2;
-        packet[offset..offset + 2].copy_from_slice(&self.tcp_header.destination_port.to_be_bytes());
-        offset += 2;
-        packet[offset..offset + 4].copy_from_slice(&self.tcp_header.sequence_number.to_be_bytes());
-        offset += 4;
-        packet[offset..offset + 4]
-            .copy_from_slice(&self.tcp_header.acknowledge_number.to_be_bytes());
-        offset += 4;
-
-        let data_offset_reserved =
-            (self.tcp_header.data_offset << 4) | (self.tcp_header.reserved & 0x0F);
-        packet[offset] = data_offset_reserved;
-        offset += 1;
-        packet[offset] = self.tcp_header.control_bit;
-        offset += 1;
-
-        packet[offset..offset + 2].copy_from_slice(&self.tcp_header.window.to_be_bytes());
-        offset += 2;
-        let tcp_checksum_pos = offset;
-        packet[offset..offset + 2].copy_from_slice(&0u16.to_be_bytes()); // zero for checksum calculation
-        offset += 2;
-        packet[offset..offset + 2].copy_from_slice(&self.tcp_header.urgent_pointer.to_be_bytes());
-
-        let _data_len = 0;
-        let tcp_len = self.tcp_header.data_offset as usize * 4;
-
-        // Create pseudo header for TCP checksum
-        let pseudo_header = Self::create_pseudo_header(
-            &self.ip_header.source.octets(),
-            &self.ip_header.destination.octets(),
-            6,
-            tcp_len as u16,
-        );
-
-        // Build checksum input: pseudo header + TCP segment (with checksum = 0)
-        let mut checksum_input = Vec::new();
-        checksum_input.extend_from_slice(&pseudo_header);
-        checksum_input.extend_from_slice(&packet[tcp_start..tcp_start + tcp_len]);
-
-        let tcp_checksum = Self::calculate_checksum(&checksum_input);
-        packet[tcp_checksum_pos..tcp_checksum_pos + 2].copy_from_slice(&tcp_checksum.to_be_bytes());
-
-        packet
-    }
-
-    fn calculate_checksum(data: &[u8]) -> u16 {
-        let mut sum = 0u32;
-        let mut i = 0;
-
-        while i + 1 < data.len() {
-            let word = ((data[i] as u16) << 8) | (data[i + 1] as u16);
-            sum += word as u32;
-            i += 2;
+        frame[0..2].copy_from_slice(&flags_bytes);
+        frame[2..4].copy_from_slice(&proto_bytes);
+
+        Ok(4 + builder.write(&mut frame[4..])?)
+    }
+}
+
+/// Writes an IPv4 datagram carrying one TCP segment straight into a
+/// caller-supplied transmit buffer, checksums included, without copying the
+/// payload anywhere else first.
+///
+/// ```text
+/// let len = SegmentBuilder::new(local, remote)
+///     .seq(seq)
+///     .ack(ack)
+///     .flags(0x18)
+///     .payload(data)
+///     .write(&mut frame[4..])?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct SegmentBuilder<'a> {
+    source: (Ipv4Addr, u16),
+    destination: (Ipv4Addr, u16),
+    type_of_service: u8,
+    identification: u16,
+    ip_flags: u8,
+    fragment_offset: u16,
+    ttl: u8,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    urgent_pointer: u16,
+
+    /// Encoded options, a multiple of 4 bytes long
+    options: Vec<u8>,
+    payload: &'a [u8],
+}
+
+/// The transmit buffer cannot hold the datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferTooSmall {
+    pub needed: usize,
+    pub available: usize,
+}
+
+impl fmt::Display for BufferTooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "datagram needs {} bytes, buffer holds {}", self.needed, self.available)
+    }
+}
+
+impl std::error::Error for BufferTooSmall {}
+
+impl<'a> SegmentBuilder<'a> {
+    /// Segment from `source` to `destination` (address, port), with TTL 64
+    /// and Don't Fragment set
+    pub fn new(source: (Ipv4Addr, u16), destination: (Ipv4Addr, u16)) -> Self {
+        Self {
+            source,
+            destination,
+            type_of_service: 0,
+            identification: 0,
+            ip_flags: 0x02,
+            fragment_offset: 0,
+            ttl: 64,
+            seq: 0,
+            ack: 0,
+            flags: 0,
+            window: 0,
+            urgent_pointer: 0,
+            options: Vec::new(),
+            payload: &[],
         }
+    }
+
+    pub fn type_of_service(mut self, type_of_service: u8) -> Self {
+        self.type_of_service = type_of_service;
+        self
+    }
+
+    pub fn identification(mut self, identification: u16) -> Self {
+        self.identification = identification;
+        self
+    }
+
+    /// The 3-bit IP flags field
+    pub fn ip_flags(mut self, flags: u8) -> Self {
+        self.ip_flags = flags & 0x07;
+        self
+    }
 
-        if i < data.len() {
-            sum += ((data[i] as u16) << 8) as u32;
+    /// Fragment offset in 8-byte units (13 bits)
+    pub fn fragment_offset(mut self, offset: u16) -> Self {
+        self.fragment_offset = offset & 0x1FFF;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn seq(mut self, seq: u32) -> Self {
+        self.seq = seq;
+        self
+    }
+
+    pub fn ack(mut self, ack: u32) -> Self {
+        self.ack = ack;
+        self
+    }
+
+    /// TCP control bits
+    pub fn flags(mut self, flags: u8) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn window(mut self, window: u16) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn urgent_pointer(mut self, urgent_pointer: u16) -> Self {
+        self.urgent_pointer = urgent_pointer;
+        self
+    }
+
+    pub fn options(mut self, options: &TcpOptions) -> Self {
+        self.options = options.encode();
+        self
+    }
+
+    pub fn payload(mut self, payload: &'a [u8]) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Length of the datagram `write` produces
+    pub fn datagram_len(&self) -> usize {
+        40 + self.options.len() + self.payload.len()
+    }
+
+    /// Write the datagram at the start of `buffer`, returning its length
+    pub fn write(&self, buffer: &mut [u8]) -> Result<usize, BufferTooSmall> {
+        let total_len = self.datagram_len();
+        if total_len > buffer.len() || total_len > u16::MAX as usize {
+            return Err(BufferTooSmall {
+                needed: total_len,
+                available: buffer.len(),
+            });
         }
+        let tcp_len = 20 + self.options.len();
+
+        let (ip, segment) = buffer[..total_len].split_at_mut(20);
+        ip[0] = (4 << 4) | 5;
+        ip[1] = self.type_of_service;
+        ip[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+        ip[4..6].copy_from_slice(&self.identification.to_be_bytes());
+        let flags_fragment = ((self.ip_flags as u16) << 13) | self.fragment_offset;
+        ip[6..8].copy_from_slice(&flags_fragment.to_be_bytes());
+        ip[8] = self.ttl;
+        ip[9] = 6;
+        ip[10..12].copy_from_slice(&0u16.to_be_bytes()); // zero for checksum calculation
+        ip[12..16].copy_from_slice(&self.source.0.octets());
+        ip[16..20].copy_from_slice(&self.destination.0.octets());
+
+        let ip_checksum = calculate_checksum(ip);
+        ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+        segment[0..2].copy_from_slice(&self.source.1.to_be_bytes());
+        segment[2..4].copy_from_slice(&self.destination.1.to_be_bytes());
+        segment[4..8].copy_from_slice(&self.seq.to_be_bytes());
+        segment[8..12].copy_from_slice(&self.ack.to_be_bytes());
+        segment[12] = ((tcp_len / 4) as u8) << 4;
+        segment[13] = self.flags;
+        segment[14..16].copy_from_slice(&self.window.to_be_bytes());
+        segment[16..18].copy_from_slice(&0u16.to_be_bytes()); // zero for checksum calculation
+        segment[18..20].copy_from_slice(&self.urgent_pointer.to_be_bytes());
+        segment[20..tcp_len].copy_from_slice(&self.options);
+        segment[tcp_len..].copy_from_slice(self.payload);
+
+        let checksum = tcp_checksum(&self.source.0.octets(), &self.destination.0.octets(), segment);
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+        Ok(total_len)
+    }
+}
+
+/// Largest frame the stack writes: the 4-byte tun header and a datagram
+/// filling a 1500-byte MTU
+pub const MAX_FRAME: usize = 1504;
+
+/// Outgoing frames written back to back into one reusable transmit buffer.
+/// The stack appends to it and the event loop sends and clears it, so once
+/// the buffer has grown no frame is allocated or copied on its own.
+#[derive(Debug, Clone, Default)]
+pub struct Frames {
+    buffer: Vec<u8>,
+
+    /// Where each frame ends in `buffer`
+    ends: Vec<usize>,
+}
+
+impl Frames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
 
-        while (sum >> 16) != 0 {
-            sum = (sum & 0xFFFF) + (sum >> 16);
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    /// Drop every frame, keeping the buffer for the next batch
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.ends.clear();
+    }
+
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        let end = *self.ends.get(index)?;
+        let start = index.checked_sub(1).map_or(0, |previous| self.ends[previous]);
+        Some(&self.buffer[start..end])
+    }
+
+    pub fn first(&self) -> Option<&[u8]> {
+        self.get(0)
+    }
+
+    pub fn last(&self) -> Option<&[u8]> {
+        self.get(self.len().checked_sub(1)?)
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { frames: self, index: 0 }
+    }
+
+    /// Let `write` fill in a frame of up to `MAX_FRAME` bytes at the end of
+    /// the buffer, returning its length. A length of 0 appends nothing.
+    pub fn push_with(&mut self, write: impl FnOnce(&mut [u8]) -> usize) -> usize {
+        let Ok(len) = self.try_push_with(|frame| Ok::<_, Infallible>(write(frame)));
+        len
+    }
+
+    /// Like `push_with` for a `write` that can fail, appending nothing if it does
+    pub fn try_push_with<E>(&mut self, write: impl FnOnce(&mut [u8]) -> Result<usize, E>) -> Result<usize, E> {
+        let start = self.buffer.len();
+        self.buffer.resize(start + MAX_FRAME, 0);
+        let result = write(&mut self.buffer[start..]);
+
+        let len = *result.as_ref().unwrap_or(&0);
+        self.buffer.truncate(start + len);
+        if len > 0 {
+            self.ends.push(start + len);
+        }
+        result
+    }
+
+    /// Append a copy of `frame`
+    pub fn push(&mut self, frame: &[u8]) {
+        if !frame.is_empty() {
+            self.buffer.extend_from_slice(frame);
+            self.ends.push(self.buffer.len());
+        }
+    }
+
+    /// Move the frames of `other` to the end, leaving it empty
+    pub fn append(&mut self, other: &mut Frames) {
+        let offset = self.buffer.len();
+        self.buffer.append(&mut other.buffer);
+        self.ends.extend(other.ends.drain(..).map(|end| offset + end));
+    }
+}
+
+impl Index<usize> for Frames {
+    type Output = [u8];
+
+    fn index(&self, index: usize) -> &[u8] {
+        match self.get(index) {
+            Some(frame) => frame,
+            None => panic!("frame {} out of {}", index, self.len()),
+        }
+    }
+}
+
+/// Iterator over the frames in a `Frames` buffer
+#[derive(Debug, Clone)]
+pub struct Iter<'a> {
+    frames: &'a Frames,
+    index: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let frame = self.frames.get(self.index)?;
+        self.index += 1;
+        Some(frame)
+    }
+}
+
+impl<'a> IntoIterator for &'a Frames {
+    type Item = &'a [u8];
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Internet checksum (RFC 1071): the one's complement of the one's
+/// complement sum of `data` as 16-bit words. Over data that includes its own
+/// correct checksum it is zero.
+pub fn calculate_checksum(data: &[u8]) -> u16 {
+    fold(sum_words(0, data))
+}
+
+/// Checksum of a TCP segment between `src` and `dst`, covering the IPv4
+/// pseudo header (RFC 793 Section 3.1) without materializing it
+pub fn tcp_checksum(src: &[u8], dst: &[u8], segment: &[u8]) -> u16 {
+    let mut sum = sum_words(0, src);
+    sum = sum_words(sum, dst);
+    sum += 6; // Zero byte and protocol
+    sum += segment.len() as u32;
+    fold(sum_words(sum, segment))
+}
+
+/// Add `data` as big-endian 16-bit words to `sum`, padding an odd byte with zero
+fn sum_words(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Fold the carries back in and complement
+fn fold(mut sum: u32) -> u16 {
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `bytes` as a frame, the way a segment writer reports its length
+    fn frame(bytes: &[u8]) -> impl FnOnce(&mut [u8]) -> usize + '_ {
+        |buffer| {
+            buffer[..bytes.len()].copy_from_slice(bytes);
+            bytes.len()
         }
+    }
 
-        !(sum as u16)
+    #[test]
+    fn frames_keep_their_own_lengths() {
+        let mut frames = Frames::new();
+        assert_eq!(frames.push_with(frame(b"first")), 5);
+        assert_eq!(frames.push_with(frame(b"")), 0);
+        assert_eq!(frames.try_push_with(|_| Err::<usize, _>("too big")), Err("too big"));
+        frames.push(b"second");
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames.iter().collect::<Vec<_>>(), [&b"first"[..], b"second"]);
+        assert_eq!(frames.first(), Some(&b"first"[..]));
+        assert_eq!(frames.last(), Some(&b"second"[..]));
+        assert_eq!(frames.get(2), None);
     }
 
-    fn create_pseudo_header(src: &[u8], dst: &[u8], proto: u8, length: u16) -> Vec<u8> {
-        let mut header = Vec::new();
-        header.extend_from_slice(src);
-        header.extend_from_slice(dst);
-        header.push(0);
-        header.push(proto);
-        header.extend_from_slice(&length.to_be_bytes());
-        header
+    #[test]
+    fn appended_frames_follow_existing_ones() {
+        let mut frames = Frames::new();
+        frames.push(b"one");
+        let mut more = Frames::new();
+        more.push(b"two");
+        more.push(b"three");
+
+        frames.append(&mut more);
+        assert!(more.is_empty());
+        assert_eq!(frames.iter().collect::<Vec<_>>(), [&b"one"[..], b"two", b"three"]);
+
+        frames.clear();
+        assert!(frames.is_empty());
+        frames.push_with(frame(b"four"));
+        assert_eq!(&frames[0], b"four");
     }
 }
@@ -42,8 +42,13 @@
 
 //                    from rfc 793 -- tcp
 
+use std::fmt;
 use std::net::Ipv4Addr;
+
+use crate::options::TcpOptions;
+use crate::packet_sender::{calculate_checksum, tcp_checksum};
 #[allow(dead_code)]
+#[derive(Debug)]
 pub struct IPHeader {
     pub version: u8, //4 bits
     pub ihl: u8,     //4 bits
@@ -59,6 +64,7 @@ pub struct IPHeader {
     pub destination: Ipv4Addr,
 }
 #[allow(dead_code)]
+#[derive(Debug)]
 pub struct TCPHeader {
     pub source_port: u16,
     pub destination_port: u16,
@@ -70,82 +76,481 @@ pub struct TCPHeader {
     pub window: u16,
     pub checksum: u16,
     pub urgent_pointer: u16,
+    pub options: TcpOptions,
 }
+/// Largest IP datagram the interface carries
+pub const MTU: usize = 1500;
+
+/// Largest TCP payload that fits in one datagram without options, and so
+/// the largest a `Packet` can carry
+pub const MAX_PAYLOAD: usize = MTU - 40;
+
 #[allow(dead_code)]
+#[derive(Debug)]
 pub struct Packet {
     pub ip_header: IPHeader,
     pub tcp_header: TCPHeader,
-    pub data: [u8; 500],
+    pub data: [u8; MAX_PAYLOAD],
 }
 
-pub fn parser(buffer: &[u8]) -> Option<Packet> {
-    if buffer.len() < 20 {
-        return None;
-    }
-
-    // Parse IP Header
-    let ip_header = IPHeader {
-        version: (buffer[0] >> 4) & 0x0F, // First 4 bits
-        ihl: buffer[0] & 0x0F,            // Last 4 bits
-        type_of_service: buffer[1],
-        total_len: u16::from_be_bytes([buffer[2], buffer[3]]),
-        identification: u16::from_be_bytes([buffer[4], buffer[5]]),
-        flags: (buffer[6] >> 5) & 0x07, // 3 bits
-        fragment_offset: u16::from_be_bytes([buffer[6] & 0x1F, buffer[7]]), // 13 bits
-        ttl: buffer[8],
-        protocol: buffer[9],
-        header_checksum: u16::from_be_bytes([buffer[10], buffer[11]]),
-        source: Ipv4Addr::new(buffer[12], buffer[13], buffer[14], buffer[15]),
-        destination: Ipv4Addr::new(buffer[16], buffer[17], buffer[18], buffer[19]),
-    };
+impl Packet {
+    /// TCP payload, as delimited by the IP total length and header lengths
+    pub fn payload(&self) -> &[u8] {
+        let headers_len = self.ip_header.ihl as usize * 4 + self.tcp_header.data_offset as usize * 4;
+        let len = (self.ip_header.total_len as usize)
+            .saturating_sub(headers_len)
+            .min(self.data.len());
+        &self.data[..len]
+    }
+}
 
-    // Calculate IP header length (IHL is in 32-bit words)
-    let ip_header_len = (ip_header.ihl as usize) * 4;
-
-    // Checking if buffer is long enough for TCP header
-    if buffer.len() < ip_header_len + 20 {
-        return None;
-    }
-
-    let tcp_start = ip_header_len;
-    let tcp_header = TCPHeader {
-        source_port: u16::from_be_bytes([buffer[tcp_start], buffer[tcp_start + 1]]),
-        destination_port: u16::from_be_bytes([buffer[tcp_start + 2], buffer[tcp_start + 3]]),
-        sequence_number: u32::from_be_bytes([
-            buffer[tcp_start + 4],
-            buffer[tcp_start + 5],
-            buffer[tcp_start + 6],
-            buffer[tcp_start + 7],
-        ]),
-        acknowledge_number: u32::from_be_bytes([
-            buffer[tcp_start + 8],
-            buffer[tcp_start + 9],
-            buffer[tcp_start + 10],
-            buffer[tcp_start + 11],
-        ]),
-        data_offset: (buffer[tcp_start + 12] >> 4) & 0x0F, // First 4 bits
-        reserved: buffer[tcp_start + 12] & 0x0F,           // Last 4 bits
-        control_bit: buffer[tcp_start + 13],
-        window: u16::from_be_bytes([buffer[tcp_start + 14], buffer[tcp_start + 15]]),
-        checksum: u16::from_be_bytes([buffer[tcp_start + 16], buffer[tcp_start + 17]]),
-        urgent_pointer: u16::from_be_bytes([buffer[tcp_start + 18], buffer[tcp_start + 19]]),
-    };
+/// Why a datagram could not be parsed into a TCP `Packet`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The buffer ends before the headers do
+    Truncated { needed: usize, available: usize },
+    /// Not an IPv4 datagram
+    BadVersion(u8),
+    /// IP header length below the 20-byte minimum
+    BadIhl(u8),
+    /// TCP data offset below the 20-byte minimum or past the datagram's end
+    BadDataOffset(u8),
+    /// IP total length disagrees with the buffer or the header length
+    LengthMismatch { total_len: u16, available: usize },
+    /// The datagram carries another protocol than TCP
+    UnsupportedProtocol(u8),
+    /// The payload does not fit in a `Packet`
+    PayloadTooLarge(usize),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Truncated { needed, available } => {
+                write!(f, "truncated datagram: need {} bytes, have {}", needed, available)
+            }
+            ParseError::BadVersion(version) => write!(f, "unsupported IP version {}", version),
+            ParseError::BadIhl(ihl) => write!(f, "invalid IP header length {}", ihl),
+            ParseError::BadDataOffset(offset) => write!(f, "invalid TCP data offset {}", offset),
+            ParseError::LengthMismatch { total_len, available } => {
+                write!(f, "IP total length {} does not match {} bytes received", total_len, available)
+            }
+            ParseError::UnsupportedProtocol(protocol) => write!(f, "unsupported protocol {}", protocol),
+            ParseError::PayloadTooLarge(len) => write!(f, "payload of {} bytes exceeds {}", len, MAX_PAYLOAD),
+        }
+    }
+}
 
-    // Calculate TCP header length (data offset is in 32-bit words)
-    let tcp_header_len = (tcp_header.data_offset as usize) * 4;
+impl std::error::Error for ParseError {}
 
-    let data_start = ip_header_len + tcp_header_len;
-    let data = if data_start < buffer.len() {
-        let mut data = [0u8; 500];
-        data[..buffer[data_start..].len()].copy_from_slice(&buffer[data_start..]);
-        data
-    } else {
-        [0u8; 500]
-    };
+/// Borrowed view of an IPv4 datagram in a receive buffer. The header
+/// lengths are validated once by `new`, so the accessors never panic.
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4View<'a> {
+    /// The datagram, up to its total length
+    buffer: &'a [u8],
+}
+
+impl<'a> Ipv4View<'a> {
+    /// Check the version and lengths of the datagram at the start of
+    /// `buffer`. Bytes past the IP total length (padding added by the tun
+    /// device) are left out of the view.
+    pub fn new(buffer: &'a [u8]) -> Result<Self, ParseError> {
+        if buffer.len() < 20 {
+            return Err(ParseError::Truncated { needed: 20, available: buffer.len() });
+        }
+
+        let version = buffer[0] >> 4;
+        if version != 4 {
+            return Err(ParseError::BadVersion(version));
+        }
+        let ihl = buffer[0] & 0x0F;
+        if ihl < 5 {
+            return Err(ParseError::BadIhl(ihl));
+        }
+
+        let total_len = u16::from_be_bytes([buffer[2], buffer[3]]);
+        if total_len as usize > buffer.len() || (total_len as usize) < ihl as usize * 4 {
+            return Err(ParseError::LengthMismatch {
+                total_len,
+                available: buffer.len(),
+            });
+        }
+
+        Ok(Self {
+            buffer: &buffer[..total_len as usize],
+        })
+    }
+
+    pub fn version(&self) -> u8 {
+        self.buffer[0] >> 4
+    }
+
+    pub fn ihl(&self) -> u8 {
+        self.buffer[0] & 0x0F
+    }
+
+    /// Header length in bytes
+    pub fn header_len(&self) -> usize {
+        self.ihl() as usize * 4
+    }
+
+    pub fn type_of_service(&self) -> u8 {
+        self.buffer[1]
+    }
+
+    pub fn total_len(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[2], self.buffer[3]])
+    }
+
+    pub fn identification(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[4], self.buffer[5]])
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.buffer[6] >> 5
+    }
+
+    pub fn fragment_offset(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[6] & 0x1F, self.buffer[7]])
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.buffer[8]
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.buffer[9]
+    }
+
+    pub fn header_checksum(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[10], self.buffer[11]])
+    }
+
+    pub fn source(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.buffer[12], self.buffer[13], self.buffer[14], self.buffer[15])
+    }
+
+    pub fn destination(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.buffer[16], self.buffer[17], self.buffer[18], self.buffer[19])
+    }
+
+    /// The whole datagram
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buffer
+    }
+
+    /// The datagram past the header
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buffer[self.header_len()..]
+    }
+
+    /// Owned copy of the header
+    pub fn header(&self) -> IPHeader {
+        IPHeader {
+            version: self.version(),
+            ihl: self.ihl(),
+            type_of_service: self.type_of_service(),
+            total_len: self.total_len(),
+            identification: self.identification(),
+            flags: self.flags(),
+            fragment_offset: self.fragment_offset(),
+            ttl: self.ttl(),
+            protocol: self.protocol(),
+            header_checksum: self.header_checksum(),
+            source: self.source(),
+            destination: self.destination(),
+        }
+    }
+}
+
+/// Borrowed view of a TCP segment, such as the payload of an `Ipv4View`.
+/// The data offset is validated once by `new`.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpView<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> TcpView<'a> {
+    pub fn new(segment: &'a [u8]) -> Result<Self, ParseError> {
+        if segment.len() < 20 {
+            return Err(ParseError::Truncated { needed: 20, available: segment.len() });
+        }
+
+        let data_offset = segment[12] >> 4;
+        if data_offset < 5 || data_offset as usize * 4 > segment.len() {
+            return Err(ParseError::BadDataOffset(data_offset));
+        }
+
+        Ok(Self { buffer: segment })
+    }
+
+    pub fn source_port(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[0], self.buffer[1]])
+    }
+
+    pub fn destination_port(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[2], self.buffer[3]])
+    }
+
+    pub fn sequence_number(&self) -> u32 {
+        u32::from_be_bytes([self.buffer[4], self.buffer[5], self.buffer[6], self.buffer[7]])
+    }
+
+    pub fn acknowledge_number(&self) -> u32 {
+        u32::from_be_bytes([self.buffer[8], self.buffer[9], self.buffer[10], self.buffer[11]])
+    }
+
+    pub fn data_offset(&self) -> u8 {
+        self.buffer[12] >> 4
+    }
+
+    /// Header length in bytes, options included
+    pub fn header_len(&self) -> usize {
+        self.data_offset() as usize * 4
+    }
 
-    Some(Packet {
-        ip_header,
-        tcp_header,
+    pub fn reserved(&self) -> u8 {
+        self.buffer[12] & 0x0F
+    }
+
+    pub fn control_bit(&self) -> u8 {
+        self.buffer[13]
+    }
+
+    pub fn window(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[14], self.buffer[15]])
+    }
+
+    pub fn checksum(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[16], self.buffer[17]])
+    }
+
+    pub fn urgent_pointer(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[18], self.buffer[19]])
+    }
+
+    /// Raw options area, between the fixed header and the data
+    pub fn options_bytes(&self) -> &'a [u8] {
+        &self.buffer[20..self.header_len()]
+    }
+
+    /// Decode the options
+    pub fn options(&self) -> TcpOptions {
+        TcpOptions::parse(self.options_bytes())
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buffer[self.header_len()..]
+    }
+
+    /// Owned copy of the header, options decoded
+    pub fn header(&self) -> TCPHeader {
+        TCPHeader {
+            source_port: self.source_port(),
+            destination_port: self.destination_port(),
+            sequence_number: self.sequence_number(),
+            acknowledge_number: self.acknowledge_number(),
+            data_offset: self.data_offset(),
+            reserved: self.reserved(),
+            control_bit: self.control_bit(),
+            window: self.window(),
+            checksum: self.checksum(),
+            urgent_pointer: self.urgent_pointer(),
+            options: self.options(),
+        }
+    }
+}
+
+/// Parse an IPv4 datagram carrying a TCP segment into an owned `Packet`.
+/// Every length field is checked against the buffer before it is used, and
+/// bytes past the IP total length (padding added by the tun device) are
+/// ignored. The receive path uses `Ipv4View` and `TcpView` directly instead.
+pub fn parser(buffer: &[u8]) -> Result<Packet, ParseError> {
+    let ip = Ipv4View::new(buffer)?;
+    if ip.protocol() != 6 {
+        return Err(ParseError::UnsupportedProtocol(ip.protocol()));
+    }
+    let tcp = TcpView::new(ip.payload())?;
+
+    let payload = tcp.payload();
+    if payload.len() > MAX_PAYLOAD {
+        return Err(ParseError::PayloadTooLarge(payload.len()));
+    }
+
+    let mut data = [0u8; MAX_PAYLOAD];
+    data[..payload.len()].copy_from_slice(payload);
+
+    Ok(Packet {
+        ip_header: ip.header(),
+        tcp_header: tcp.header(),
         data,
     })
 }
+
+/// Which checksum of a received datagram was wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumError {
+    Ip,
+    Tcp,
+}
+
+/// Verify the IP header checksum of an IPv4 datagram and, if it carries TCP,
+/// the TCP checksum over the pseudo header and segment. Datagrams too short
+/// to hold the headers are left for `parser` to reject.
+pub fn verify_checksums(buffer: &[u8]) -> Result<(), ChecksumError> {
+    let Some(&first) = buffer.first() else {
+        return Ok(());
+    };
+    let ip_header_len = (first & 0x0F) as usize * 4;
+    let Some(ip_header) = buffer.get(..ip_header_len).filter(|header| header.len() >= 20) else {
+        return Ok(());
+    };
+
+    if calculate_checksum(ip_header) != 0 {
+        return Err(ChecksumError::Ip);
+    }
+    if ip_header[9] != 6 {
+        return Ok(());
+    }
+
+    // The segment ends where the IP total length says, not at the end of the
+    // frame. A total length short of the headers is malformed, not corrupted.
+    let total_len = (u16::from_be_bytes([ip_header[2], ip_header[3]]) as usize).min(buffer.len());
+    if total_len < ip_header_len + 20 {
+        return Ok(());
+    }
+    let segment = &buffer[ip_header_len..total_len];
+
+    if tcp_checksum(&ip_header[12..16], &ip_header[16..20], segment) != 0 {
+        return Err(ChecksumError::Tcp);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_sender::SegmentBuilder;
+
+    /// A valid datagram carrying `payload`
+    fn datagram(payload: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0u8; 2048];
+        let len = SegmentBuilder::new((Ipv4Addr::new(192, 0, 2, 1), 50000), (Ipv4Addr::new(192, 168, 0, 1), 8080))
+            .seq(1000)
+            .ack(2000)
+            .flags(0x18)
+            .payload(payload)
+            .write(&mut buffer)
+            .unwrap();
+        buffer.truncate(len);
+        buffer
+    }
+
+    /// Recompute the IP header checksum after editing the header
+    fn fix_ip_checksum(datagram: &mut [u8]) {
+        let header_len = (datagram[0] & 0x0F) as usize * 4;
+        datagram[10..12].fill(0);
+        let checksum = calculate_checksum(&datagram[..header_len.min(datagram.len())]);
+        datagram[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn set_total_len(datagram: &mut [u8], total_len: u16) {
+        datagram[2..4].copy_from_slice(&total_len.to_be_bytes());
+        fix_ip_checksum(datagram);
+    }
+
+    #[test]
+    fn valid_datagram_parses() {
+        let datagram = datagram(b"hello");
+        assert_eq!(verify_checksums(&datagram), Ok(()));
+        let packet = parser(&datagram).unwrap();
+        assert_eq!(packet.tcp_header.sequence_number, 1000);
+        assert_eq!(packet.payload(), b"hello");
+    }
+
+    #[test]
+    fn truncated_headers() {
+        let datagram = datagram(&[]);
+        assert_eq!(
+            parser(&datagram[..12]).unwrap_err(),
+            ParseError::Truncated { needed: 20, available: 12 }
+        );
+
+        // The IP header is complete, the TCP header is not
+        let mut short = datagram[..30].to_vec();
+        set_total_len(&mut short, 30);
+        assert_eq!(parser(&short).unwrap_err(), ParseError::Truncated { needed: 20, available: 10 });
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut datagram = datagram(&[]);
+        datagram[0] = 0x65;
+        assert_eq!(parser(&datagram).unwrap_err(), ParseError::BadVersion(6));
+    }
+
+    #[test]
+    fn bad_ihl() {
+        let mut datagram = datagram(&[]);
+        datagram[0] = 0x44;
+        assert_eq!(parser(&datagram).unwrap_err(), ParseError::BadIhl(4));
+    }
+
+    #[test]
+    fn bad_data_offset() {
+        let mut datagram = datagram(&[]);
+        datagram[32] = 4 << 4;
+        assert_eq!(parser(&datagram).unwrap_err(), ParseError::BadDataOffset(4));
+
+        // Past the end of the segment
+        datagram[32] = 15 << 4;
+        assert_eq!(parser(&datagram).unwrap_err(), ParseError::BadDataOffset(15));
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let mut long = datagram(b"hello");
+        set_total_len(&mut long, 100);
+        assert_eq!(
+            parser(&long).unwrap_err(),
+            ParseError::LengthMismatch { total_len: 100, available: 45 }
+        );
+
+        // Shorter than the IP header itself: malformed rather than a TCP
+        // checksum failure
+        let mut short = datagram(b"hello");
+        set_total_len(&mut short, 12);
+        assert_eq!(verify_checksums(&short), Ok(()));
+        assert_eq!(
+            parser(&short).unwrap_err(),
+            ParseError::LengthMismatch { total_len: 12, available: 45 }
+        );
+    }
+
+    #[test]
+    fn unsupported_protocol() {
+        let mut datagram = datagram(&[]);
+        datagram[9] = 17;
+        fix_ip_checksum(&mut datagram);
+        assert_eq!(verify_checksums(&datagram), Ok(()));
+        assert_eq!(parser(&datagram).unwrap_err(), ParseError::UnsupportedProtocol(17));
+    }
+
+    #[test]
+    fn payload_too_large() {
+        let datagram = datagram(&[0u8; MAX_PAYLOAD + 1]);
+        assert_eq!(parser(&datagram).unwrap_err(), ParseError::PayloadTooLarge(MAX_PAYLOAD + 1));
+    }
+
+    #[test]
+    fn corrupted_checksums() {
+        let mut datagram = datagram(b"hello");
+        datagram[44] ^= 0xFF;
+        assert_eq!(verify_checksums(&datagram), Err(ChecksumError::Tcp));
+
+        datagram[8] ^= 0xFF;
+        assert_eq!(verify_checksums(&datagram), Err(ChecksumError::Ip));
+    }
+}
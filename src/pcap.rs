@@ -0,0 +1,862 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::sniffer::Filter;
+
+// Capture files for Wireshark and tcpdump.
+//
+// pcap (https://www.tcpdump.org/manpages/pcap-savefile.5.html) has no
+// direction field, so frames are wrapped in a Linux cooked capture (SLL)
+// header whose packet type says whether we received or sent them. pcapng
+// (RFC draft-ietf-opsawg-pcapng) stores raw IP with the direction in
+// epb_flags and the stack's notes in opt_comment. Both use nanosecond
+// timestamps.
+
+/// LINKTYPE_LINUX_SLL: 16-byte cooked header, then the datagram
+const LINKTYPE_LINUX_SLL: u16 = 113;
+
+/// LINKTYPE_RAW: the datagram alone
+const LINKTYPE_RAW: u16 = 101;
+
+/// Other link types captures are read from
+const LINKTYPE_NULL: u16 = 0;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_IPV4: u16 = 228;
+const LINKTYPE_LINUX_SLL2: u16 = 276;
+
+/// pcap magic for microsecond and nanosecond timestamps
+const PCAP_MAGIC_MICROS: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+
+/// SLL packet types
+const SLL_HOST: u16 = 0;
+const SLL_OUTGOING: u16 = 4;
+
+/// ARPHRD_NONE, the hardware type of a TUN device
+const ARPHRD_NONE: u16 = 0xFFFE;
+
+const SNAPLEN: u32 = 65535;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const SIMPLE_PACKET_BLOCK: u32 = 3;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_EPB_FLAGS: u16 = 2;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_IF_TSOFFSET: u16 = 14;
+
+/// Largest block or record read; anything bigger means a corrupt file
+const MAX_RECORD: usize = 1 << 24;
+
+/// Capture file format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Pcap,
+    Pcapng,
+}
+
+impl Format {
+    /// pcapng for a `.pcapng` file, pcap otherwise
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext == "pcapng" => Format::Pcapng,
+            _ => Format::Pcap,
+        }
+    }
+}
+
+/// Whether the stack received or sent a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Writes frames to a pcap or pcapng capture as the stack handles them.
+///
+/// Frames are stamped with wall-clock time derived from the stack's clock:
+/// the first frame (or `starting_at`) ties an `Instant` to a `SystemTime`,
+/// and later frames are offset from it, so captures of virtual-time runs
+/// keep their exact spacing.
+pub struct Capture {
+    writer: Box<dyn Write + Send>,
+    format: Format,
+    origin: Option<(Instant, SystemTime)>,
+
+    /// Datagrams to record; all when unset
+    filter: Option<Filter>,
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture")
+            .field("format", &self.format)
+            .field("origin", &self.origin)
+            .field("filter", &self.filter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Capture {
+    /// Start a capture on `writer`, writing the file header
+    pub fn new(writer: impl Write + Send + 'static, format: Format) -> io::Result<Self> {
+        let mut capture = Self {
+            writer: Box::new(writer),
+            format,
+            origin: None,
+            filter: None,
+        };
+        capture.write_header()?;
+        Ok(capture)
+    }
+
+    /// Create a capture file, pcapng if it ends in `.pcapng`
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Self::new(BufWriter::new(File::create(path)?), Format::from_path(path))
+    }
+
+    /// Stamp frames recorded at `instant` with `time`
+    pub fn starting_at(mut self, instant: Instant, time: SystemTime) -> Self {
+        self.origin = Some((instant, time));
+        self
+    }
+
+    /// Only record datagrams `filter` selects
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Record an IPv4 datagram handled at `instant`, with an optional note
+    /// on what the stack made of it (pcapng only)
+    pub fn record(
+        &mut self,
+        instant: Instant,
+        direction: Direction,
+        datagram: &[u8],
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        if self
+            .filter
+            .as_ref()
+            .is_some_and(|filter| !filter.matches(direction, datagram))
+        {
+            return Ok(());
+        }
+
+        let (origin, time) = *self
+            .origin
+            .get_or_insert_with(|| (instant, SystemTime::now()));
+        let time = match instant.checked_duration_since(origin) {
+            Some(elapsed) => time + elapsed,
+            None => time - origin.duration_since(instant),
+        };
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+
+        match self.format {
+            Format::Pcap => self.write_record(since_epoch, direction, datagram),
+            Format::Pcapng => self.write_enhanced_packet(since_epoch, direction, datagram, comment),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        match self.format {
+            Format::Pcap => {
+                let mut header = Vec::with_capacity(24);
+                header.extend_from_slice(&PCAP_MAGIC_NANOS.to_le_bytes());
+                header.extend_from_slice(&2u16.to_le_bytes()); // Version 2.4
+                header.extend_from_slice(&4u16.to_le_bytes());
+                header.extend_from_slice(&0i32.to_le_bytes()); // GMT
+                header.extend_from_slice(&0u32.to_le_bytes()); // Timestamp accuracy
+                header.extend_from_slice(&SNAPLEN.to_le_bytes());
+                header.extend_from_slice(&(LINKTYPE_LINUX_SLL as u32).to_le_bytes());
+                self.writer.write_all(&header)
+            }
+            Format::Pcapng => {
+                let mut body = Vec::new();
+                body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+                body.extend_from_slice(&1u16.to_le_bytes()); // Version 1.0
+                body.extend_from_slice(&0u16.to_le_bytes());
+                body.extend_from_slice(&(-1i64).to_le_bytes()); // Section length unknown
+                push_option(
+                    &mut body,
+                    OPT_SHB_USERAPPL,
+                    env!("CARGO_PKG_NAME").as_bytes(),
+                );
+                push_option(&mut body, OPT_END, &[]);
+                self.write_block(SECTION_HEADER_BLOCK, &body)?;
+
+                let mut body = Vec::new();
+                body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+                body.extend_from_slice(&0u16.to_le_bytes()); // Reserved
+                body.extend_from_slice(&SNAPLEN.to_le_bytes());
+                push_option(&mut body, OPT_IF_NAME, b"tun0");
+                push_option(&mut body, OPT_IF_TSRESOL, &[9]); // Nanoseconds
+                push_option(&mut body, OPT_END, &[]);
+                self.write_block(INTERFACE_DESCRIPTION_BLOCK, &body)
+            }
+        }
+    }
+
+    fn write_record(
+        &mut self,
+        time: Duration,
+        direction: Direction,
+        datagram: &[u8],
+    ) -> io::Result<()> {
+        let packet_type = match direction {
+            Direction::Inbound => SLL_HOST,
+            Direction::Outbound => SLL_OUTGOING,
+        };
+        let len = 16 + datagram.len();
+
+        let mut record = Vec::with_capacity(16 + len);
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_nanos().to_le_bytes());
+        record.extend_from_slice(&(len as u32).to_le_bytes()); // Captured length
+        record.extend_from_slice(&(len as u32).to_le_bytes()); // Original length
+
+        // The cooked header is big-endian
+        record.extend_from_slice(&packet_type.to_be_bytes());
+        record.extend_from_slice(&ARPHRD_NONE.to_be_bytes());
+        record.extend_from_slice(&0u16.to_be_bytes()); // No link-layer address
+        record.extend_from_slice(&[0; 8]);
+        record.extend_from_slice(&0x0800u16.to_be_bytes());
+        record.extend_from_slice(datagram);
+
+        self.writer.write_all(&record)
+    }
+
+    fn write_enhanced_packet(
+        &mut self,
+        time: Duration,
+        direction: Direction,
+        datagram: &[u8],
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        let nanos = time.as_nanos() as u64;
+        let flags: u32 = match direction {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        };
+
+        let mut body = Vec::with_capacity(32 + datagram.len());
+        body.extend_from_slice(&0u32.to_le_bytes()); // Interface
+        body.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(nanos as u32).to_le_bytes());
+        body.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        body.extend_from_slice(datagram);
+        pad(&mut body);
+        if let Some(comment) = comment {
+            push_option(&mut body, OPT_COMMENT, comment.as_bytes());
+        }
+        push_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut body, OPT_END, &[]);
+
+        self.write_block(ENHANCED_PACKET_BLOCK, &body)
+    }
+
+    /// Write a pcapng block: type, total length, body, total length again
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let total_len = (12 + body.len()) as u32;
+        let mut block = Vec::with_capacity(total_len as usize);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&total_len.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&total_len.to_le_bytes());
+        self.writer.write_all(&block)
+    }
+}
+
+/// Append a pcapng option, padded to 32 bits
+fn push_option(buffer: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buffer.extend_from_slice(&code.to_le_bytes());
+    buffer.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(value);
+    pad(buffer);
+}
+
+fn pad(buffer: &mut Vec<u8>) {
+    buffer.resize(buffer.len().next_multiple_of(4), 0);
+}
+
+/// An IPv4 datagram read from a capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    /// When the datagram was captured
+    pub time: SystemTime,
+
+    /// Whether the capturing host received or sent it, if the file says
+    pub direction: Option<Direction>,
+
+    pub datagram: Vec<u8>,
+}
+
+/// Reads IPv4 datagrams from a pcap or pcapng capture.
+///
+/// Both byte orders and any timestamp resolution are accepted, as are raw
+/// IP, Ethernet, BSD loopback and Linux cooked (v1 and v2) link types.
+/// Frames carrying anything but IPv4, or cut short by the capture's
+/// snapshot length, are skipped and counted.
+pub struct Reader {
+    reader: Box<dyn Read + Send>,
+    format: Format,
+
+    /// Byte order of the file, or of the current pcapng section
+    big_endian: bool,
+
+    /// Link types and clocks of the pcapng section's interfaces, or of the
+    /// whole pcap file
+    interfaces: Vec<Interface>,
+
+    skipped: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    link_type: u16,
+    units_per_second: u64,
+
+    /// Seconds added to every timestamp
+    offset: i64,
+}
+
+/// A captured frame before its link-layer header is removed
+struct Frame {
+    time: SystemTime,
+    direction: Option<Direction>,
+    link_type: u16,
+    data: Vec<u8>,
+    truncated: bool,
+}
+
+impl fmt::Debug for Reader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reader")
+            .field("format", &self.format)
+            .field("big_endian", &self.big_endian)
+            .field("interfaces", &self.interfaces)
+            .field("skipped", &self.skipped)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Reader {
+    /// Read a capture from `reader`, telling pcap from pcapng by its header
+    pub fn new(reader: impl Read + Send + 'static) -> io::Result<Self> {
+        let mut reader = Self {
+            reader: Box::new(reader),
+            format: Format::Pcap,
+            big_endian: false,
+            interfaces: Vec::new(),
+            skipped: 0,
+        };
+
+        let mut magic = [0; 4];
+        reader.reader.read_exact(&mut magic)?;
+        if u32::from_le_bytes(magic) == SECTION_HEADER_BLOCK {
+            reader.format = Format::Pcapng;
+            let mut len = [0; 4];
+            reader.reader.read_exact(&mut len)?;
+            reader.read_section_header(len)?;
+        } else {
+            reader.read_pcap_header(magic)?;
+        }
+        Ok(reader)
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Frames skipped so far for not carrying a whole IPv4 datagram
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// The next IPv4 datagram, or `None` at the end of the capture
+    pub fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            let frame = match self.format {
+                Format::Pcap => self.read_record()?,
+                Format::Pcapng => self.read_block()?,
+            };
+            let Some(frame) = frame else {
+                return Ok(None);
+            };
+
+            match decode_link(frame.link_type, &frame.data) {
+                Some((datagram, direction)) if !frame.truncated => {
+                    return Ok(Some(Packet {
+                        time: frame.time,
+                        direction: frame.direction.or(direction),
+                        datagram: datagram.to_vec(),
+                    }));
+                }
+                _ => self.skipped += 1,
+            }
+        }
+    }
+
+    fn read_pcap_header(&mut self, magic: [u8; 4]) -> io::Result<()> {
+        let (big_endian, units_per_second) =
+            match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                (PCAP_MAGIC_MICROS, _) => (false, 1_000_000),
+                (PCAP_MAGIC_NANOS, _) => (false, 1_000_000_000),
+                (_, PCAP_MAGIC_MICROS) => (true, 1_000_000),
+                (_, PCAP_MAGIC_NANOS) => (true, 1_000_000_000),
+                _ => return Err(invalid("not a pcap or pcapng file")),
+            };
+        self.big_endian = big_endian;
+
+        // Version, time zone, accuracy and snapshot length are not needed
+        let mut header = [0; 20];
+        self.reader.read_exact(&mut header)?;
+        let link_type = self.u32(&header, 16)? as u16;
+        self.interfaces = vec![Interface {
+            link_type,
+            units_per_second,
+            offset: 0,
+        }];
+        Ok(())
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Frame>> {
+        let mut header = [0; 16];
+        if !self.read_or_eof(&mut header)? {
+            return Ok(None);
+        }
+        let seconds = self.u32(&header, 0)? as u64;
+        let fraction = self.u32(&header, 4)? as u64;
+        let captured = self.u32(&header, 8)? as usize;
+        let original = self.u32(&header, 12)? as usize;
+        if captured > MAX_RECORD {
+            return Err(invalid("pcap record too large"));
+        }
+
+        let mut data = vec![0; captured];
+        self.reader.read_exact(&mut data)?;
+
+        let interface = self.interfaces[0];
+        let units = seconds * interface.units_per_second + fraction;
+        Ok(Some(Frame {
+            time: timestamp(units, interface),
+            direction: None,
+            link_type: interface.link_type,
+            data,
+            truncated: captured < original,
+        }))
+    }
+
+    /// Read pcapng blocks up to the next packet
+    fn read_block(&mut self) -> io::Result<Option<Frame>> {
+        loop {
+            let mut header = [0; 8];
+            if !self.read_or_eof(&mut header)? {
+                return Ok(None);
+            }
+
+            // The section header's type reads the same in either byte order
+            let block_type = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            if block_type == SECTION_HEADER_BLOCK {
+                self.read_section_header([header[4], header[5], header[6], header[7]])?;
+                continue;
+            }
+
+            let block_type = self.u32(&header, 0)?;
+            let body = self.read_block_body(self.u32(&header, 4)?)?;
+            match block_type {
+                INTERFACE_DESCRIPTION_BLOCK => self.read_interface(&body)?,
+                ENHANCED_PACKET_BLOCK => return self.read_enhanced_packet(&body).map(Some),
+                // Simple packet blocks carry no timestamp to replay them at
+                SIMPLE_PACKET_BLOCK => self.skipped += 1,
+                _ => {}
+            }
+        }
+    }
+
+    fn read_section_header(&mut self, len: [u8; 4]) -> io::Result<()> {
+        let mut magic = [0; 4];
+        self.reader.read_exact(&mut magic)?;
+        self.big_endian = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (BYTE_ORDER_MAGIC, _) => false,
+            (_, BYTE_ORDER_MAGIC) => true,
+            _ => return Err(invalid("bad pcapng byte-order magic")),
+        };
+
+        // Version, section length and options are not needed
+        let len = self.u32(&len, 0)?;
+        let rest = (len as usize)
+            .checked_sub(12)
+            .ok_or_else(|| invalid("pcapng section header too short"))?;
+        let mut rest = vec![0; rest];
+        self.reader.read_exact(&mut rest)?;
+        self.interfaces.clear();
+        Ok(())
+    }
+
+    /// The body of a block whose header was read, checking the trailing length
+    fn read_block_body(&mut self, len: u32) -> io::Result<Vec<u8>> {
+        let len = len as usize;
+        if len < 12 || !len.is_multiple_of(4) || len > MAX_RECORD {
+            return Err(invalid("bad pcapng block length"));
+        }
+        let mut body = vec![0; len - 8];
+        self.reader.read_exact(&mut body)?;
+        let trailer = body.split_off(len - 12);
+        if self.u32(&trailer, 0)? as usize != len {
+            return Err(invalid("pcapng block lengths differ"));
+        }
+        Ok(body)
+    }
+
+    fn read_interface(&mut self, body: &[u8]) -> io::Result<()> {
+        let mut interface = Interface {
+            link_type: self.u16(body, 0)?,
+            units_per_second: 1_000_000,
+            offset: 0,
+        };
+
+        for (code, value) in self.options(body.get(8..).unwrap_or_default())? {
+            match (code, value) {
+                (OPT_IF_TSRESOL, &[resolution]) => {
+                    let exponent = (resolution & 0x7F) as u32;
+                    let units = if resolution & 0x80 != 0 {
+                        2u64.checked_pow(exponent)
+                    } else {
+                        10u64.checked_pow(exponent)
+                    };
+                    interface.units_per_second =
+                        units.ok_or_else(|| invalid("bad pcapng timestamp resolution"))?;
+                }
+                (OPT_IF_TSOFFSET, value) if value.len() == 8 => {
+                    interface.offset = self.u64(value, 0)? as i64;
+                }
+                _ => {}
+            }
+        }
+
+        self.interfaces.push(interface);
+        Ok(())
+    }
+
+    fn read_enhanced_packet(&mut self, body: &[u8]) -> io::Result<Frame> {
+        let id = self.u32(body, 0)? as usize;
+        let interface = *self
+            .interfaces
+            .get(id)
+            .ok_or_else(|| invalid("packet on an undescribed interface"))?;
+        let units = (self.u32(body, 4)? as u64) << 32 | self.u32(body, 8)? as u64;
+        let captured = self.u32(body, 12)? as usize;
+        let original = self.u32(body, 16)? as usize;
+
+        let data = body
+            .get(20..20 + captured)
+            .ok_or_else(|| invalid("pcapng packet overruns its block"))?;
+        let options = body
+            .get((20 + captured).next_multiple_of(4)..)
+            .unwrap_or_default();
+
+        let mut direction = None;
+        for (code, value) in self.options(options)? {
+            if code == OPT_EPB_FLAGS && value.len() == 4 {
+                direction = match self.u32(value, 0)? & 0x3 {
+                    1 => Some(Direction::Inbound),
+                    2 => Some(Direction::Outbound),
+                    _ => None,
+                };
+            }
+        }
+
+        Ok(Frame {
+            time: timestamp(units, interface),
+            direction,
+            link_type: interface.link_type,
+            data: data.to_vec(),
+            truncated: captured < original,
+        })
+    }
+
+    /// Split a pcapng option list into (code, value) pairs
+    fn options<'a>(&self, mut options: &'a [u8]) -> io::Result<Vec<(u16, &'a [u8])>> {
+        let mut parsed = Vec::new();
+        while options.len() >= 4 {
+            let code = self.u16(options, 0)?;
+            let len = self.u16(options, 2)? as usize;
+            if code == OPT_END {
+                break;
+            }
+            let value = options
+                .get(4..4 + len)
+                .ok_or_else(|| invalid("pcapng option overruns its block"))?;
+            parsed.push((code, value));
+            options = options
+                .get((4 + len).next_multiple_of(4)..)
+                .unwrap_or_default();
+        }
+        Ok(parsed)
+    }
+
+    /// Fill `buffer`, returning false at a clean end of file
+    fn read_or_eof(&mut self, buffer: &mut [u8]) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match self.reader.read(&mut buffer[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    fn u16(&self, bytes: &[u8], offset: usize) -> io::Result<u16> {
+        let bytes = field(bytes, offset)?;
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, bytes: &[u8], offset: usize) -> io::Result<u32> {
+        let bytes = field(bytes, offset)?;
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&self, bytes: &[u8], offset: usize) -> io::Result<u64> {
+        let bytes = field(bytes, offset)?;
+        Ok(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+}
+
+impl Iterator for Reader {
+    type Item = io::Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+fn field<const N: usize>(bytes: &[u8], offset: usize) -> io::Result<[u8; N]> {
+    bytes
+        .get(offset..offset + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid("truncated capture header"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Wall-clock time of a timestamp counted in the interface's units
+fn timestamp(units: u64, interface: Interface) -> SystemTime {
+    let seconds = units / interface.units_per_second;
+    let nanos = (units % interface.units_per_second) as u128 * 1_000_000_000
+        / interface.units_per_second as u128;
+    let time = UNIX_EPOCH + Duration::new(seconds, nanos as u32);
+    if interface.offset >= 0 {
+        time + Duration::from_secs(interface.offset as u64)
+    } else {
+        time - Duration::from_secs(interface.offset.unsigned_abs())
+    }
+}
+
+/// Strip the link-layer header from a frame, returning the IPv4 datagram
+/// (without link-layer padding) and the direction the header records
+fn decode_link(link_type: u16, frame: &[u8]) -> Option<(&[u8], Option<Direction>)> {
+    let (payload, direction) = match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 => (frame, None),
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            // Step over VLAN tags
+            while matches!(frame.get(offset..offset + 2)?, [0x81, 0x00] | [0x88, 0xA8]) {
+                offset += 4;
+            }
+            if frame.get(offset..offset + 2)? != [0x08, 0x00] {
+                return None;
+            }
+            (&frame[offset + 2..], None)
+        }
+        // The address family is in the capturing host's byte order
+        LINKTYPE_NULL => match frame.get(..4)? {
+            [2, 0, 0, 0] | [0, 0, 0, 2] => (&frame[4..], None),
+            _ => return None,
+        },
+        LINKTYPE_LINUX_SLL => {
+            if frame.get(14..16)? != [0x08, 0x00] {
+                return None;
+            }
+            (
+                &frame[16..],
+                sll_direction(u16::from_be_bytes([frame[0], frame[1]])),
+            )
+        }
+        LINKTYPE_LINUX_SLL2 => {
+            if frame.get(..2)? != [0x08, 0x00] {
+                return None;
+            }
+            (frame.get(20..)?, sll_direction(frame[10] as u16))
+        }
+        _ => return None,
+    };
+
+    if payload.first()? >> 4 != 4 {
+        return None;
+    }
+    let total_len = u16::from_be_bytes(field(payload, 2).ok()?) as usize;
+    Some((payload.get(..total_len)?, direction))
+}
+
+/// Direction of a Linux cooked capture packet type
+fn sll_direction(packet_type: u16) -> Option<Direction> {
+    match packet_type {
+        SLL_OUTGOING => Some(Direction::Outbound),
+        // Unicast, broadcast or multicast to us; not frames for other hosts
+        0..=2 => Some(Direction::Inbound),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::packet_sender::SegmentBuilder;
+
+    /// In-memory file the capture writes while the test still holds it
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct SharedFile(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn datagram(seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0u8; 1500];
+        let len = SegmentBuilder::new(
+            (Ipv4Addr::new(10, 0, 0, 1), 5000),
+            (Ipv4Addr::new(10, 0, 0, 2), 80),
+        )
+        .seq(seq)
+        .flags(0x18)
+        .payload(payload)
+        .write(&mut buffer)
+        .unwrap();
+        buffer.truncate(len);
+        buffer
+    }
+
+    /// Record three datagrams and read them back
+    fn round_trip(format: Format) {
+        let file = SharedFile::default();
+        let start = Instant::now();
+        let epoch = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let mut capture = Capture::new(file.clone(), format)
+            .unwrap()
+            .starting_at(start, epoch);
+
+        let offsets = [
+            Duration::ZERO,
+            Duration::from_nanos(1_500_001),
+            Duration::from_secs(2),
+        ];
+        let directions = [Direction::Inbound, Direction::Outbound, Direction::Inbound];
+        for (i, (offset, direction)) in offsets.into_iter().zip(directions).enumerate() {
+            let datagram = datagram(i as u32, &vec![i as u8; i * 100]);
+            capture
+                .record(start + offset, direction, &datagram, Some("note"))
+                .unwrap();
+        }
+        capture.flush().unwrap();
+
+        let bytes = file.0.lock().unwrap().clone();
+        let reader = Reader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.format(), format);
+        let packets: Vec<Packet> = reader.collect::<io::Result<_>>().unwrap();
+
+        assert_eq!(packets.len(), 3);
+        for (i, packet) in packets.iter().enumerate() {
+            assert_eq!(packet.datagram, datagram(i as u32, &vec![i as u8; i * 100]));
+            assert_eq!(packet.direction, Some(directions[i]));
+            assert_eq!(packet.time, epoch + offsets[i]);
+        }
+    }
+
+    #[test]
+    fn pcap_round_trip() {
+        round_trip(Format::Pcap);
+    }
+
+    #[test]
+    fn pcapng_round_trip() {
+        round_trip(Format::Pcapng);
+    }
+
+    #[test]
+    fn filtered_capture_leaves_other_datagrams_out() {
+        let file = SharedFile::default();
+        let filter = Filter::parse("port 80 and outbound").unwrap();
+        let mut capture = Capture::new(file.clone(), Format::Pcap)
+            .unwrap()
+            .with_filter(filter);
+        let now = Instant::now();
+        capture
+            .record(now, Direction::Inbound, &datagram(1, b""), None)
+            .unwrap();
+        capture
+            .record(now, Direction::Outbound, &datagram(2, b""), None)
+            .unwrap();
+
+        let bytes = file.0.lock().unwrap().clone();
+        let packets: Vec<Packet> = Reader::new(Cursor::new(bytes))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].datagram, datagram(2, b""));
+    }
+}
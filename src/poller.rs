@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::task::{Wake, Waker};
+
+use crate::async_stream::{AsyncTcpListener, AsyncTcpStream};
+use crate::blocking::{TcpListener, TcpStream};
+use crate::stack::SharedStack;
+use sealed::Source;
+
+/// Readiness of a stack socket
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Readiness {
+    /// Data (or end of stream) can be read, or a connection accepted
+    pub readable: bool,
+
+    /// The send buffer has room
+    pub writable: bool,
+
+    /// The peer closed its side or the connection is gone
+    pub hangup: bool,
+}
+
+impl Readiness {
+    pub fn is_empty(&self) -> bool {
+        !self.readable && !self.writable && !self.hangup
+    }
+}
+
+/// A ready socket, identified by the token it was registered with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub token: usize,
+    pub readiness: Readiness,
+}
+
+/// A stack socket a `Poller` can watch: a `TcpStream`, `TcpListener`,
+/// `AsyncTcpStream` or `AsyncTcpListener`
+pub trait Pollable: sealed::Sealed {}
+
+mod sealed {
+    use crate::tcb::Quad;
+
+    #[derive(Debug, Clone, Copy)]
+    pub enum Source {
+        Stream(Quad),
+        Listener(u16),
+    }
+
+    pub trait Sealed {
+        fn source(&self) -> Source;
+    }
+}
+
+impl sealed::Sealed for TcpStream {
+    fn source(&self) -> Source {
+        Source::Stream(self.quad())
+    }
+}
+
+impl sealed::Sealed for AsyncTcpStream {
+    fn source(&self) -> Source {
+        Source::Stream(self.quad())
+    }
+}
+
+impl sealed::Sealed for TcpListener {
+    fn source(&self) -> Source {
+        Source::Listener(self.port())
+    }
+}
+
+impl sealed::Sealed for AsyncTcpListener {
+    fn source(&self) -> Source {
+        Source::Listener(self.port())
+    }
+}
+
+impl Pollable for TcpStream {}
+impl Pollable for AsyncTcpStream {}
+impl Pollable for TcpListener {}
+impl Pollable for AsyncTcpListener {}
+
+/// Waker that bumps an eventfd counter, making the descriptor readable
+struct EventFdWaker(OwnedFd);
+
+impl Wake for EventFdWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let one = 1u64;
+        let n = unsafe {
+            libc::write(
+                self.0.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                8,
+            )
+        };
+        // Only a counter about to overflow refuses the write, and then the
+        // descriptor is readable already
+        debug_assert!(
+            n == 8 || io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock,
+            "eventfd write failed: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Readiness notification for stack sockets through an eventfd.
+///
+/// Register the poller's descriptor for readability with epoll, or with mio
+/// through `SourceFd`, next to kernel sockets. When it fires, call `poll` to
+/// collect the ready stack sockets. `poll` reports level readiness: every
+/// registered socket that is ready is returned, whether or not it changed.
+/// The descriptor itself only fires when a socket's state changes, so drain
+/// reported sockets to `WouldBlock` (or call `poll` again) before waiting.
+pub struct Poller {
+    stack: SharedStack,
+    eventfd: Arc<EventFdWaker>,
+    waker: Waker,
+    sources: Mutex<HashMap<usize, Source>>,
+}
+
+impl Poller {
+    pub fn new(stack: SharedStack) -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let eventfd = Arc::new(EventFdWaker(unsafe { OwnedFd::from_raw_fd(fd) }));
+        Ok(Self {
+            stack,
+            waker: Waker::from(eventfd.clone()),
+            eventfd,
+            sources: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Report readiness of `socket` under `token`: data and hangups on a
+    /// stream, incoming connections on a listener
+    pub fn register(&self, socket: &impl Pollable, token: usize) -> io::Result<()> {
+        let source = socket.source();
+        let mut sources = self.sources.lock().unwrap();
+        if sources.contains_key(&token) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+
+        self.set_waker(source, Some(self.waker.clone()));
+        sources.insert(token, source);
+
+        // Report the socket's current readiness on the next poll
+        self.waker.wake_by_ref();
+        Ok(())
+    }
+
+    /// Stop reporting the socket registered under `token`
+    pub fn deregister(&self, token: usize) -> io::Result<()> {
+        let source = self
+            .sources
+            .lock()
+            .unwrap()
+            .remove(&token)
+            .ok_or(io::ErrorKind::NotFound)?;
+
+        self.set_waker(source, None);
+        Ok(())
+    }
+
+    fn set_waker(&self, source: Source, waker: Option<Waker>) {
+        let mut stack = self.stack.lock().unwrap();
+        match source {
+            Source::Stream(quad) => stack.set_stream_poller(quad, waker),
+            Source::Listener(port) => stack.set_listener_poller(port, waker),
+        }
+    }
+
+    /// Reset the eventfd and append an event for every registered socket that
+    /// is ready. Never blocks; wait on the descriptor first.
+    pub fn poll(&self, events: &mut Vec<Event>) -> io::Result<()> {
+        let mut counter = 0u64;
+        let n = unsafe {
+            libc::read(
+                self.as_raw_fd(),
+                &mut counter as *mut u64 as *mut libc::c_void,
+                8,
+            )
+        };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err);
+            }
+        }
+
+        let sources = self.sources.lock().unwrap();
+        let stack = self.stack.lock().unwrap();
+        for (&token, &source) in sources.iter() {
+            let readiness = match source {
+                Source::Stream(quad) => stack.stream_readiness(quad),
+                Source::Listener(port) => stack.listener_readiness(port),
+            };
+
+            if !readiness.is_empty() {
+                events.push(Event { token, readiness });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        let sources = std::mem::take(self.sources.get_mut().unwrap());
+        for source in sources.into_values() {
+            self.set_waker(source, None);
+        }
+    }
+}
+
+impl AsFd for Poller {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.eventfd.0.as_fd()
+    }
+}
+
+impl AsRawFd for Poller {
+    fn as_raw_fd(&self) -> RawFd {
+        self.eventfd.0.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use futures_lite::future;
+
+    use super::*;
+    use crate::stack::tests::{
+        establish, quiet_stack, receive, segment, transmit, LOCAL, REMOTE_ISN,
+    };
+
+    const PORT: u16 = 50000;
+
+    /// Whether the poller's descriptor is readable, without blocking
+    fn fired(poller: &Poller) -> bool {
+        let mut fd = libc::pollfd {
+            fd: poller.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let n = unsafe { libc::poll(&mut fd, 1, 0) };
+        assert!(n >= 0, "poll failed: {}", io::Error::last_os_error());
+        n == 1
+    }
+
+    fn poll(poller: &Poller) -> Vec<Event> {
+        let mut events = Vec::new();
+        poller.poll(&mut events).unwrap();
+        events
+    }
+
+    fn event(token: usize, readable: bool, writable: bool, hangup: bool) -> Event {
+        Event {
+            token,
+            readiness: Readiness {
+                readable,
+                writable,
+                hangup,
+            },
+        }
+    }
+
+    /// A poller watching a stream accepted over a handshake fed straight
+    /// into the stack under token 1, with the stack's ISN
+    fn watched_stream() -> (SharedStack, Poller, TcpStream, u32) {
+        let stack = Arc::new(Mutex::new(quiet_stack()));
+        let listener = TcpListener::bind(stack.clone(), LOCAL.1).unwrap();
+        let iss = establish(&mut stack.lock().unwrap(), PORT);
+        let stream = listener.accept().unwrap();
+
+        let poller = Poller::new(stack.clone()).unwrap();
+        poller.register(&stream, 1).unwrap();
+        (stack, poller, stream, iss)
+    }
+
+    #[test]
+    fn incoming_connection_fires_listener() {
+        let stack = Arc::new(Mutex::new(quiet_stack()));
+        let listener = TcpListener::bind(stack.clone(), LOCAL.1).unwrap();
+        let poller = Poller::new(stack.clone()).unwrap();
+        poller.register(&listener, 7).unwrap();
+
+        // Registering reports the current state, which is nothing yet
+        assert!(fired(&poller));
+        assert!(poll(&poller).is_empty());
+        assert!(!fired(&poller));
+
+        establish(&mut stack.lock().unwrap(), PORT);
+        assert!(fired(&poller));
+        assert_eq!(poll(&poller), [event(7, true, false, false)]);
+
+        listener.accept().unwrap();
+        assert!(poll(&poller).is_empty());
+    }
+
+    #[test]
+    fn arriving_data_fires_stream() {
+        let (stack, poller, _stream, iss) = watched_stream();
+        assert_eq!(poll(&poller), [event(1, false, true, false)]);
+        assert!(!fired(&poller));
+
+        let data = segment(PORT, REMOTE_ISN + 1, iss + 1, 0x18, b"hello");
+        receive(&mut stack.lock().unwrap(), &data);
+        assert!(fired(&poller));
+        assert_eq!(poll(&poller), [event(1, true, true, false)]);
+    }
+
+    #[test]
+    fn acknowledged_data_fires_writable() {
+        let (stack, poller, mut stream, iss) = watched_stream();
+        stream.set_send_buffer_size(4).unwrap();
+        stream.write_all(b"full").unwrap();
+        assert!(poll(&poller).is_empty());
+
+        transmit(&mut stack.lock().unwrap());
+        assert!(!fired(&poller));
+
+        let ack = segment(PORT, REMOTE_ISN + 1, iss + 5, 0x10, &[]);
+        receive(&mut stack.lock().unwrap(), &ack);
+        assert!(fired(&poller));
+        assert_eq!(poll(&poller), [event(1, false, true, false)]);
+    }
+
+    #[test]
+    fn poll_is_level_but_the_descriptor_is_edge() {
+        let (stack, poller, mut stream, iss) = watched_stream();
+        let data = segment(PORT, REMOTE_ISN + 1, iss + 1, 0x18, b"hello");
+        receive(&mut stack.lock().unwrap(), &data);
+        assert!(fired(&poller));
+
+        // Undrained readiness is reported again, but the descriptor stays
+        // quiet until something changes
+        assert_eq!(poll(&poller), [event(1, true, true, false)]);
+        assert!(!fired(&poller));
+        assert_eq!(poll(&poller), [event(1, true, true, false)]);
+
+        let mut buf = [0u8; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 5);
+        assert_eq!(poll(&poller), [event(1, false, true, false)]);
+
+        let more = segment(PORT, REMOTE_ISN + 6, iss + 1, 0x18, b"again");
+        receive(&mut stack.lock().unwrap(), &more);
+        assert!(fired(&poller));
+    }
+
+    #[test]
+    fn reset_fires_hangup() {
+        let (stack, poller, _stream, iss) = watched_stream();
+        poll(&poller);
+
+        let rst = segment(PORT, REMOTE_ISN + 1, iss + 1, 0x04, &[]);
+        receive(&mut stack.lock().unwrap(), &rst);
+        assert!(fired(&poller));
+        assert_eq!(poll(&poller), [event(1, true, false, true)]);
+    }
+
+    #[test]
+    fn deregistered_socket_is_not_reported() {
+        let (stack, poller, stream, iss) = watched_stream();
+        assert_eq!(
+            poller.register(&stream, 1).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+
+        poller.deregister(1).unwrap();
+        poll(&poller);
+        let data = segment(PORT, REMOTE_ISN + 1, iss + 1, 0x18, b"hello");
+        receive(&mut stack.lock().unwrap(), &data);
+        assert!(!fired(&poller));
+        assert!(poll(&poller).is_empty());
+        assert_eq!(
+            poller.deregister(1).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn async_handles_can_be_registered() {
+        let stack = Arc::new(Mutex::new(quiet_stack()));
+        let listener = AsyncTcpListener::bind(stack.clone(), LOCAL.1).unwrap();
+        let poller = Poller::new(stack.clone()).unwrap();
+        poller.register(&listener, 1).unwrap();
+        poll(&poller);
+
+        let iss = establish(&mut stack.lock().unwrap(), PORT);
+        assert!(fired(&poller));
+        assert_eq!(poll(&poller), [event(1, true, false, false)]);
+
+        let stream = future::block_on(listener.accept()).unwrap();
+        poller.register(&stream, 2).unwrap();
+        assert_eq!(poll(&poller), [event(2, false, true, false)]);
+
+        let data = segment(PORT, REMOTE_ISN + 1, iss + 1, 0x18, b"hello");
+        receive(&mut stack.lock().unwrap(), &data);
+        assert!(fired(&poller));
+        assert_eq!(poll(&poller), [event(2, true, true, false)]);
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+/// Maximum number of events collected by one `wait`
+const MAX_EVENTS: usize = 64;
+
+/// Level-triggered epoll instance waiting for descriptors to become readable.
+///
+/// Unlike `select`, registration is done once, descriptors of any number can
+/// be watched, and a wait costs the same however many are registered.
+#[derive(Debug)]
+pub struct Reactor {
+    epoll: OwnedFd,
+}
+
+impl Reactor {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            epoll: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Report `fd` under `token` whenever it is readable
+    pub fn register(&self, fd: BorrowedFd<'_>, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token,
+        };
+        self.ctl(libc::EPOLL_CTL_ADD, fd.as_raw_fd(), &mut event)
+    }
+
+    pub fn deregister(&self, fd: BorrowedFd<'_>) -> io::Result<()> {
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        self.ctl(libc::EPOLL_CTL_DEL, fd.as_raw_fd(), &mut event)
+    }
+
+    fn ctl(&self, op: libc::c_int, fd: RawFd, event: &mut libc::epoll_event) -> io::Result<()> {
+        if unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), op, fd, event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Wait until a registered descriptor is readable or `timeout` passes
+    /// (`None` waits forever), replacing `tokens` with the ready ones. A wait
+    /// interrupted by a signal returns no tokens.
+    pub fn wait(&self, tokens: &mut Vec<u64>, timeout: Option<Duration>) -> io::Result<()> {
+        tokens.clear();
+
+        // Round up so a timer is never polled just before its deadline
+        let timeout_ms = match timeout {
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let n = unsafe {
+            libc::epoll_wait(
+                self.epoll.as_raw_fd(),
+                events.as_mut_ptr(),
+                MAX_EVENTS as i32,
+                timeout_ms,
+            )
+        };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(err);
+        }
+
+        tokens.extend(events[..n as usize].iter().map(|event| event.u64));
+        Ok(())
+    }
+}
+
+impl AsFd for Reactor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll.as_fd()
+    }
+}
@@ -0,0 +1,324 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clock::{Clock, ManualClock};
+use crate::packet_sender::Frames;
+use crate::pcap::{Capture, Direction, Packet};
+use crate::stack::Stack;
+
+/// Replays a recorded capture through a `Stack` without a TUN device.
+///
+/// Datagrams the capturing host received are fed to the receive path at
+/// the virtual time their timestamps give, and connection timers fire in
+/// between just as they would have live. What the stack sends goes only to
+/// the capture set with `set_capture`, stamped in the recording's time, so
+/// the two files line up in Wireshark. Time is virtual, so a recording
+/// always replays the same way however long it spans.
+///
+/// Frames are replayed as the capture records them, so the peer's
+/// acknowledgment numbers only fit a stack choosing the same initial
+/// sequence numbers: captures of this stack replay faithfully, captures of
+/// other stacks show how this one would have handled the peer's segments.
+///
+/// ```text
+/// let mut replay = Replay::new();
+/// replay.set_capture(Capture::create("responses.pcapng")?);
+/// replay.run(&mut Reader::open("incident.pcap")?)?;
+/// replay.finish(Duration::from_secs(60))?;
+/// ```
+#[derive(Debug)]
+pub struct Replay {
+    stack: Stack,
+    clock: ManualClock,
+
+    /// Instant the first packet is replayed at
+    start: Instant,
+
+    /// Capture time of the first packet
+    epoch: Option<SystemTime>,
+
+    /// Address of the capturing host, for captures that do not record
+    /// direction: datagrams to it are replayed
+    local: Option<Ipv4Addr>,
+
+    /// Capture waiting for the first packet to know the recording's time
+    capture: Option<Capture>,
+
+    /// Transmit buffer for the stack's responses, which go nowhere
+    frames: Frames,
+
+    statistics: ReplayStatistics,
+}
+
+/// What happened to the datagrams of a replayed capture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayStatistics {
+    /// Datagrams fed to the stack
+    pub replayed: u64,
+
+    /// Datagrams the capturing host sent, or that were addressed to another host
+    pub ignored: u64,
+
+    /// Frames the stack sent in response
+    pub sent: u64,
+}
+
+impl Default for Replay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        let clock = ManualClock::new();
+        Self {
+            stack: Stack::with_clock(clock.clone()),
+            start: clock.now(),
+            clock,
+            epoch: None,
+            local: None,
+            capture: None,
+            frames: Frames::new(),
+            statistics: ReplayStatistics::default(),
+        }
+    }
+
+    /// Replay datagrams addressed to `local`. Needed only for captures
+    /// without direction; by default the destination of the first datagram
+    /// is taken as the capturing host.
+    pub fn set_local(&mut self, local: Ipv4Addr) {
+        self.local = Some(local);
+    }
+
+    /// Write the replayed datagrams and the stack's responses to `capture`
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture);
+    }
+
+    /// The stack being replayed into, e.g. to listen or tune it before the first packet
+    pub fn stack_mut(&mut self) -> &mut Stack {
+        &mut self.stack
+    }
+
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    pub fn statistics(&self) -> ReplayStatistics {
+        self.statistics
+    }
+
+    /// Virtual time since the first packet
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now() - self.start
+    }
+
+    /// Replay every packet of a capture, e.g. a `pcap::Reader`
+    pub fn run(&mut self, packets: impl IntoIterator<Item = io::Result<Packet>>) -> io::Result<()> {
+        for packet in packets {
+            self.feed(&packet?);
+        }
+        Ok(())
+    }
+
+    /// Run timers up to the packet's capture time, then hand it to the
+    /// stack if the capturing host received it
+    pub fn feed(&mut self, packet: &Packet) {
+        let epoch = *self.epoch.get_or_insert(packet.time);
+        if let Some(capture) = self.capture.take() {
+            self.stack
+                .set_capture(Some(capture.starting_at(self.start, epoch)));
+        }
+
+        // Captures are not always in order; late packets replay at once
+        let offset = packet.time.duration_since(epoch).unwrap_or_default();
+        self.run_timers(self.start + offset);
+
+        if !self.is_inbound(packet) {
+            self.statistics.ignored += 1;
+            return;
+        }
+
+        let mut frame = Vec::with_capacity(4 + packet.datagram.len());
+        frame.extend_from_slice(&[0, 0, 0x08, 0x00]);
+        frame.extend_from_slice(&packet.datagram);
+
+        self.stack.process_frame(&frame, &mut self.frames);
+        self.stack.poll_transmit(&mut self.frames);
+        self.statistics.replayed += 1;
+        self.discard_frames();
+    }
+
+    /// Let `linger` of virtual time pass after the last packet, so the
+    /// stack's retransmissions and timeouts play out, then flush the capture
+    pub fn finish(&mut self, linger: Duration) -> io::Result<()> {
+        self.run_timers(self.clock.now() + linger);
+        match self.stack.set_capture(None) {
+            Some(mut capture) => capture.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn is_inbound(&mut self, packet: &Packet) -> bool {
+        if let Some(direction) = packet.direction {
+            return direction == Direction::Inbound;
+        }
+        let Some(&[a, b, c, d]) = packet.datagram.get(16..20) else {
+            // Let the stack count it as malformed
+            return true;
+        };
+        let destination = Ipv4Addr::new(a, b, c, d);
+        *self.local.get_or_insert(destination) == destination
+    }
+
+    /// Fire every timer due up to `until`, leaving the clock there
+    fn run_timers(&mut self, until: Instant) {
+        while let Some(timeout) = self.stack.next_timeout() {
+            let deadline = self.clock.now() + timeout;
+            if deadline > until {
+                break;
+            }
+            self.clock.set(deadline);
+            self.stack.on_timer(&mut self.frames);
+            self.stack.poll_transmit(&mut self.frames);
+            self.discard_frames();
+        }
+        self.clock.set(until);
+    }
+
+    /// Count the frames the stack sent, then drop them
+    fn discard_frames(&mut self) {
+        self.statistics.sent += self.frames.len() as u64;
+        self.frames.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::task::{Poll, Waker};
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::options::TcpOptions;
+    use crate::packet_sender::SegmentBuilder;
+    use crate::pcap::tests::SharedFile;
+    use crate::pcap::{Format, Reader};
+    use crate::stack::tests::{remote, segment, LOCAL, REMOTE_ISN};
+    use crate::trace::Tracer;
+
+    const PORT: u16 = 5000;
+
+    fn packet(seconds: f64, direction: Option<Direction>, frame: Vec<u8>) -> io::Result<Packet> {
+        Ok(Packet {
+            time: UNIX_EPOCH
+                + Duration::from_secs(1_700_000_000)
+                + Duration::from_secs_f64(seconds),
+            direction,
+            datagram: frame[4..].to_vec(),
+        })
+    }
+
+    /// The SYN-ACK the capturing host sent, which replay leaves to the stack
+    fn recorded_syn_ack() -> Vec<u8> {
+        let options = TcpOptions {
+            mss: Some(1460),
+            ..TcpOptions::default()
+        };
+        let mut frame = vec![0u8; 64];
+        let len = SegmentBuilder::new(LOCAL, remote(PORT))
+            .seq(1000)
+            .ack(REMOTE_ISN + 1)
+            .flags(0x12)
+            .window(65535)
+            .options(&options)
+            .write(&mut frame[4..])
+            .unwrap();
+        frame.truncate(4 + len);
+        frame
+    }
+
+    /// A client connecting and sending five bytes, as the server recorded it
+    fn recording(direction: impl Fn(Direction) -> Option<Direction>) -> Vec<io::Result<Packet>> {
+        vec![
+            packet(
+                0.0,
+                direction(Direction::Inbound),
+                segment(PORT, REMOTE_ISN, 0, 0x02, &[]),
+            ),
+            packet(0.0, direction(Direction::Outbound), recorded_syn_ack()),
+            packet(
+                0.01,
+                direction(Direction::Inbound),
+                segment(PORT, REMOTE_ISN + 1, 1001, 0x10, &[]),
+            ),
+            packet(
+                0.02,
+                direction(Direction::Inbound),
+                segment(PORT, REMOTE_ISN + 1, 1001, 0x18, b"hello"),
+            ),
+        ]
+    }
+
+    fn replay() -> Replay {
+        let mut replay = Replay::new();
+        replay.stack_mut().set_tracer(Tracer::disabled());
+        replay.stack_mut().listen(LOCAL.1).unwrap();
+        replay
+    }
+
+    /// The replayed stack accepted the connection and received its data
+    fn assert_received_hello(replay: &mut Replay) {
+        let quad = replay
+            .stack_mut()
+            .poll_accept(LOCAL.1, Waker::noop())
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let read = replay.stack_mut().poll_read(quad, &mut buf, Waker::noop());
+        assert!(matches!(read, Poll::Ready(Ok(5))));
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test]
+    fn replay_feeds_received_datagrams_and_captures_responses() {
+        let file = SharedFile::default();
+        let mut replay = replay();
+        replay.set_capture(Capture::new(file.clone(), Format::Pcapng).unwrap());
+        replay.run(recording(Some)).unwrap();
+        replay.finish(Duration::from_secs(1)).unwrap();
+
+        let statistics = replay.statistics();
+        assert_eq!(statistics.replayed, 3);
+        assert_eq!(statistics.ignored, 1);
+        assert!(statistics.sent >= 2);
+        assert_received_hello(&mut replay);
+
+        // The stack's answers are stamped in the recording's time
+        let bytes = file.0.lock().unwrap().clone();
+        let packets: Vec<Packet> = Reader::new(Cursor::new(bytes))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let expected = recording(Some);
+        assert_eq!(packets[0], *expected[0].as_ref().unwrap());
+        assert_eq!(packets[1].direction, Some(Direction::Outbound));
+        assert_eq!(packets[1].time, expected[0].as_ref().unwrap().time);
+        // A capture of this stack replays faithfully
+        assert_eq!(packets[1].datagram, expected[1].as_ref().unwrap().datagram);
+        assert_eq!(packets.len() as u64, statistics.replayed + statistics.sent);
+    }
+
+    #[test]
+    fn replay_without_direction_feeds_datagrams_to_the_first_destination() {
+        let mut replay = replay();
+        replay.run(recording(|_| None)).unwrap();
+        replay.finish(Duration::from_secs(1)).unwrap();
+
+        let statistics = replay.statistics();
+        assert_eq!(statistics.replayed, 3);
+        assert_eq!(statistics.ignored, 1);
+        assert_received_hello(&mut replay);
+    }
+}
@@ -0,0 +1,629 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::Ipv4Addr;
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, ManualClock};
+use crate::options::TcpOptions;
+use crate::packet_sender::{Frames, SegmentBuilder};
+use crate::parser::{Ipv4View, TcpView};
+use crate::stack::Stack;
+use crate::tcb::Quad;
+
+// packetdrill-style scripts (https://github.com/google/packetdrill). Each
+// line starts with a time, absolute ("0.100") or relative to the previous
+// line ("+0", "+.04"), followed by a segment or a call:
+//
+//     0     < S 0:0(0) win 32792 <mss 1000,sackOK,TS val 100 ecr 0>
+//     +0    > S. 0:0(0) ack 1 <mss 1460,TS val 0 ecr 100>
+//     +.1   < . 1:1(0) ack 1 win 32792
+//     +0    write(1000) = 1000
+//     +0    > P. 1:1001(1000) ack 1
+//
+// "<" injects a segment from the remote end, ">" expects the stack to send
+// one at that time. Flags are S, F, R, P and "." for ACK. Sequence numbers
+// are relative to the sender's ISN and acknowledgment numbers to the
+// receiver's, as in packetdrill. Calls drive the application side:
+// connect(), write(n), read(n), close(), each optionally checked against a byte
+// count or an `io::ErrorKind` name ("= 1000", "= ConnectionReset").
+
+/// Address and port the stack under test uses
+pub const LOCAL: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 168, 0, 1), 8080);
+
+/// Address and port of the scripted remote end
+pub const REMOTE: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 0, 2, 1), 50000);
+
+/// ISN of the scripted remote end
+const REMOTE_ISN: u32 = 1_000_000;
+
+/// How far from its scripted time an outbound segment may be sent
+const TOLERANCE: Duration = Duration::from_millis(4);
+
+/// Window of injected segments that do not give one
+const DEFAULT_WINDOW: u16 = 65535;
+
+/// A parsed script, ready to run against a fresh stack
+#[derive(Debug, Clone)]
+pub struct Script {
+    lines: Vec<Line>,
+}
+
+/// Why a script failed, and on which line (1-based)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+#[derive(Debug, Clone)]
+struct Line {
+    number: usize,
+    time: Duration,
+    action: Action,
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+    Inject(Segment),
+    Expect(Segment),
+    Call { call: Call, result: Option<Outcome> },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Call {
+    Connect,
+    Write(usize),
+    Read(usize),
+    Close,
+}
+
+/// Result of a call: a byte count or an error
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    Bytes(usize),
+    Error(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Bytes(n) => write!(f, "{}", n),
+            Outcome::Error(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+
+/// A segment as written in a script, with relative sequence numbers
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    flags: u8,
+    seq: u32,
+    len: u32,
+    ack: Option<u32>,
+    window: Option<u16>,
+    options: TcpOptions,
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}:{}({})",
+            flags_str(self.flags),
+            self.seq,
+            self.seq.wrapping_add(self.len),
+            self.len
+        )?;
+        if let Some(ack) = self.ack {
+            write!(f, " ack {}", ack)?;
+        }
+        if let Some(window) = self.window {
+            write!(f, " win {}", window)?;
+        }
+        if self.options != TcpOptions::default() {
+            write!(f, " <{}>", options_str(&self.options))?;
+        }
+        Ok(())
+    }
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Self, ScriptError> {
+        let mut lines = Vec::new();
+        let mut time = Duration::ZERO;
+
+        for (index, raw) in text.lines().enumerate() {
+            let number = index + 1;
+            let error = |message: String| ScriptError {
+                line: number,
+                message,
+            };
+
+            let content = raw.split("//").next().unwrap_or("").trim();
+            if content.is_empty() {
+                continue;
+            }
+
+            let (time_spec, rest) = content
+                .split_once(char::is_whitespace)
+                .unwrap_or((content, ""));
+            time = match time_spec.strip_prefix('+') {
+                Some(delta) => {
+                    time + parse_seconds(delta)
+                        .ok_or_else(|| error(format!("bad time {:?}", time_spec)))?
+                }
+                None => parse_seconds(time_spec)
+                    .ok_or_else(|| error(format!("bad time {:?}", time_spec)))?,
+            };
+
+            let rest = rest.trim();
+            let action = if let Some(segment) = rest.strip_prefix('<') {
+                Action::Inject(parse_segment(segment).map_err(error)?)
+            } else if let Some(segment) = rest.strip_prefix('>') {
+                Action::Expect(parse_segment(segment).map_err(error)?)
+            } else {
+                parse_call(rest).map_err(error)?
+            };
+
+            lines.push(Line {
+                number,
+                time,
+                action,
+            });
+        }
+
+        Ok(Self { lines })
+    }
+
+    /// Run the script against a new stack on a virtual clock
+    pub fn run(&self) -> Result<(), ScriptError> {
+        let mut device = Device::new();
+
+        for line in &self.lines {
+            let error = |message: String| ScriptError {
+                line: line.number,
+                message,
+            };
+
+            match &line.action {
+                Action::Expect(expected) => device.expect(expected, line.time).map_err(error)?,
+                Action::Inject(segment) => {
+                    device.settle(line.time).map_err(error)?;
+                    device.inject(segment);
+                }
+                Action::Call { call, result } => {
+                    device.settle(line.time).map_err(error)?;
+                    let outcome = device.call(*call);
+                    if let Some(expected) = result.as_ref().filter(|&expected| expected != &outcome)
+                    {
+                        return Err(error(format!(
+                            "{:?} returned {}, expected {}",
+                            call, outcome, expected
+                        )));
+                    }
+                }
+            }
+        }
+
+        // Nothing may be left unexpected at the end either
+        if let Some(last) = self.lines.last() {
+            device.settle(last.time).map_err(|message| ScriptError {
+                line: last.number,
+                message,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse and run a script
+pub fn run(text: &str) -> Result<(), ScriptError> {
+    Script::parse(text)?.run()
+}
+
+/// In-memory network device: segments go straight into the stack, and what
+/// it sends is queued with the virtual time it was sent at
+#[derive(Debug)]
+struct Device {
+    stack: Stack,
+    clock: ManualClock,
+    start: Instant,
+    quad: Quad,
+    outbound: VecDeque<(Duration, Vec<u8>)>,
+
+    /// Transmit buffer the stack writes into, emptied into `outbound`
+    frames: Frames,
+
+    /// Learned from the first SYN the stack sends
+    local_isn: Option<u32>,
+}
+
+impl Device {
+    fn new() -> Self {
+        let clock = ManualClock::new();
+        let mut stack = Stack::with_clock(clock.clone());
+        stack.listen(LOCAL.1).expect("fresh stack has no listeners");
+        Self {
+            stack,
+            start: clock.now(),
+            clock,
+            quad: Quad {
+                src: REMOTE,
+                dst: LOCAL,
+            },
+            outbound: VecDeque::new(),
+            frames: Frames::new(),
+            local_isn: None,
+        }
+    }
+
+    fn now(&self) -> Duration {
+        self.clock.now() - self.start
+    }
+
+    /// Move the clock to `time`, firing every timer due on the way at its deadline
+    fn advance(&mut self, time: Duration) {
+        self.run_timers(time, false);
+    }
+
+    /// Fire timers due by `time` at their deadlines, stopping early at the
+    /// first one that sends something if `until_sent`
+    fn run_timers(&mut self, time: Duration, until_sent: bool) {
+        while let Some(timeout) = self.stack.next_timeout() {
+            let deadline = self.now() + timeout;
+            if deadline > time {
+                break;
+            }
+            self.clock.set(self.start + deadline);
+            self.stack.on_timer(&mut self.frames);
+            self.stack.poll_transmit(&mut self.frames);
+            self.queue();
+            if until_sent && !self.outbound.is_empty() {
+                return;
+            }
+        }
+        self.clock.set(self.start + time);
+    }
+
+    /// Check that the next segment sent is `expected`, within the tolerance of `time`
+    fn expect(&mut self, expected: &Segment, time: Duration) -> Result<(), String> {
+        self.advance(time.saturating_sub(TOLERANCE));
+        let (sent_at, datagram) = self
+            .next_outbound(time + TOLERANCE)
+            .ok_or_else(|| format!("expected {} at {:?}, nothing was sent", expected, time))?;
+
+        let actual = self.describe(&datagram);
+        if sent_at.abs_diff(time) > TOLERANCE {
+            return Err(format!(
+                "expected {} at {:?}, sent at {:?}",
+                expected, time, sent_at
+            ));
+        }
+        if !matches(expected, &actual) {
+            return Err(format!("expected {}, sent {}", expected, actual));
+        }
+        Ok(())
+    }
+
+    /// Move the clock to `time`, failing if the stack sent anything the
+    /// script did not expect
+    fn settle(&mut self, time: Duration) -> Result<(), String> {
+        self.advance(time);
+        match self.outbound.pop_front() {
+            Some((sent_at, datagram)) => Err(format!(
+                "unexpected {} sent at {:?}",
+                self.describe(&datagram),
+                sent_at
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The next segment sent no later than `deadline`
+    fn next_outbound(&mut self, deadline: Duration) -> Option<(Duration, Vec<u8>)> {
+        if self.outbound.is_empty() {
+            self.run_timers(deadline, true);
+        }
+        self.outbound.pop_front()
+    }
+
+    /// Move what the stack sent into `outbound`
+    fn queue(&mut self) {
+        let now = self.now();
+        for frame in &self.frames {
+            let datagram = frame[4..].to_vec();
+
+            // Sequence numbers of what we send are relative to our SYN
+            if self.local_isn.is_none() && datagram[33] & 0x02 != 0 {
+                self.local_isn = TcpView::new(&datagram[20..])
+                    .ok()
+                    .map(|tcp| tcp.sequence_number());
+            }
+            self.outbound.push_back((now, datagram));
+        }
+        self.frames.clear();
+    }
+
+    fn inject(&mut self, segment: &Segment) {
+        let payload = vec![0u8; segment.len as usize];
+        let local_isn = self.local_isn.unwrap_or(0);
+
+        let mut frame = vec![0u8; 1504];
+        frame[2..4].copy_from_slice(&0x0800u16.to_be_bytes());
+        let len = SegmentBuilder::new(REMOTE, LOCAL)
+            .seq(REMOTE_ISN.wrapping_add(segment.seq))
+            .ack(segment.ack.map_or(0, |ack| local_isn.wrapping_add(ack)))
+            .flags(segment.flags)
+            .window(segment.window.unwrap_or(DEFAULT_WINDOW))
+            .options(&segment.options)
+            .payload(&payload)
+            .write(&mut frame[4..])
+            .expect("scripted segment exceeds the MTU");
+        frame.truncate(4 + len);
+
+        self.stack.process_frame(&frame, &mut self.frames);
+        self.stack.poll_transmit(&mut self.frames);
+        self.queue();
+
+        // Calls act on the connection as an application accepting it as soon
+        // as it is established would
+        self.stack.poll_accept(LOCAL.1, Waker::noop());
+    }
+
+    fn call(&mut self, call: Call) -> Outcome {
+        let result = match call {
+            Call::Connect => self
+                .stack
+                .connect(LOCAL, REMOTE, &mut self.frames)
+                .map(|_| 0),
+            Call::Write(len) => ready(self.stack.poll_write(
+                self.quad,
+                &vec![0u8; len],
+                Waker::noop(),
+            )),
+            Call::Read(len) => ready(self.stack.poll_read(
+                self.quad,
+                &mut vec![0u8; len],
+                Waker::noop(),
+            )),
+            Call::Close => self.stack.close(self.quad).map(|()| 0),
+        };
+
+        self.stack.poll_transmit(&mut self.frames);
+        self.queue();
+
+        match result {
+            Ok(n) => Outcome::Bytes(n),
+            Err(e) => Outcome::Error(format!("{:?}", e.kind())),
+        }
+    }
+
+    /// A sent datagram in script form, relative to both ISNs
+    fn describe(&self, datagram: &[u8]) -> Segment {
+        let ip = Ipv4View::new(datagram).expect("stack sent a malformed datagram");
+        let tcp = TcpView::new(ip.payload()).expect("stack sent a malformed segment");
+        let flags = tcp.control_bit();
+        Segment {
+            flags,
+            seq: tcp
+                .sequence_number()
+                .wrapping_sub(self.local_isn.unwrap_or(0)),
+            len: tcp.payload().len() as u32,
+            ack: (flags & 0x10 != 0).then(|| tcp.acknowledge_number().wrapping_sub(REMOTE_ISN)),
+            window: Some(tcp.window()),
+            options: tcp.options(),
+        }
+    }
+}
+
+fn ready(poll: Poll<io::Result<usize>>) -> io::Result<usize> {
+    match poll {
+        Poll::Ready(result) => result,
+        Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+    }
+}
+
+/// Whether a sent segment is the expected one; the window is only checked
+/// if the script gives it
+fn matches(expected: &Segment, actual: &Segment) -> bool {
+    expected.flags == actual.flags
+        && expected.seq == actual.seq
+        && expected.len == actual.len
+        && expected.ack == actual.ack
+        && expected
+            .window
+            .is_none_or(|window| Some(window) == actual.window)
+        && expected.options == actual.options
+}
+
+fn parse_seconds(text: &str) -> Option<Duration> {
+    let seconds: f64 = text.parse().ok()?;
+    (seconds >= 0.0).then(|| Duration::from_micros((seconds * 1e6).round() as u64))
+}
+
+/// "S. 0:0(0) ack 1 win 1000 <mss 1460>"
+fn parse_segment(text: &str) -> Result<Segment, String> {
+    let (text, options) = match text.split_once('<') {
+        Some((head, options)) => {
+            let options = options.strip_suffix('>').ok_or("unterminated options")?;
+            (head, parse_options(options)?)
+        }
+        None => (text, TcpOptions::default()),
+    };
+
+    let mut words = text.split_whitespace();
+    let flags = parse_flags(words.next().ok_or("missing flags")?)?;
+
+    let range = words.next().ok_or("missing sequence numbers")?;
+    let (seq, rest) = range
+        .split_once(':')
+        .ok_or_else(|| format!("bad sequence numbers {:?}", range))?;
+    let (end, len) = rest
+        .strip_suffix(')')
+        .and_then(|rest| rest.split_once('('))
+        .ok_or_else(|| format!("bad sequence numbers {:?}", range))?;
+    let seq: u32 = parse_number(seq)?;
+    let end: u32 = parse_number(end)?;
+    let len: u32 = parse_number(len)?;
+    if end.wrapping_sub(seq) != len {
+        return Err(format!("{:?} does not span {} bytes", range, len));
+    }
+
+    let mut segment = Segment {
+        flags,
+        seq,
+        len,
+        ack: None,
+        window: None,
+        options,
+    };
+    while let Some(word) = words.next() {
+        let value = words
+            .next()
+            .ok_or_else(|| format!("missing value for {:?}", word))?;
+        match word {
+            "ack" => segment.ack = Some(parse_number(value)?),
+            "win" => segment.window = Some(parse_number(value)?),
+            _ => return Err(format!("unknown field {:?}", word)),
+        }
+    }
+
+    if (segment.flags & 0x10 != 0) != segment.ack.is_some() {
+        return Err("ack must be given exactly when the ACK flag is set".to_string());
+    }
+    Ok(segment)
+}
+
+fn parse_flags(text: &str) -> Result<u8, String> {
+    text.chars().try_fold(0u8, |flags, c| {
+        Ok(flags
+            | match c {
+                'F' => 0x01,
+                'S' => 0x02,
+                'R' => 0x04,
+                'P' => 0x08,
+                '.' => 0x10,
+                'U' => 0x20,
+                'E' => 0x40,
+                'W' => 0x80,
+                _ => return Err(format!("unknown flag {:?}", c)),
+            })
+    })
+}
+
+fn flags_str(flags: u8) -> String {
+    [
+        (0x01, 'F'),
+        (0x02, 'S'),
+        (0x04, 'R'),
+        (0x08, 'P'),
+        (0x20, 'U'),
+        (0x40, 'E'),
+        (0x80, 'W'),
+        (0x10, '.'),
+    ]
+    .iter()
+    .filter(|(bit, _)| flags & bit != 0)
+    .map(|&(_, c)| c)
+    .collect()
+}
+
+/// "mss 1460,sackOK,TS val 100 ecr 0,nop,wscale 7,sack 1:2"
+fn parse_options(text: &str) -> Result<TcpOptions, String> {
+    let mut options = TcpOptions::default();
+
+    for option in text.split(',') {
+        let words: Vec<&str> = option.split_whitespace().collect();
+        match words.as_slice() {
+            ["nop"] | ["eol"] => {}
+            ["mss", mss] => options.mss = Some(parse_number(mss)?),
+            ["wscale", shift] => options.window_scale = Some(parse_number(shift)?),
+            ["sackOK"] => options.sack_permitted = true,
+            ["TS", "val", tsval, "ecr", tsecr] => {
+                options.timestamps = Some((parse_number(tsval)?, parse_number(tsecr)?))
+            }
+            ["sack", blocks @ ..] => {
+                for block in blocks {
+                    let (left, right) = block
+                        .split_once(':')
+                        .ok_or_else(|| format!("bad SACK block {:?}", block))?;
+                    options
+                        .sack_blocks
+                        .push((parse_number(left)?, parse_number(right)?));
+                }
+            }
+            _ => return Err(format!("unknown option {:?}", option.trim())),
+        }
+    }
+
+    Ok(options)
+}
+
+fn options_str(options: &TcpOptions) -> String {
+    let mut parts = Vec::new();
+    if let Some(mss) = options.mss {
+        parts.push(format!("mss {}", mss));
+    }
+    if options.sack_permitted {
+        parts.push("sackOK".to_string());
+    }
+    if let Some((tsval, tsecr)) = options.timestamps {
+        parts.push(format!("TS val {} ecr {}", tsval, tsecr));
+    }
+    if let Some(shift) = options.window_scale {
+        parts.push(format!("wscale {}", shift));
+    }
+    if !options.sack_blocks.is_empty() {
+        let blocks: Vec<String> = options
+            .sack_blocks
+            .iter()
+            .map(|(l, r)| format!("{}:{}", l, r))
+            .collect();
+        parts.push(format!("sack {}", blocks.join(" ")));
+    }
+    parts.join(",")
+}
+
+/// "connect()", "write(1000) = 1000", "read(10) = ConnectionReset"
+fn parse_call(text: &str) -> Result<Action, String> {
+    let (call, result) = match text.split_once('=') {
+        Some((call, result)) => {
+            let result = result.trim();
+            let result = match result.parse() {
+                Ok(n) => Outcome::Bytes(n),
+                Err(_) => Outcome::Error(result.to_string()),
+            };
+            (call.trim(), Some(result))
+        }
+        None => (text, None),
+    };
+
+    let (name, args) = call
+        .strip_suffix(')')
+        .and_then(|call| call.split_once('('))
+        .ok_or_else(|| format!("expected a segment or a call, found {:?}", text))?;
+    let args = args.trim();
+
+    let call = match name.trim() {
+        "connect" if args.is_empty() => Call::Connect,
+        "write" => Call::Write(parse_number(args)?),
+        "read" => Call::Read(parse_number(args)?),
+        "close" if args.is_empty() => Call::Close,
+        name => return Err(format!("unknown call {:?}", name)),
+    };
+    Ok(Action::Call { call, result })
+}
+
+fn parse_number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("bad number {:?}", text))
+}
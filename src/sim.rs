@@ -0,0 +1,604 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, ManualClock};
+use crate::packet_sender::Frames;
+use crate::stack::Stack;
+use crate::tcb::Quad;
+
+/// Deterministic discrete-event network simulator.
+///
+/// Hosts each run a `Stack` and exchange frames over simulated links with
+/// configurable delay, bandwidth, loss, reordering and duplication. Time is
+/// virtual: the simulator jumps from one event to the next instead of
+/// sleeping, so a transfer spanning minutes of retransmission timeouts
+/// replays in milliseconds. All randomness comes from one seeded generator,
+/// so a seed always replays the same run.
+///
+/// ```
+/// # use std::net::Ipv4Addr;
+/// # use std::time::Duration;
+/// # use tcp::sim::{Link, Simulator};
+/// # fn main() -> std::io::Result<()> {
+/// let mut sim = Simulator::new(7);
+/// let client = sim.add_host(Ipv4Addr::new(10, 0, 0, 1));
+/// let server = sim.add_host(Ipv4Addr::new(10, 0, 0, 2));
+/// sim.link(client, server, Link { loss: 0.01, ..Link::default() });
+///
+/// sim.with_stack(server, |stack| stack.listen(80))?;
+/// let quad = sim.connect(client, 5000, server, 80)?;
+/// assert!(sim.run_until(Duration::from_secs(5), |sim| sim.stack(client).stream_readiness(quad).writable));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Simulator {
+    /// Clock shared by every host's stack, standing still between events
+    clock: ManualClock,
+
+    /// Instant of virtual time zero
+    start: Instant,
+
+    /// Virtual time since `start`
+    now: Duration,
+
+    rng: Rng,
+    hosts: Vec<Host>,
+
+    /// Link configuration and state per direction, keyed by (from, to)
+    links: HashMap<(HostId, HostId), LinkState>,
+
+    /// Pending events, earliest first
+    events: BinaryHeap<Reverse<Scheduled>>,
+
+    /// Events scheduled so far, ordering simultaneous events
+    scheduled: u64,
+
+    /// Transmit buffer the stack being run writes into
+    frames: Frames,
+
+    statistics: SimStatistics,
+}
+
+/// Index of a host in its simulator
+pub type HostId = usize;
+
+#[derive(Debug)]
+struct Host {
+    address: Ipv4Addr,
+    stack: Stack,
+
+    /// Virtual time the host's next timer event is scheduled for; earlier
+    /// timer events left in the queue are stale
+    timer_at: Option<Duration>,
+}
+
+#[derive(Debug)]
+enum Event {
+    Deliver { host: HostId, frame: Vec<u8> },
+    Timer { host: HostId },
+}
+
+/// An event due at `time`; events due at the same time run in the order
+/// they were scheduled
+#[derive(Debug)]
+struct Scheduled {
+    time: Duration,
+    order: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.time, self.order) == (other.time, other.order)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.time, self.order).cmp(&(other.time, other.order))
+    }
+}
+
+/// Behaviour of one direction of a link
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Link {
+    /// One-way propagation delay
+    pub delay: Duration,
+
+    /// Bytes per second the link serializes frames at, or `None` for no limit
+    pub bandwidth: Option<u64>,
+
+    /// Probability that a frame is dropped
+    pub loss: f64,
+
+    /// Probability that a frame is held back by up to `reorder_delay`, so
+    /// frames sent after it can overtake it
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+
+    /// Probability that a frame is delivered twice
+    pub duplicate: f64,
+}
+
+impl Default for Link {
+    /// A 10 ms link without limits or faults
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(10),
+            bandwidth: None,
+            loss: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(10),
+            duplicate: 0.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LinkState {
+    link: Link,
+
+    /// Virtual time the link finishes serializing the frames queued on it
+    busy_until: Duration,
+}
+
+/// What happened to the frames hosts sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimStatistics {
+    pub sent: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    pub reordered: u64,
+    pub duplicated: u64,
+
+    /// Frames addressed to no host, or to a host without a link to it
+    pub unroutable: u64,
+}
+
+impl Simulator {
+    /// Empty network whose faults are drawn from `seed`
+    pub fn new(seed: u64) -> Self {
+        let clock = ManualClock::new();
+        Self {
+            start: clock.now(),
+            clock,
+            now: Duration::ZERO,
+            rng: Rng::new(seed),
+            hosts: Vec::new(),
+            links: HashMap::new(),
+            events: BinaryHeap::new(),
+            scheduled: 0,
+            frames: Frames::new(),
+            statistics: SimStatistics::default(),
+        }
+    }
+
+    /// Virtual time elapsed since the simulation started
+    pub fn elapsed(&self) -> Duration {
+        self.now
+    }
+
+    pub fn statistics(&self) -> SimStatistics {
+        self.statistics
+    }
+
+    /// Add a host with a fresh stack running on virtual time
+    pub fn add_host(&mut self, address: Ipv4Addr) -> HostId {
+        let stack = Stack::with_clock(self.clock.clone());
+        self.hosts.push(Host {
+            address,
+            stack,
+            timer_at: None,
+        });
+        self.hosts.len() - 1
+    }
+
+    pub fn address(&self, host: HostId) -> Ipv4Addr {
+        self.hosts[host].address
+    }
+
+    pub fn stack(&self, host: HostId) -> &Stack {
+        &self.hosts[host].stack
+    }
+
+    /// Connect two hosts with `link` in both directions
+    pub fn link(&mut self, a: HostId, b: HostId, link: Link) {
+        self.link_one_way(a, b, link);
+        self.link_one_way(b, a, link);
+    }
+
+    /// Connect `from` to `to` with `link`, replacing any link between them
+    /// in that direction
+    pub fn link_one_way(&mut self, from: HostId, to: HostId, link: Link) {
+        self.links.insert(
+            (from, to),
+            LinkState {
+                link,
+                busy_until: Duration::ZERO,
+            },
+        );
+    }
+
+    /// Run `f` on a host's stack at the current virtual time, then send
+    /// whatever it queued
+    pub fn with_stack<T>(&mut self, host: HostId, f: impl FnOnce(&mut Stack) -> T) -> T {
+        let value = f(&mut self.hosts[host].stack);
+        self.hosts[host].stack.poll_transmit(&mut self.frames);
+        self.send(host);
+        self.schedule_timer(host);
+        value
+    }
+
+    /// Open a connection from `port` on `client` to `server_port` on
+    /// `server`, returning its quad on the client
+    pub fn connect(
+        &mut self,
+        client: HostId,
+        port: u16,
+        server: HostId,
+        server_port: u16,
+    ) -> io::Result<Quad> {
+        let local = (self.address(client), port);
+        let remote = (self.address(server), server_port);
+        self.with_stack(client, |stack| stack.open(local, remote))
+    }
+
+    /// Process the next event, returning false once there are none left
+    pub fn step(&mut self) -> bool {
+        let Some(Reverse(Scheduled { time, event, .. })) = self.events.pop() else {
+            return false;
+        };
+        self.advance_to(time);
+
+        let host = match event {
+            Event::Deliver { host, frame } => {
+                self.statistics.delivered += 1;
+                let stack = &mut self.hosts[host].stack;
+                stack.process_frame(&frame, &mut self.frames);
+                stack.poll_transmit(&mut self.frames);
+                self.send(host);
+                host
+            }
+            Event::Timer { host } => {
+                if self.hosts[host].timer_at != Some(time) {
+                    return true;
+                }
+                self.hosts[host].timer_at = None;
+                let stack = &mut self.hosts[host].stack;
+                stack.on_timer(&mut self.frames);
+                stack.poll_transmit(&mut self.frames);
+                self.send(host);
+                host
+            }
+        };
+        self.schedule_timer(host);
+        true
+    }
+
+    /// Run events until `condition` holds or `limit` of virtual time has
+    /// passed, returning whether the condition was met
+    pub fn run_until(
+        &mut self,
+        limit: Duration,
+        mut condition: impl FnMut(&mut Self) -> bool,
+    ) -> bool {
+        let deadline = self.now + limit;
+        loop {
+            if condition(self) {
+                return true;
+            }
+            if self.next_event_time().is_none_or(|time| time > deadline) || !self.step() {
+                self.advance_to(deadline);
+                return condition(self);
+            }
+        }
+    }
+
+    /// Run every event due within `duration` of virtual time
+    pub fn run_for(&mut self, duration: Duration) {
+        self.run_until(duration, |_| false);
+    }
+
+    fn next_event_time(&self) -> Option<Duration> {
+        self.events.peek().map(|Reverse(scheduled)| scheduled.time)
+    }
+
+    fn advance_to(&mut self, time: Duration) {
+        self.now = self.now.max(time);
+        self.clock.set(self.start + self.now);
+    }
+
+    /// Put the frames a host wrote into the transmit buffer on the links
+    /// towards their destinations
+    fn send(&mut self, from: HostId) {
+        let mut frames = std::mem::take(&mut self.frames);
+
+        // The stack's tables are hash maps, so frames produced together come
+        // out in no fixed order; sort them so a seed always replays the same run
+        let mut sorted: Vec<&[u8]> = frames.iter().collect();
+        sorted.sort_unstable();
+
+        for frame in sorted {
+            self.statistics.sent += 1;
+
+            let destination = Ipv4Addr::new(frame[20], frame[21], frame[22], frame[23]);
+
+            let to = self
+                .hosts
+                .iter()
+                .position(|host| host.address == destination);
+            let Some((to, state)) = to.and_then(|to| Some((to, self.links.get_mut(&(from, to))?)))
+            else {
+                self.statistics.unroutable += 1;
+                continue;
+            };
+            let link = state.link;
+
+            // Frames leave one after another at the link's bandwidth
+            let transmission = link.bandwidth.map_or(Duration::ZERO, |rate| {
+                Duration::from_secs_f64(frame.len() as f64 / rate as f64)
+            });
+            state.busy_until = state.busy_until.max(self.now) + transmission;
+            let departure = state.busy_until;
+
+            if self.rng.chance(link.loss) {
+                self.statistics.lost += 1;
+                continue;
+            }
+
+            let mut arrival = departure + link.delay;
+            if self.rng.chance(link.reorder) {
+                self.statistics.reordered += 1;
+                arrival += link.reorder_delay.mul_f64(self.rng.unit());
+            }
+
+            if self.rng.chance(link.duplicate) {
+                self.statistics.duplicated += 1;
+                self.schedule(
+                    arrival,
+                    Event::Deliver {
+                        host: to,
+                        frame: frame.to_vec(),
+                    },
+                );
+            }
+            self.schedule(
+                arrival,
+                Event::Deliver {
+                    host: to,
+                    frame: frame.to_vec(),
+                },
+            );
+        }
+
+        frames.clear();
+        self.frames = frames;
+    }
+
+    /// Queue a timer event for the host's earliest connection timer
+    fn schedule_timer(&mut self, host: HostId) {
+        let Some(timeout) = self.hosts[host].stack.next_timeout() else {
+            return;
+        };
+        let time = self.now + timeout;
+        if self.hosts[host].timer_at == Some(time) {
+            return;
+        }
+        self.hosts[host].timer_at = Some(time);
+        self.schedule(time, Event::Timer { host });
+    }
+
+    fn schedule(&mut self, time: Duration, event: Event) {
+        let order = self.scheduled;
+        self.scheduled += 1;
+        self.events.push(Reverse(Scheduled { time, order, event }));
+    }
+}
+
+/// SplitMix64: small, fast and the same on every platform
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// True with probability `p`; draws nothing when `p` is zero, so a
+    /// fault-free link consumes no randomness
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.unit() < p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::task::{Poll, Waker};
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::trace::{Event, EventKind, Level, Sink, Tracer};
+
+    /// Keeps every event, without its wall-clock time
+    #[derive(Debug, Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl Sink for Recorder {
+        fn record(&mut self, _time: SystemTime, event: &Event) -> io::Result<()> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    impl Recorder {
+        fn events(&self) -> Vec<Event> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    /// Two hosts joined by `link`, each tracing every event into the recorder
+    fn network(seed: u64, link: Link) -> (Simulator, HostId, HostId, Recorder) {
+        let recorder = Recorder::default();
+        let mut sim = Simulator::new(seed);
+        let client = sim.add_host(Ipv4Addr::new(10, 0, 0, 1));
+        let server = sim.add_host(Ipv4Addr::new(10, 0, 0, 2));
+        sim.link(client, server, link);
+        for host in [client, server] {
+            sim.with_stack(host, |stack| {
+                stack.set_tracer(Tracer::new(recorder.clone(), Level::Trace))
+            });
+        }
+        (sim, client, server, recorder)
+    }
+
+    /// Send `len` bytes from the client to the server, returning what the
+    /// server read
+    fn transfer(sim: &mut Simulator, client: HostId, server: HostId, len: usize) -> Vec<u8> {
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        sim.with_stack(server, |stack| stack.listen(80)).unwrap();
+        let quad = sim.connect(client, 5000, server, 80).unwrap();
+
+        let mut accepted = None;
+        let mut written = 0;
+        let mut received = Vec::new();
+        sim.run_until(Duration::from_secs(600), |sim| {
+            if accepted.is_none() {
+                accepted = sim.with_stack(server, |stack| stack.poll_accept(80, Waker::noop()));
+            }
+            if written < data.len() && sim.stack(client).stream_readiness(quad).writable {
+                let result = sim.with_stack(client, |stack| {
+                    stack.poll_write(quad, &data[written..], Waker::noop())
+                });
+                if let Poll::Ready(Ok(n)) = result {
+                    written += n;
+                }
+            }
+            if let Some(peer) = accepted {
+                let mut buf = [0u8; 4096];
+                while let Poll::Ready(Ok(n @ 1..)) = sim.with_stack(server, |stack| {
+                    stack.poll_read(peer, &mut buf, Waker::noop())
+                }) {
+                    received.extend_from_slice(&buf[..n]);
+                }
+            }
+            received.len() == data.len()
+        });
+        assert_eq!(received, data);
+        received
+    }
+
+    #[test]
+    fn same_seed_replays_the_same_run() {
+        let link = Link {
+            loss: 0.2,
+            reorder: 0.1,
+            ..Link::default()
+        };
+        let run = |seed| {
+            let (mut sim, client, server, recorder) = network(seed, link);
+            transfer(&mut sim, client, server, 50_000);
+            (sim.elapsed(), sim.statistics(), recorder.events())
+        };
+
+        let first = run(7);
+        assert!(first.1.lost > 0);
+        assert_eq!(run(7), first);
+        assert_ne!(run(8), first);
+    }
+
+    #[test]
+    fn data_survives_loss_reordering_and_duplication() {
+        let link = Link {
+            loss: 0.05,
+            reorder: 0.2,
+            duplicate: 0.05,
+            ..Link::default()
+        };
+        let (mut sim, client, server, _) = network(1, link);
+        transfer(&mut sim, client, server, 100_000);
+
+        let statistics = sim.statistics();
+        assert!(statistics.lost > 0);
+        assert!(statistics.reordered > 0);
+        assert!(statistics.duplicated > 0);
+    }
+
+    #[test]
+    fn lost_segment_is_retransmitted() {
+        let (mut sim, client, server, recorder) = network(1, Link::default());
+        sim.with_stack(server, |stack| stack.listen(80)).unwrap();
+        let quad = sim.connect(client, 5000, server, 80).unwrap();
+        assert!(sim.run_until(Duration::from_secs(1), |sim| sim
+            .stack(client)
+            .stream_readiness(quad)
+            .writable));
+
+        // Everything the client sends is lost until the link is restored
+        sim.link_one_way(
+            client,
+            server,
+            Link {
+                loss: 1.0,
+                ..Link::default()
+            },
+        );
+        let written = sim.with_stack(client, |stack| {
+            stack.poll_write(quad, b"hello", Waker::noop())
+        });
+        assert!(matches!(written, Poll::Ready(Ok(5))));
+        sim.run_for(Duration::from_secs(2));
+        let retransmitted = |recorder: &Recorder| {
+            recorder
+                .events()
+                .iter()
+                .filter(|event| event.kind == EventKind::Retransmit)
+                .count()
+        };
+        assert!(retransmitted(&recorder) > 0);
+
+        sim.link_one_way(client, server, Link::default());
+        let peer = sim
+            .with_stack(server, |stack| stack.poll_accept(80, Waker::noop()))
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let arrived = sim.run_until(Duration::from_secs(10), |sim| {
+            matches!(
+                sim.with_stack(server, |stack| stack.poll_read(
+                    peer,
+                    &mut buf,
+                    Waker::noop()
+                )),
+                Poll::Ready(Ok(5))
+            )
+        });
+        assert!(arrived);
+        assert_eq!(&buf[..5], b"hello");
+        assert!(sim.statistics().lost > 0);
+    }
+}
@@ -1,16 +1,513 @@
-use crate::parser::IPHeader;
-
-impl IPHeader {
-    pub fn sniffer(&self) {
-        let proto = match self.protocol {
-            1 => String::from("ICMP"),
-            6 => String::from("TCP"),
-            _ => String::from("other than TCP and ICMP packet"),
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::parser::{Ipv4View, TcpView};
+use crate::pcap::Direction;
+
+/// Decodes datagrams into one line each, tcpdump style:
+///
+/// ```text
+///    0.000000 In  IP 10.0.0.1.5000 > 10.0.0.2.80: Flags [S], seq 0, win 65535, options [mss 1460,sackOK,TS val 0 ecr 0,wscale 7], length 0
+///    0.000000 Out IP 10.0.0.2.80 > 10.0.0.1.5000: Flags [S.], seq 0, ack 1, win 65535, options [mss 1460], length 0
+/// ```
+///
+/// Times are seconds since the first datagram. Sequence numbers are
+/// relative to the first one seen in each direction of a flow, and
+/// acknowledgments to the first one seen in the other direction, until a
+/// SYN or RST starts the flow over.
+#[derive(Debug, Default)]
+pub struct Sniffer {
+    filter: Option<Filter>,
+
+    /// Time of the first datagram decoded
+    origin: Option<Instant>,
+
+    /// First sequence number seen per (source, destination) endpoint pair
+    flows: HashMap<(Endpoint, Endpoint), u32>,
+}
+
+/// Address and port
+type Endpoint = (Ipv4Addr, u16);
+
+/// Flows remembered before the table is cleared, so a sniffer attached to a
+/// busy stack does not grow without bound
+const FLOW_LIMIT: usize = 4096;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+const URG: u8 = 0x20;
+const ECE: u8 = 0x40;
+const CWR: u8 = 0x80;
+
+impl Sniffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only decode datagrams `filter` selects
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// One line describing a datagram handled at `now`, or `None` if the
+    /// filter rejects it
+    pub fn decode(&mut self, now: Instant, direction: Direction, datagram: &[u8]) -> Option<String> {
+        if let Some(filter) = &self.filter {
+            if !filter.matches(direction, datagram) {
+                return None;
+            }
+        }
+
+        let origin = *self.origin.get_or_insert(now);
+        let mut line = format!("{:11.6} ", now.saturating_duration_since(origin).as_secs_f64());
+        line.push_str(match direction {
+            Direction::Inbound => "In  ",
+            Direction::Outbound => "Out ",
+        });
+
+        let ip = match Ipv4View::new(datagram) {
+            Ok(ip) => ip,
+            Err(error) => {
+                let _ = write!(line, "IP [malformed: {}]", error);
+                return Some(line);
+            }
+        };
+
+        match ip.protocol() {
+            6 => match TcpView::new(ip.payload()) {
+                Ok(segment) => self.decode_tcp(&mut line, &ip, &segment),
+                Err(error) => {
+                    let _ = write!(line, "IP {} > {}: [malformed TCP: {}]", ip.source(), ip.destination(), error);
+                }
+            },
+            1 => {
+                let payload = ip.payload();
+                let _ = write!(line, "IP {} > {}: ICMP", ip.source(), ip.destination());
+                if let [kind, code, ..] = payload {
+                    let _ = write!(line, " type {} code {}", kind, code);
+                }
+                let _ = write!(line, ", length {}", payload.len());
+            }
+            protocol => {
+                let _ = write!(
+                    line,
+                    "IP {} > {}: ip-proto-{} length {}",
+                    ip.source(),
+                    ip.destination(),
+                    protocol,
+                    ip.payload().len()
+                );
+            }
+        }
+        Some(line)
+    }
+
+    fn decode_tcp(&mut self, line: &mut String, ip: &Ipv4View, segment: &TcpView) {
+        let source = (ip.source(), segment.source_port());
+        let destination = (ip.destination(), segment.destination_port());
+        let flags = segment.control_bit();
+        let seq = segment.sequence_number();
+        let len = segment.payload().len() as u32;
+
+        if self.flows.len() >= FLOW_LIMIT {
+            self.flows.clear();
+        }
+        let base = if flags & SYN != 0 {
+            self.flows.insert((source, destination), seq);
+            seq
+        } else {
+            *self.flows.entry((source, destination)).or_insert(seq)
         };
+        let peer_base = self.flows.get(&(destination, source)).copied();
+        let relative = |value: u32| peer_base.map_or(value, |base| value.wrapping_sub(base));
+
+        let _ = write!(
+            line,
+            "IP {}.{} > {}.{}: Flags [{}], seq {}",
+            source.0,
+            source.1,
+            destination.0,
+            destination.1,
+            flag_letters(flags),
+            seq.wrapping_sub(base)
+        );
+        if len > 0 {
+            let _ = write!(line, ":{}", seq.wrapping_sub(base).wrapping_add(len));
+        }
+        if flags & ACK != 0 {
+            let _ = write!(line, ", ack {}", relative(segment.acknowledge_number()));
+        }
+        let _ = write!(line, ", win {}", segment.window());
+
+        let options = segment.options();
+        let mut decoded = Vec::new();
+        if let Some(mss) = options.mss {
+            decoded.push(format!("mss {}", mss));
+        }
+        if options.sack_permitted {
+            decoded.push("sackOK".to_string());
+        }
+        if let Some((tsval, tsecr)) = options.timestamps {
+            decoded.push(format!("TS val {} ecr {}", tsval, tsecr));
+        }
+        if let Some(shift) = options.window_scale {
+            decoded.push(format!("wscale {}", shift));
+        }
+        if !options.sack_blocks.is_empty() {
+            let mut sack = format!("sack {} ", options.sack_blocks.len());
+            for &(left, right) in &options.sack_blocks {
+                let _ = write!(sack, "{{{}:{}}}", relative(left), relative(right));
+            }
+            decoded.push(sack);
+        }
+        if !decoded.is_empty() {
+            let _ = write!(line, ", options [{}]", decoded.join(","));
+        }
+        let _ = write!(line, ", length {}", len);
+
+        if flags & RST != 0 {
+            self.flows.remove(&(source, destination));
+            self.flows.remove(&(destination, source));
+        }
+    }
+}
+
+/// Flags in tcpdump notation: `S`, `F`, `R`, `P`, `U`, `E`, `W`, then `.`
+/// for ACK, or `none`
+fn flag_letters(flags: u8) -> String {
+    let mut letters: String = [(SYN, 'S'), (FIN, 'F'), (RST, 'R'), (PSH, 'P'), (URG, 'U'), (ECE, 'E'), (CWR, 'W')]
+        .into_iter()
+        .filter(|&(flag, _)| flags & flag != 0)
+        .map(|(_, letter)| letter)
+        .collect();
+    if flags & ACK != 0 {
+        letters.push('.');
+    }
+    if letters.is_empty() {
+        letters.push_str("none");
+    }
+    letters
+}
+
+/// Which packets to print or capture, parsed from a tcpdump-like expression:
+///
+/// ```text
+/// expr      = and { ("or" | "||") and }
+/// and       = unary { ("and" | "&&") unary }
+/// unary     = ("not" | "!") unary | "(" expr ")" | primitive
+/// primitive = ["src" | "dst"] "host" ADDRESS
+///           | ["src" | "dst"] "port" NUMBER
+///           | "syn" | "ack" | "fin" | "rst" | "psh" | "urg" | "ece" | "cwr"
+///           | "inbound" | "outbound"
+/// ```
+///
+/// A flag name matches TCP segments with that flag set, so
+/// `port 80 and syn and not ack` selects connection attempts to port 80.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Host(Option<Side>, Ipv4Addr),
+    Port(Option<Side>, u16),
+    Flag(u8),
+    Direction(Direction),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+/// Which end of a datagram a `host` or `port` primitive looks at; either
+/// when not given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Source,
+    Destination,
+}
+
+/// Why a filter expression could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterError {
+    pub message: String,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid filter: {}", self.message)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+/// The fields of a datagram filters look at
+struct Fields {
+    direction: Direction,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+
+    /// Ports and flags, for TCP segments
+    tcp: Option<(u16, u16, u8)>,
+}
+
+impl Filter {
+    pub fn parse(expression: &str) -> Result<Self, FilterError> {
+        let tokens = tokenize(expression);
+        let mut parser = FilterParser { tokens, position: 0 };
+        let filter = parser.or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(error(format!("unexpected '{}'", token))),
+        }
+    }
+
+    /// Whether the filter selects a datagram; malformed ones never match
+    pub fn matches(&self, direction: Direction, datagram: &[u8]) -> bool {
+        let Ok(ip) = Ipv4View::new(datagram) else {
+            return false;
+        };
+        let tcp = (ip.protocol() == 6)
+            .then(|| TcpView::new(ip.payload()).ok())
+            .flatten()
+            .map(|segment| (segment.source_port(), segment.destination_port(), segment.control_bit()));
+
+        self.evaluate(&Fields {
+            direction,
+            source: ip.source(),
+            destination: ip.destination(),
+            tcp,
+        })
+    }
+
+    fn evaluate(&self, fields: &Fields) -> bool {
+        match self {
+            Filter::Host(side, address) => match side {
+                Some(Side::Source) => fields.source == *address,
+                Some(Side::Destination) => fields.destination == *address,
+                None => fields.source == *address || fields.destination == *address,
+            },
+            Filter::Port(side, port) => fields.tcp.is_some_and(|(source, destination, _)| match side {
+                Some(Side::Source) => source == *port,
+                Some(Side::Destination) => destination == *port,
+                None => source == *port || destination == *port,
+            }),
+            Filter::Flag(flag) => fields.tcp.is_some_and(|(_, _, flags)| flags & flag != 0),
+            Filter::Direction(direction) => fields.direction == *direction,
+            Filter::Not(filter) => !filter.evaluate(fields),
+            Filter::And(left, right) => left.evaluate(fields) && right.evaluate(fields),
+            Filter::Or(left, right) => left.evaluate(fields) || right.evaluate(fields),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        Self::parse(expression)
+    }
+}
+
+/// Split an expression into words, parentheses and `!`
+fn tokenize(expression: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    for c in expression.chars() {
+        if c.is_whitespace() || matches!(c, '(' | ')' | '!') {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        } else {
+            word.push(c);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+/// Recursive-descent parser over the tokens of a filter expression
+struct FilterParser {
+    tokens: Vec<String>,
+    position: usize,
+}
+
+impl FilterParser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Filter, FilterError> {
+        let mut filter = self.and()?;
+        while matches!(self.peek(), Some("or" | "||")) {
+            self.position += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, FilterError> {
+        let mut filter = self.unary()?;
+        while matches!(self.peek(), Some("and" | "&&")) {
+            self.position += 1;
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn unary(&mut self) -> Result<Filter, FilterError> {
+        let Some(token) = self.next() else {
+            return Err(error("unexpected end of expression".to_string()));
+        };
+
+        match token.as_str() {
+            "not" | "!" => Ok(Filter::Not(Box::new(self.unary()?))),
+            "(" => {
+                let filter = self.or()?;
+                match self.next().as_deref() {
+                    Some(")") => Ok(filter),
+                    _ => Err(error("missing ')'".to_string())),
+                }
+            }
+            "src" => self.primitive(Some(Side::Source)),
+            "dst" => self.primitive(Some(Side::Destination)),
+            "host" | "port" => {
+                self.position -= 1;
+                self.primitive(None)
+            }
+            "inbound" => Ok(Filter::Direction(Direction::Inbound)),
+            "outbound" => Ok(Filter::Direction(Direction::Outbound)),
+            "fin" => Ok(Filter::Flag(FIN)),
+            "syn" => Ok(Filter::Flag(SYN)),
+            "rst" => Ok(Filter::Flag(RST)),
+            "psh" => Ok(Filter::Flag(PSH)),
+            "ack" => Ok(Filter::Flag(ACK)),
+            "urg" => Ok(Filter::Flag(URG)),
+            "ece" => Ok(Filter::Flag(ECE)),
+            "cwr" => Ok(Filter::Flag(CWR)),
+            other => Err(error(format!("unknown primitive '{}'", other))),
+        }
+    }
+
+    /// `host ADDRESS` or `port NUMBER`, after an optional `src` or `dst`
+    fn primitive(&mut self, side: Option<Side>) -> Result<Filter, FilterError> {
+        let keyword = match self.next() {
+            Some(keyword) if keyword == "host" || keyword == "port" => keyword,
+            Some(other) => return Err(error(format!("expected 'host' or 'port', found '{}'", other))),
+            None => return Err(error("expected 'host' or 'port'".to_string())),
+        };
+        let Some(value) = self.next() else {
+            return Err(error(format!("'{}' needs a value", keyword)));
+        };
+
+        if keyword == "host" {
+            let address = value.parse().map_err(|_| error(format!("invalid address '{}'", value)))?;
+            Ok(Filter::Host(side, address))
+        } else {
+            let port = value.parse().map_err(|_| error(format!("invalid port '{}'", value)))?;
+            Ok(Filter::Port(side, port))
+        }
+    }
+}
+
+fn error(message: String) -> FilterError {
+    FilterError { message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_sender::SegmentBuilder;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    /// Segment from the client's port 5000 to the server's port 80
+    fn datagram(flags: u8) -> Vec<u8> {
+        let mut buffer = vec![0u8; 64];
+        let len = SegmentBuilder::new((CLIENT, 5000), (SERVER, 80))
+            .flags(flags)
+            .write(&mut buffer)
+            .unwrap();
+        buffer.truncate(len);
+        buffer
+    }
+
+    fn matches(expression: &str, flags: u8) -> bool {
+        Filter::parse(expression).unwrap().matches(Direction::Inbound, &datagram(flags))
+    }
+
+    fn parse_error(expression: &str) -> String {
+        Filter::parse(expression).unwrap_err().message
+    }
+
+    #[test]
+    fn primitives() {
+        assert!(matches("host 10.0.0.1", SYN));
+        assert!(matches("src host 10.0.0.1", SYN));
+        assert!(!matches("dst host 10.0.0.1", SYN));
+        assert!(matches("port 80", SYN));
+        assert!(matches("dst port 80", SYN));
+        assert!(!matches("src port 80", SYN));
+        assert!(matches("syn", SYN | ACK));
+        assert!(!matches("fin", SYN | ACK));
+        assert!(matches("inbound", SYN));
+        assert!(!matches("outbound", SYN));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            Filter::parse("syn or fin and rst").unwrap(),
+            Filter::Or(
+                Box::new(Filter::Flag(SYN)),
+                Box::new(Filter::And(Box::new(Filter::Flag(FIN)), Box::new(Filter::Flag(RST)))),
+            )
+        );
+        assert!(matches("syn or fin and rst", SYN));
+        assert!(!matches("(syn or fin) and rst", SYN));
+        assert!(matches("port 443 || port 80 && syn", SYN));
+    }
+
+    #[test]
+    fn not_applies_to_the_next_term() {
+        assert!(matches("syn and not ack", SYN));
+        assert!(!matches("syn and not ack", SYN | ACK));
+        assert!(!matches("!syn or fin", SYN));
+        assert!(matches("!(syn and fin)", SYN));
+        assert!(matches("not not syn", SYN));
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        assert_eq!(parse_error(""), "unexpected end of expression");
+        assert_eq!(parse_error("syn and"), "unexpected end of expression");
+        assert_eq!(parse_error("(syn or fin"), "missing ')'");
+        assert_eq!(parse_error("syn fin"), "unexpected 'fin'");
+        assert_eq!(parse_error("syn)"), "unexpected ')'");
+        assert_eq!(parse_error("bogus"), "unknown primitive 'bogus'");
+        assert_eq!(parse_error("src syn"), "expected 'host' or 'port', found 'syn'");
+        assert_eq!(parse_error("port"), "'port' needs a value");
+        assert_eq!(parse_error("port http"), "invalid port 'http'");
+        assert_eq!(parse_error("host 10.0.0"), "invalid address '10.0.0'");
+    }
 
-        println!(
-            "{} packet received from {} ttl={}",
-            proto, self.source, self.ttl
-        )
+    #[test]
+    fn malformed_datagrams_never_match() {
+        let filter = Filter::parse("not syn").unwrap();
+        assert!(!filter.matches(Direction::Inbound, &datagram(SYN)[..12]));
     }
 }
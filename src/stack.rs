@@ -0,0 +1,1393 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, MonotonicClock};
+use crate::icmp;
+use crate::options::TcpOptions;
+use crate::packet_sender::Frames;
+use crate::parser::{self, ChecksumError, Ipv4View, ParseError, TcpView};
+use crate::pcap::{Capture, Direction};
+use crate::poller::Readiness;
+use crate::sniffer::Sniffer;
+use crate::syncookie::SynCookies;
+use crate::tcb::{self, ConnectionError, Quad, RetransmitAction, Tcb, TcpState, TimerKind};
+use crate::tcp::{ChallengeAckLimit, State};
+use crate::timer_wheel::TimerWheel;
+use crate::trace::{Event, EventKind, Level, Tracer};
+
+/// A stack shared between its event loop and application handles
+pub type SharedStack = Arc<Mutex<Stack>>;
+
+/// The connection table together with the packet and timer processing that
+/// every event loop (blocking, async or threaded) drives.
+///
+/// Connections are reaped from the table as soon as a segment or one of
+/// their timers shows they are finished: aborted, closed, out of TIME-WAIT,
+/// or left half-open by the peer.
+#[derive(Debug)]
+pub struct Stack {
+    /// Connections keyed by (remote, local) address pair
+    pub connections: HashMap<Quad, Tcb>,
+
+    /// Errors of aborted connections the application holds a handle to,
+    /// kept after their TCB is removed until the last handle is dropped or
+    /// the quad is reused
+    errors: HashMap<Quad, ConnectionError>,
+
+    /// Deadlines of every connection timer
+    timers: TimerWheel<(Quad, TimerKind)>,
+
+    /// Connections that may have something to transmit
+    transmit_pending: HashSet<Quad>,
+
+    /// Maximum segment lifetime given to new connections
+    msl: Duration,
+
+    /// Connections entering TIME-WAIT, oldest first. Entries for connections
+    /// that left TIME-WAIT meanwhile are skipped.
+    time_wait: VecDeque<(Quad, Instant)>,
+
+    /// Number of connections in TIME-WAIT, and how many are allowed before
+    /// the oldest is evicted
+    time_wait_count: usize,
+    time_wait_limit: usize,
+
+    /// Connections in SYN-RECEIVED, and how many may be before new SYNs
+    /// are answered with SYN cookies instead of allocating a TCB
+    half_open: HashSet<Quad>,
+    syn_backlog: usize,
+    syn_cookies: SynCookies,
+
+    /// Budget of challenge ACKs shared by all connections
+    challenge_acks: ChallengeAckLimit,
+
+    /// Local ports an application is listening on
+    listeners: HashMap<u16, Listener>,
+
+    /// Counters of received frames that were dropped
+    statistics: Statistics,
+
+    /// Time source of every timer and deadline
+    clock: Box<dyn Clock>,
+
+    /// Where events are reported, and from which level
+    tracer: Tracer,
+
+    /// Why the tracer stopped, until taken
+    trace_error: Option<io::Error>,
+
+    /// Decodes every received and sent frame into a trace event
+    sniffer: Option<Sniffer>,
+
+    /// File every received and sent frame is written to
+    capture: Option<Capture>,
+
+    /// What the stack made of the frame being processed, written as its
+    /// capture comment
+    capture_notes: Vec<String>,
+
+    /// Event loop task to wake when the application queued work for it
+    driver_waker: Option<Waker>,
+
+    /// Set when the application queued work the event loop has not seen yet
+    driver_pending: bool,
+
+    /// Frames built outside the event loop, sent with the next `poll_transmit`
+    outbox: Frames,
+}
+
+/// Counters kept by a `Stack`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// Datagrams dropped for a bad IP header checksum
+    pub ip_checksum_errors: u64,
+
+    /// Segments dropped for a bad TCP checksum
+    pub tcp_checksum_errors: u64,
+
+    /// Datagrams dropped because their headers or lengths are invalid
+    pub malformed: u64,
+}
+
+/// Default cap on connections in TIME-WAIT
+const TIME_WAIT_LIMIT: usize = 16384;
+
+/// Default number of half-open connections kept before using SYN cookies
+const SYN_BACKLOG: usize = 1024;
+
+/// Established connections waiting to be accepted on a port
+#[derive(Debug, Default)]
+struct Listener {
+    backlog: VecDeque<Quad>,
+
+    /// Tasks waiting to accept
+    wakers: Vec<Waker>,
+
+    /// Readiness poller, woken on every new connection without being consumed
+    poller: Option<Waker>,
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stack {
+    pub fn new() -> Self {
+        Self::with_clock(MonotonicClock)
+    }
+
+    /// Stack measuring time with `clock`, e.g. a `ManualClock` that tests
+    /// advance by hand
+    pub fn with_clock(clock: impl Clock + 'static) -> Self {
+        let now = clock.now();
+        Self {
+            connections: HashMap::new(),
+            errors: HashMap::new(),
+            timers: TimerWheel::new(now),
+            transmit_pending: HashSet::new(),
+            msl: tcb::MSL,
+            time_wait: VecDeque::new(),
+            time_wait_count: 0,
+            time_wait_limit: TIME_WAIT_LIMIT,
+            half_open: HashSet::new(),
+            syn_backlog: SYN_BACKLOG,
+            syn_cookies: SynCookies::new(now),
+            challenge_acks: ChallengeAckLimit::default(),
+            listeners: HashMap::new(),
+            statistics: Statistics::default(),
+            clock: Box::new(clock),
+            tracer: Tracer::default(),
+            trace_error: None,
+            sniffer: Some(Sniffer::new()),
+            capture: None,
+            capture_notes: Vec::new(),
+            driver_waker: None,
+            driver_pending: false,
+            outbox: Frames::new(),
+        }
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        self.statistics
+    }
+
+    /// Set the maximum segment lifetime of connections opened from now on;
+    /// they stay in TIME-WAIT for twice as long
+    pub fn set_msl(&mut self, msl: Duration) {
+        self.msl = msl;
+    }
+
+    /// Set how many half-open connections are kept before switching to SYN cookies
+    pub fn set_syn_backlog(&mut self, backlog: usize) {
+        self.syn_backlog = backlog;
+    }
+
+    /// Set how many challenge ACKs (RFC 5961) may be sent per second
+    pub fn set_challenge_ack_limit(&mut self, per_second: u32) {
+        self.challenge_acks.set_limit(per_second);
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Report events to `tracer` from now on, returning the previous tracer
+    pub fn set_tracer(&mut self, tracer: Tracer) -> Tracer {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    /// Report an event at the stack's current time, e.g. a device error in
+    /// the event loop
+    pub fn trace(&mut self, event: Event) {
+        let now = self.now();
+        if let Err(error) = self.tracer.emit(now, event) {
+            self.trace_error = Some(error);
+        }
+    }
+
+    /// The error that stopped the tracer, if its sink failed to write since
+    /// the last call
+    pub fn take_trace_error(&mut self) -> Option<io::Error> {
+        self.trace_error.take()
+    }
+
+    /// Report the events a connection raised
+    fn flush_events(&mut self, quad: Quad) {
+        let Some(tcb) = self.connections.get_mut(&quad) else {
+            return;
+        };
+        if tcb.events.is_empty() {
+            return;
+        }
+
+        let events = std::mem::take(&mut tcb.events);
+        for event in events {
+            self.trace(event);
+        }
+    }
+
+    /// Decode frames with `sniffer` into `Packet` events, or stop decoding
+    /// them, returning the previous sniffer
+    pub fn set_sniffer(&mut self, sniffer: Option<Sniffer>) -> Option<Sniffer> {
+        std::mem::replace(&mut self.sniffer, sniffer)
+    }
+
+    /// Write every frame received or sent from now on to `capture`,
+    /// returning the previous capture
+    pub fn set_capture(&mut self, capture: Option<Capture>) -> Option<Capture> {
+        std::mem::replace(&mut self.capture, capture)
+    }
+
+    /// Report a frame decoded by the sniffer, if any and if packets are traced
+    fn sniff_frame(&mut self, direction: Direction, frame: &[u8]) {
+        if !self.tracer.enabled(Level::Trace) {
+            return;
+        }
+        let now = self.now();
+        let Some(sniffer) = &mut self.sniffer else {
+            return;
+        };
+
+        if let Some(line) = sniffer.decode(now, direction, datagram(direction, frame)) {
+            let direction = match direction {
+                Direction::Inbound => "in",
+                Direction::Outbound => "out",
+            };
+            self.trace(
+                Event::new(EventKind::Packet)
+                    .field("direction", direction)
+                    .field("decode", line),
+            );
+        }
+    }
+
+    /// Record a frame in the capture, if any. A capture that fails to write
+    /// is dropped rather than failing the stack.
+    fn capture_frame(&mut self, direction: Direction, frame: &[u8], comment: Option<&str>) {
+        let now = self.now();
+        let Some(capture) = &mut self.capture else {
+            return;
+        };
+
+        let datagram = datagram(direction, frame);
+        let result = capture
+            .record(now, direction, datagram, comment)
+            .and_then(|()| capture.flush());
+        if let Err(error) = result {
+            self.capture = None;
+            self.trace(Event::new(EventKind::CaptureError).field("error", error.to_string()));
+        }
+    }
+
+    /// Print and capture a frame the stack is sending
+    fn transmitted(&mut self, frame: &[u8], comment: Option<&str>) {
+        self.sniff_frame(Direction::Outbound, frame);
+        self.capture_frame(Direction::Outbound, frame, comment);
+    }
+
+    /// Note what the stack made of the frame being processed, when capturing
+    fn annotate(&mut self, note: impl FnOnce() -> String) {
+        if self.capture.is_some() {
+            self.capture_notes.push(note());
+        }
+    }
+
+    /// Cap the number of connections in TIME-WAIT; beyond it the oldest is
+    /// dropped early
+    pub fn set_time_wait_limit(&mut self, limit: usize) {
+        self.time_wait_limit = limit;
+        self.enforce_time_wait_limit();
+    }
+
+    /// Process one frame read from the TUN device, appending the frames to
+    /// send back to `packets`
+    pub fn process_frame(&mut self, frame: &[u8], packets: &mut Frames) {
+        // Only IPv4 is decoded and fits the capture's link type
+        let ipv4 = frame.get(2..4) == Some(&[0x08, 0x00]);
+        if ipv4 {
+            self.sniff_frame(Direction::Inbound, frame);
+        }
+
+        let first = packets.len();
+        self.receive_frame(frame, packets);
+
+        let notes = std::mem::take(&mut self.capture_notes);
+        if ipv4 {
+            let comment = (!notes.is_empty()).then(|| notes.join("; "));
+            self.capture_frame(Direction::Inbound, frame, comment.as_deref());
+        }
+        for packet in packets.iter().skip(first) {
+            self.transmitted(packet, None);
+        }
+    }
+
+    fn receive_frame(&mut self, frame: &[u8], packets: &mut Frames) {
+        if frame.len() < 4 {
+            return;
+        }
+
+        let _flags = u16::from_be_bytes([frame[0], frame[1]]);
+        let proto = u16::from_be_bytes([frame[2], frame[3]]);
+
+        if proto != 0x0800 {
+            return;
+        }
+
+        // Corrupted frames must not be mistaken for genuine segments
+        if let Err(error) = parser::verify_checksums(&frame[4..]) {
+            let layer = match error {
+                ChecksumError::Ip => {
+                    self.statistics.ip_checksum_errors += 1;
+                    "ip"
+                }
+                ChecksumError::Tcp => {
+                    self.statistics.tcp_checksum_errors += 1;
+                    "tcp"
+                }
+            };
+            self.trace(Event::new(EventKind::ChecksumError).field("layer", layer));
+            self.annotate(|| format!("dropped: {:?} checksum error", error));
+            return;
+        }
+
+        // Read the headers in place rather than copying the frame
+        let parsed = Ipv4View::new(&frame[4..]).and_then(|ip| match ip.protocol() {
+            6 => Ok((ip, TcpView::new(ip.payload())?)),
+            protocol => Err(ParseError::UnsupportedProtocol(protocol)),
+        });
+        let (ip, segment) = match parsed {
+            Ok(parsed) => parsed,
+            Err(ParseError::UnsupportedProtocol(1)) => {
+                self.process_icmp(&frame[4..]);
+                return;
+            }
+            Err(ParseError::UnsupportedProtocol(_)) => return,
+            Err(error) => {
+                self.statistics.malformed += 1;
+                self.trace(Event::new(EventKind::Malformed).field("error", error.to_string()));
+                self.annotate(|| format!("dropped: {}", error));
+                return;
+            }
+        };
+
+        let quad = Quad {
+            src: (ip.source(), segment.source_port()),
+            dst: (ip.destination(), segment.destination_port()),
+        };
+
+        let state = State::check_state(segment.control_bit());
+        if self.tracer.enabled(Level::Debug) {
+            self.trace(
+                Event::new(EventKind::Segment)
+                    .connection(quad)
+                    .field("flags", state.as_str())
+                    .field("seq", segment.sequence_number())
+                    .field("ack", segment.acknowledge_number()),
+            );
+        }
+
+        let flags = segment.control_bit();
+        if !self.connections.contains_key(&quad) && flags & 0x10 != 0 && flags & 0x06 == 0 {
+            // May complete a handshake answered with a SYN cookie
+            self.accept_syn_cookie(&segment, quad);
+        }
+
+        if state == "SYN" {
+            match self.connections.get(&quad) {
+                None if self.half_open.len() >= self.syn_backlog => {
+                    self.annotate(|| "SYN backlog full, answered with a SYN cookie".to_string());
+                    packets.push_with(|frame| self.syn_cookie_ack(&segment, quad, frame));
+                    return;
+                }
+                // A new connection reuses the quad of an aborted one
+                None => {
+                    self.errors.remove(&quad);
+                }
+                Some(tcb) if tcb.state == TcpState::TimeWait => {
+                    let timestamps = segment.options().timestamps;
+                    if !tcb.accepts_reincarnation(segment.sequence_number(), timestamps) {
+                        self.annotate(|| "dropped: SYN for a connection in TimeWait".to_string());
+                        return;
+                    }
+                    self.trace(Event::new(EventKind::TimeWaitReused).connection(quad));
+                    self.annotate(|| "reusing connection in TimeWait".to_string());
+                    self.remove_connection(quad);
+                }
+                Some(_) => {}
+            }
+        }
+
+        let previous = self.connections.get(&quad).map(|tcb| tcb.state);
+        let now = self.now();
+        packets.push_with(|reply| {
+            State::tcp_connection(
+                &state,
+                &segment,
+                &mut self.connections,
+                quad,
+                &mut self.challenge_acks,
+                now,
+                reply,
+            )
+        });
+        self.flush_events(quad);
+
+        let current = self.connections.get_mut(&quad).map(|tcb| {
+            if previous.is_none() {
+                tcb.timers.msl = self.msl;
+            }
+            tcb.state
+        });
+        if current == Some(TcpState::TimeWait) && previous != Some(TcpState::TimeWait) {
+            self.enter_time_wait(quad);
+        }
+        if current != previous {
+            self.trace(
+                Event::new(EventKind::StateChange)
+                    .connection(quad)
+                    .field("from", state_name(previous))
+                    .field("to", state_name(current)),
+            );
+            self.annotate(|| format!("{} -> {}", state_name(previous), state_name(current)));
+        }
+
+        self.reap(quad);
+        self.connection_changed(quad);
+
+        // Hand newly established connections to a listening application,
+        // including those whose peer already closed its side
+        let established = matches!(current, Some(TcpState::Established | TcpState::CloseWait));
+        if previous == Some(TcpState::SynRcvd) && established {
+            if let Some(listener) = self.listeners.get_mut(&quad.dst.1) {
+                listener.backlog.push_back(quad);
+                for waker in listener.wakers.drain(..) {
+                    waker.wake();
+                }
+                if let Some(waker) = &listener.poller {
+                    waker.wake_by_ref();
+                }
+            }
+        }
+    }
+
+    /// Answer a SYN without keeping state: the SYN-ACK's sequence number is
+    /// a cookie from which `accept_syn_cookie` rebuilds the connection.
+    /// Returns the length of the frame written into `frame`, 0 if none.
+    fn syn_cookie_ack(&mut self, segment: &TcpView, quad: Quad, frame: &mut [u8]) -> usize {
+        let seq = segment.sequence_number();
+        let options = segment.options();
+        let now = self.now();
+        let (cookie, _) = self
+            .syn_cookies
+            .encode(quad, seq, options.mss.unwrap_or(0), now);
+        self.trace(Event::new(EventKind::SynCookieSent).connection(quad));
+
+        // Build the SYN-ACK from a TCB that is dropped right after
+        let mut tcb = Tcb::new(quad);
+        tcb.passive_open();
+        tcb.process_syn(seq, segment.window(), cookie, now);
+        tcb.process_syn_options(&options);
+        tcb.update_ts_recent(seq, options.timestamps);
+
+        let mut reply = tcb.segment_options(0x12, now);
+        if let Some((tsval, _)) = &mut reply.timestamps {
+            *tsval =
+                SynCookies::encode_options(*tsval, options.window_scale, options.sack_permitted);
+        }
+        match State::create_segment(&quad, cookie, 0x12, &[], &tcb, reply, frame) {
+            Ok(len) => len,
+            Err(error) => {
+                self.trace(
+                    Event::new(EventKind::TransmitError)
+                        .connection(quad)
+                        .field("seq", cookie)
+                        .field("error", error.to_string()),
+                );
+                0
+            }
+        }
+    }
+
+    /// Rebuild the connection from an ACK carrying a valid SYN cookie, in
+    /// SYN-RECEIVED so the ACK then completes the handshake as usual
+    fn accept_syn_cookie(&mut self, segment: &TcpView, quad: Quad) {
+        let irs = segment.sequence_number().wrapping_sub(1);
+        let cookie = segment.acknowledge_number().wrapping_sub(1);
+        let now = self.now();
+        let Some(mss) = self.syn_cookies.decode(quad, irs, cookie, now) else {
+            return;
+        };
+
+        let timestamps = segment.options().timestamps;
+        let (window_scale, sack_permitted) = match timestamps {
+            Some((_, tsecr)) => SynCookies::decode_options(tsecr),
+            None => (None, false),
+        };
+
+        let mut tcb = Tcb::new(quad);
+        tcb.passive_open();
+        tcb.process_syn(irs, segment.window(), cookie, now);
+        tcb.process_syn_options(&TcpOptions {
+            mss: Some(mss),
+            window_scale,
+            sack_permitted,
+            timestamps,
+            ..TcpOptions::default()
+        });
+        tcb.snd.nxt = cookie.wrapping_add(1);
+        tcb.timers.msl = self.msl;
+
+        self.trace(
+            Event::new(EventKind::SynCookieAccepted)
+                .connection(quad)
+                .field("mss", mss),
+        );
+        self.annotate(|| format!("valid SYN cookie (MSS {})", mss));
+        self.connections.insert(quad, tcb);
+    }
+
+    /// Abort the connection an ICMP destination unreachable refers to
+    fn process_icmp(&mut self, packet: &[u8]) {
+        let Some(unreachable) = icmp::parse_unreachable(packet) else {
+            return;
+        };
+        let Some(tcb) = self.connections.get_mut(&unreachable.quad) else {
+            return;
+        };
+
+        if tcb.process_icmp_unreachable(unreachable.code, unreachable.seq) {
+            tcb.trace(Event::new(EventKind::Unreachable).field("code", unreachable.code));
+            self.annotate(|| {
+                format!(
+                    "destination unreachable (code {}), connection aborted",
+                    unreachable.code
+                )
+            });
+            self.reap(unreachable.quad);
+        }
+    }
+
+    /// Remove a connection if it is finished with: aborted, closed with
+    /// nothing left to read, out of TIME-WAIT, or a handshake the peer
+    /// abandoned
+    fn reap(&mut self, quad: Quad) {
+        let now = self.now();
+        let Some(tcb) = self.connections.get(&quad) else {
+            return;
+        };
+
+        let finished = tcb.error.is_some()
+            || (tcb.state == TcpState::Closed && tcb.recv_buffer.is_empty())
+            || tcb.is_time_wait_expired(now)
+            || tcb.is_handshake_expired(now);
+        if finished {
+            self.remove_connection(quad);
+        }
+    }
+
+    /// Drop a connection from the table, keeping its error for the application
+    fn remove_connection(&mut self, quad: Quad) {
+        self.flush_events(quad);
+        let Some(tcb) = self.connections.remove(&quad) else {
+            return;
+        };
+
+        let mut closed = Event::new(EventKind::Closed)
+            .connection(quad)
+            .field("state", state_name(Some(tcb.state)));
+        if let Some(error) = tcb.error {
+            closed = closed.field("error", error.to_string());
+        }
+        self.trace(closed);
+
+        // Nobody would ever collect the errors of connections never handed
+        // out, such as spoofed handshakes reset before completing
+        if let Some(error) = tcb.error.filter(|_| tcb.has_handle) {
+            self.errors.insert(quad, error);
+        }
+        if tcb.time_wait_counted {
+            self.time_wait_count -= 1;
+        }
+        self.connection_changed(quad);
+
+        // Forget queue entries of connections that already left TIME-WAIT
+        while let Some(&(quad, start)) = self.time_wait.front() {
+            if self.is_in_time_wait(quad, start) {
+                break;
+            }
+            self.time_wait.pop_front();
+        }
+    }
+
+    fn enter_time_wait(&mut self, quad: Quad) {
+        let Some(tcb) = self.connections.get_mut(&quad) else {
+            return;
+        };
+        let Some(start) = tcb.timers.time_wait.filter(|_| !tcb.time_wait_counted) else {
+            return;
+        };
+
+        tcb.time_wait_counted = true;
+        self.time_wait.push_back((quad, start));
+        self.time_wait_count += 1;
+        self.enforce_time_wait_limit();
+    }
+
+    /// Evict the oldest TIME-WAIT connections while there are too many
+    fn enforce_time_wait_limit(&mut self) {
+        while self.time_wait_count > self.time_wait_limit {
+            let Some((quad, start)) = self.time_wait.pop_front() else {
+                break;
+            };
+            if self.is_in_time_wait(quad, start) {
+                self.trace(Event::new(EventKind::TimeWaitEvicted).connection(quad));
+                self.remove_connection(quad);
+            }
+        }
+    }
+
+    /// Whether the connection on `quad` is the one that entered TIME-WAIT at `start`
+    fn is_in_time_wait(&self, quad: Quad, start: Instant) -> bool {
+        self.connections.get(&quad).is_some_and(|tcb| {
+            tcb.state == TcpState::TimeWait && tcb.timers.time_wait == Some(start)
+        })
+    }
+
+    /// Bring the timer wheel in line with a connection's timers after it was
+    /// touched, and let the next `poll_transmit` look at it
+    fn connection_changed(&mut self, quad: Quad) {
+        self.flush_events(quad);
+        self.sync_timers(quad);
+
+        let state = self.connections.get(&quad).map(|tcb| tcb.state);
+        if state.is_some() {
+            self.transmit_pending.insert(quad);
+        } else {
+            self.transmit_pending.remove(&quad);
+        }
+
+        if state == Some(TcpState::SynRcvd) {
+            self.half_open.insert(quad);
+        } else {
+            self.half_open.remove(&quad);
+        }
+    }
+
+    /// Copy a connection's timer deadlines into the wheel, or cancel them
+    /// all if it is gone
+    fn sync_timers(&mut self, quad: Quad) {
+        let tcb = self.connections.get(&quad);
+        for kind in TimerKind::ALL {
+            self.timers
+                .set((quad, kind), tcb.and_then(|tcb| tcb.deadline(kind)));
+        }
+    }
+
+    /// Run expired connection timers, appending the frames to send to `packets`
+    pub fn on_timer(&mut self, packets: &mut Frames) {
+        let now = self.now();
+        for (quad, kind) in self.timers.advance(now) {
+            let Some(tcb) = self.connections.get_mut(&quad) else {
+                continue;
+            };
+
+            let actions = match kind {
+                TimerKind::Retransmit => tcb.check_retransmission_timeout(now),
+                TimerKind::Persist => tcb.check_persist_timeout(now).into_iter().collect(),
+                TimerKind::Keepalive => tcb.check_keepalive_timeout(now).into_iter().collect(),
+                TimerKind::DelayedAck => {
+                    if tcb.check_delayed_ack(now) {
+                        let seq = tcb.snd.nxt;
+                        if packets.push_with(|packet| {
+                            State::transmit(&quad, seq, 0x10, &[], tcb, now, packet)
+                        }) > 0
+                        {
+                            self.transmitted(packets.last().unwrap(), Some("delayed ACK"));
+                        }
+                    }
+                    Vec::new()
+                }
+                // Reaped below once expired
+                TimerKind::TimeWait | TimerKind::Handshake => Vec::new(),
+            };
+
+            for action in actions {
+                self.run_timer_action(quad, action, packets);
+            }
+            self.reap(quad);
+            self.connection_changed(quad);
+        }
+    }
+
+    fn run_timer_action(&mut self, quad: Quad, action: RetransmitAction, packets: &mut Frames) {
+        let now = self.now();
+        let Some(tcb) = self.connections.get_mut(&quad) else {
+            return;
+        };
+
+        let (len, comment) = match action {
+            RetransmitAction::Retransmit {
+                seq,
+                flags,
+                data,
+                attempt,
+            } => {
+                tcb.trace(
+                    Event::new(EventKind::Retransmit)
+                        .field("seq", seq)
+                        .field("attempt", attempt),
+                );
+                let len = packets.push_with(|packet| {
+                    State::transmit(&quad, seq, flags, &data, tcb, now, packet)
+                });
+                (len, format!("retransmission #{} of SEQ={}", attempt, seq))
+            }
+            RetransmitAction::GiveUp { seq, reason } => {
+                tcb.trace(
+                    Event::new(EventKind::GiveUp)
+                        .field("seq", seq)
+                        .field("reason", reason.to_string()),
+                );
+                tcb.abort(ConnectionError::TimedOut);
+                self.reap(quad);
+                return;
+            }
+            RetransmitAction::WindowProbe { seq, data, attempt } => {
+                tcb.trace(
+                    Event::new(EventKind::WindowProbe)
+                        .field("seq", seq)
+                        .field("attempt", attempt),
+                );
+                let len = packets
+                    .push_with(|packet| State::transmit(&quad, seq, 0x18, &data, tcb, now, packet));
+                (len, format!("zero window probe #{}", attempt))
+            }
+            RetransmitAction::KeepAlive { seq, attempt } => {
+                tcb.trace(
+                    Event::new(EventKind::KeepAlive)
+                        .field("seq", seq)
+                        .field("attempt", attempt),
+                );
+                let len = packets
+                    .push_with(|packet| State::transmit(&quad, seq, 0x10, &[], tcb, now, packet));
+                (len, format!("keep-alive probe #{}", attempt))
+            }
+        };
+        if len > 0 {
+            self.transmitted(packets.last().unwrap(), Some(&comment));
+        }
+    }
+
+    /// Append frames carrying whatever queued data the windows now allow to
+    /// `packets`
+    pub fn poll_transmit(&mut self, packets: &mut Frames) {
+        self.driver_pending = false;
+
+        // Queued frames were traced when they were built
+        packets.append(&mut self.outbox);
+        let first = packets.len();
+
+        let now = self.now();
+        let quads: Vec<Quad> = self.transmit_pending.drain().collect();
+        State::poll_transmit(&mut self.connections, &quads, now, packets);
+        for quad in quads {
+            self.flush_events(quad);
+            self.sync_timers(quad);
+        }
+        for packet in packets.iter().skip(first) {
+            self.transmitted(packet, None);
+        }
+    }
+
+    /// Time until the earliest connection timer fires
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.timers
+            .next_deadline()
+            .map(|deadline| deadline.saturating_duration_since(self.now()))
+    }
+
+    /// Enable keep-alive probes on a connection after `idle` without traffic,
+    /// or disable them
+    pub fn set_keepalive(&mut self, quad: Quad, idle: Option<Duration>) -> io::Result<()> {
+        let now = self.now();
+        let Some(tcb) = self.connections.get_mut(&quad) else {
+            return Err(self.missing_connection_error(quad));
+        };
+
+        tcb.set_keepalive(idle, now);
+        self.connection_changed(quad);
+        Ok(())
+    }
+
+    /// Bound how much written data a connection holds until the peer
+    /// acknowledges it; once the send buffer is full, writes wait
+    pub fn set_send_buffer_size(&mut self, quad: Quad, size: usize) -> io::Result<()> {
+        let Some(tcb) = self.connections.get_mut(&quad) else {
+            return Err(self.missing_connection_error(quad));
+        };
+
+        tcb.set_send_buffer_size(size);
+        self.connection_changed(quad);
+        Ok(())
+    }
+
+    /// Open a connection from `local` to `remote` (address, port), appending
+    /// the SYN to send to `packets`. The connection is writable once established.
+    pub fn connect(
+        &mut self,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        packets: &mut Frames,
+    ) -> io::Result<Quad> {
+        let quad = Quad {
+            src: remote,
+            dst: local,
+        };
+        if self.connections.contains_key(&quad) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        self.errors.remove(&quad);
+
+        // Generate ISN (in production, use secure random)
+        let iss: u32 = 1000;
+
+        let now = self.now();
+        let mut tcb = Tcb::new(quad);
+        tcb.active_open(iss, now);
+        tcb.timers.msl = self.msl;
+        tcb.has_handle = true;
+        tcb.snd.nxt = iss.wrapping_add(1);
+        tcb.queue_for_retransmission(iss, 0x02, vec![], now);
+        packets
+            .try_push_with(|syn| {
+                State::create_retransmit_packet(&quad, iss, 0x02, &[], &tcb, now, syn)
+            })
+            .map_err(io::Error::other)?;
+
+        self.connections.insert(quad, tcb);
+        self.connection_changed(quad);
+        self.trace(
+            Event::new(EventKind::StateChange)
+                .connection(quad)
+                .field("from", "Closed")
+                .field("to", "SynSent"),
+        );
+        self.transmitted(packets.last().unwrap(), Some("Closed -> SynSent"));
+        Ok(quad)
+    }
+
+    /// Open a connection like `connect`, queueing the SYN for the event loop
+    /// to send instead of returning it
+    pub fn open(&mut self, local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16)) -> io::Result<Quad> {
+        let mut outbox = std::mem::take(&mut self.outbox);
+        let opened = self.connect(local, remote, &mut outbox);
+        self.outbox = outbox;
+
+        let quad = opened?;
+        self.wake_driver();
+        Ok(quad)
+    }
+
+    /// Complete once the handshake of an opened connection does, registering
+    /// `waker` on it while the handshake is in progress
+    pub fn poll_connect(&mut self, quad: Quad, waker: &Waker) -> Poll<io::Result<()>> {
+        let Some(tcb) = self.connections.get_mut(&quad) else {
+            return Poll::Ready(Err(self.missing_connection_error(quad)));
+        };
+
+        if matches!(tcb.state, TcpState::SynSent | TcpState::SynRcvd) {
+            tcb::add_waiter(&mut tcb.wakers.write, waker);
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Start queueing established connections on `port` for `accept`
+    pub fn listen(&mut self, port: u16) -> io::Result<()> {
+        if self.listeners.contains_key(&port) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        self.listeners.insert(port, Listener::default());
+        Ok(())
+    }
+
+    /// Stop listening on `port`, dropping connections not yet accepted
+    pub fn unlisten(&mut self, port: u16) {
+        self.listeners.remove(&port);
+    }
+
+    /// Take the next established connection on `port`, registering `waker`
+    /// to be woken when one arrives if there is none. Connections that were
+    /// aborted and removed while waiting are skipped.
+    pub fn poll_accept(&mut self, port: u16, waker: &Waker) -> Option<Quad> {
+        let listener = self.listeners.get_mut(&port)?;
+        while let Some(quad) = listener.backlog.pop_front() {
+            if let Some(tcb) = self.connections.get_mut(&quad) {
+                tcb.has_handle = true;
+                return Some(quad);
+            }
+        }
+        tcb::add_waiter(&mut listener.wakers, waker);
+        None
+    }
+
+    /// Close the application's side of a connection: data already written is
+    /// still sent, followed by a FIN, and reads go on until the peer closes
+    /// its side too. Closing again, or closing a connection that is already
+    /// gone, does nothing unless it was aborted.
+    pub fn close(&mut self, quad: Quad) -> io::Result<()> {
+        let Some(tcb) = self.connections.get_mut(&quad) else {
+            return match self.errors.get(&quad) {
+                Some(&error) => Err(error.into()),
+                None => Ok(()),
+            };
+        };
+
+        let previous = tcb.state;
+        tcb.close();
+        let current = tcb.state;
+        if current != previous {
+            self.trace(
+                Event::new(EventKind::StateChange)
+                    .connection(quad)
+                    .field("from", state_name(Some(previous)))
+                    .field("to", state_name(Some(current))),
+            );
+        }
+
+        self.reap(quad);
+        self.connection_changed(quad);
+        self.wake_driver();
+        Ok(())
+    }
+
+    /// The application dropped its last handle to a connection: nobody is
+    /// left to report its error to, and its side of the connection is closed
+    pub fn release_handle(&mut self, quad: Quad) {
+        self.errors.remove(&quad);
+        if let Some(tcb) = self.connections.get_mut(&quad) {
+            tcb.has_handle = false;
+            // Nothing can be written any more either
+            let _ = self.close(quad);
+        }
+    }
+
+    /// Read from a connection, registering `waker` on it if no data is ready.
+    /// Returns `Ok(0)` at end of stream, including once a closed connection
+    /// has been reaped.
+    pub fn poll_read(
+        &mut self,
+        quad: Quad,
+        buf: &mut [u8],
+        waker: &Waker,
+    ) -> Poll<io::Result<usize>> {
+        let now = self.now();
+        let Some(tcb) = self.connections.get_mut(&quad) else {
+            return Poll::Ready(match self.errors.get(&quad) {
+                Some(&error) => Err(error.into()),
+                None => Ok(0),
+            });
+        };
+
+        let nbytes = tcb.read(buf, now);
+        if nbytes > 0 || buf.is_empty() {
+            if tcb.window.window_update {
+                self.transmit_pending.insert(quad);
+                self.wake_driver();
+            }
+            return Poll::Ready(Ok(nbytes));
+        }
+
+        // Nothing more will arrive once the peer has closed its side
+        if !matches!(
+            tcb.state,
+            TcpState::SynRcvd | TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        ) {
+            self.reap(quad);
+            return Poll::Ready(Ok(0));
+        }
+
+        tcb::add_waiter(&mut tcb.wakers.read, waker);
+        Poll::Pending
+    }
+
+    /// Queue data on a connection, registering `waker` on it if the send buffer is full
+    pub fn poll_write(&mut self, quad: Quad, buf: &[u8], waker: &Waker) -> Poll<io::Result<usize>> {
+        let now = self.now();
+        let Some(tcb) = self.connections.get_mut(&quad) else {
+            return Poll::Ready(Err(self.missing_connection_error(quad)));
+        };
+
+        if !matches!(tcb.state, TcpState::Established | TcpState::CloseWait) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        match tcb.write(buf, now) {
+            Ok(nbytes) => {
+                self.connection_changed(quad);
+                self.wake_driver();
+                Poll::Ready(Ok(nbytes))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                tcb::add_waiter(&mut tcb.wakers.write, waker);
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Error for a connection that is no longer in the table: why it was
+    /// aborted, if it was
+    fn missing_connection_error(&self, quad: Quad) -> io::Error {
+        match self.errors.get(&quad) {
+            Some(&error) => error.into(),
+            None => io::ErrorKind::NotConnected.into(),
+        }
+    }
+
+    /// Current readiness of a connection; a connection that no longer exists has hung up
+    pub fn stream_readiness(&self, quad: Quad) -> Readiness {
+        let Some(tcb) = self.connections.get(&quad) else {
+            return Readiness {
+                readable: true,
+                writable: false,
+                hangup: true,
+            };
+        };
+
+        let hangup = !matches!(
+            tcb.state,
+            TcpState::SynRcvd | TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        );
+        Readiness {
+            readable: !tcb.recv_buffer.is_empty() || hangup,
+            writable: matches!(tcb.state, TcpState::Established | TcpState::CloseWait)
+                && tcb.send_buffer.free() > 0,
+            hangup,
+        }
+    }
+
+    /// A listener is readable while connections wait to be accepted
+    pub fn listener_readiness(&self, port: u16) -> Readiness {
+        Readiness {
+            readable: self
+                .listeners
+                .get(&port)
+                .is_some_and(|l| !l.backlog.is_empty()),
+            writable: false,
+            hangup: !self.listeners.contains_key(&port),
+        }
+    }
+
+    /// Set or clear the readiness poller waker of a connection
+    pub fn set_stream_poller(&mut self, quad: Quad, waker: Option<Waker>) {
+        if let Some(tcb) = self.connections.get_mut(&quad) {
+            tcb.wakers.poll = waker;
+        }
+    }
+
+    /// Set or clear the readiness poller waker of a listener
+    pub fn set_listener_poller(&mut self, port: u16, waker: Option<Waker>) {
+        if let Some(listener) = self.listeners.get_mut(&port) {
+            listener.poller = waker;
+        }
+    }
+
+    /// Tell the event loop the application queued data or opened a window
+    pub fn wake_driver(&mut self) {
+        self.driver_pending = true;
+        if let Some(waker) = self.driver_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Register the event loop task, returning true if work is already pending
+    pub fn register_driver(&mut self, waker: &Waker) -> bool {
+        self.driver_waker = Some(waker.clone());
+        self.driver_pending
+    }
+}
+
+/// Shared by the clones of an application handle to a connection, releasing
+/// the connection in the stack when the last clone is dropped
+#[derive(Debug)]
+pub(crate) struct HandleRef {
+    stack: SharedStack,
+    quad: Quad,
+}
+
+impl HandleRef {
+    pub(crate) fn new(stack: SharedStack, quad: Quad) -> Arc<Self> {
+        Arc::new(Self { stack, quad })
+    }
+}
+
+impl Drop for HandleRef {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            stack.release_handle(self.quad);
+        }
+    }
+}
+
+/// The datagram in a frame: sent frames are padded to the full buffer,
+/// received ones are exact
+fn datagram(direction: Direction, frame: &[u8]) -> &[u8] {
+    match direction {
+        Direction::Inbound => frame.get(4..).unwrap_or_default(),
+        Direction::Outbound => {
+            let total_len = u16::from_be_bytes([frame[6], frame[7]]) as usize;
+            &frame[4..(4 + total_len).min(frame.len())]
+        }
+    }
+}
+
+/// Name of a connection state for capture comments, `Closed` for no connection
+fn state_name(state: Option<TcpState>) -> String {
+    format!("{:?}", state.unwrap_or(TcpState::Closed))
+}
+
+/// Helpers for tests driving a stack with hand-made segments
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::packet_sender::SegmentBuilder;
+
+    pub(crate) const LOCAL: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 168, 0, 1), 8080);
+
+    /// ISN of the remote ends
+    pub(crate) const REMOTE_ISN: u32 = 5000;
+
+    pub(crate) fn remote(port: u16) -> (Ipv4Addr, u16) {
+        (Ipv4Addr::new(192, 0, 2, 1), port)
+    }
+
+    /// Stack that does not trace
+    pub(crate) fn quiet_stack() -> Stack {
+        let mut stack = Stack::new();
+        stack.set_tracer(Tracer::disabled());
+        stack
+    }
+
+    fn listening_stack() -> Stack {
+        let mut stack = quiet_stack();
+        stack.listen(LOCAL.1).unwrap();
+        stack
+    }
+
+    /// Frame carrying a segment from `port` on the remote host
+    pub(crate) fn segment(port: u16, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 1504];
+        frame[2..4].copy_from_slice(&0x0800u16.to_be_bytes());
+        let len = SegmentBuilder::new(remote(port), LOCAL)
+            .seq(seq)
+            .ack(ack)
+            .flags(flags)
+            .window(65535)
+            .payload(payload)
+            .write(&mut frame[4..])
+            .unwrap();
+        frame.truncate(4 + len);
+        frame
+    }
+
+    /// The segment in a frame the stack sent
+    pub(crate) fn sent(frame: &[u8]) -> TcpView<'_> {
+        let ip = Ipv4View::new(datagram(Direction::Outbound, frame)).unwrap();
+        TcpView::new(ip.payload()).unwrap()
+    }
+
+    /// Complete a handshake from `port`, returning the stack's ISN
+    pub(crate) fn establish(stack: &mut Stack, port: u16) -> u32 {
+        let syn_ack = receive(stack, &segment(port, REMOTE_ISN, 0, 0x02, &[]));
+        let iss = sent(&syn_ack[0]).sequence_number();
+        receive(
+            stack,
+            &segment(port, REMOTE_ISN + 1, iss.wrapping_add(1), 0x10, &[]),
+        );
+        iss
+    }
+
+    /// Frames the stack sends in reply to `frame`
+    pub(crate) fn receive(stack: &mut Stack, frame: &[u8]) -> Frames {
+        let mut frames = Frames::new();
+        stack.process_frame(frame, &mut frames);
+        frames
+    }
+
+    /// Frames carrying whatever the stack has queued to send
+    pub(crate) fn transmit(stack: &mut Stack) -> Frames {
+        let mut frames = Frames::new();
+        stack.poll_transmit(&mut frames);
+        frames
+    }
+
+    #[test]
+    fn errors_of_connections_never_handed_out_are_not_kept() {
+        let mut stack = listening_stack();
+
+        // Spoofed SYNs, each reset at the sequence number the SYN-ACK acknowledges
+        for port in 1..=1000 {
+            receive(&mut stack, &segment(port, REMOTE_ISN, 0, 0x02, &[]));
+            receive(&mut stack, &segment(port, REMOTE_ISN + 1, 0, 0x04, &[]));
+        }
+
+        assert!(stack.connections.is_empty());
+        assert!(stack.errors.is_empty());
+    }
+
+    #[test]
+    fn error_of_accepted_connection_is_kept_until_released() {
+        let mut stack = listening_stack();
+        establish(&mut stack, 50000);
+
+        let waker = Waker::noop();
+        let quad = stack.poll_accept(LOCAL.1, waker).unwrap();
+        receive(&mut stack, &segment(50000, REMOTE_ISN + 1, 0, 0x04, &[]));
+        assert!(stack.errors.contains_key(&quad));
+
+        let mut buf = [0u8; 16];
+        for _ in 0..2 {
+            let Poll::Ready(Err(e)) = stack.poll_read(quad, &mut buf, waker) else {
+                panic!("the reset was not reported");
+            };
+            assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        }
+
+        stack.release_handle(quad);
+        assert!(stack.errors.is_empty());
+        assert!(matches!(
+            stack.poll_read(quad, &mut buf, waker),
+            Poll::Ready(Ok(0))
+        ));
+    }
+
+    /// Actively close the connection from `port` and let the peer finish
+    /// it, leaving it in TIME-WAIT
+    fn time_wait(stack: &mut Stack, port: u16) -> Quad {
+        let iss = establish(stack, port);
+        let quad = Quad {
+            src: remote(port),
+            dst: LOCAL,
+        };
+        stack.close(quad).unwrap();
+        transmit(stack);
+        receive(
+            stack,
+            &segment(port, REMOTE_ISN + 1, iss.wrapping_add(2), 0x11, &[]),
+        );
+        assert_eq!(stack.connections[&quad].state, TcpState::TimeWait);
+        quad
+    }
+
+    #[test]
+    fn reset_in_time_wait_leaves_the_time_wait_limit() {
+        let mut stack = listening_stack();
+        stack.set_time_wait_limit(2);
+
+        for port in [50000, 50001] {
+            let quad = time_wait(&mut stack, port);
+            receive(&mut stack, &segment(port, REMOTE_ISN + 2, 0, 0x04, &[]));
+            assert!(!stack.connections.contains_key(&quad));
+        }
+        assert_eq!(stack.time_wait_count, 0);
+
+        // Neither reset connection counts against the limit any more
+        let first = time_wait(&mut stack, 50002);
+        let second = time_wait(&mut stack, 50003);
+        assert!(stack.connections.contains_key(&first));
+        assert!(stack.connections.contains_key(&second));
+        assert_eq!(stack.time_wait_count, 2);
+    }
+
+    #[test]
+    fn out_of_window_ack_is_only_acknowledged() {
+        let mut stack = listening_stack();
+        let iss = establish(&mut stack, 50000);
+        let quad = stack.poll_accept(LOCAL.1, Waker::noop()).unwrap();
+        assert!(matches!(
+            stack.poll_write(quad, b"hello", Waker::noop()),
+            Poll::Ready(Ok(5))
+        ));
+        transmit(&mut stack);
+        let (una, wnd) = (
+            stack.connections[&quad].snd.una,
+            stack.connections[&quad].snd.wnd,
+        );
+
+        // Acknowledges the data and shrinks the window, with a sequence
+        // number far outside the receive window
+        let mut frame = vec![0u8; 64];
+        frame[2..4].copy_from_slice(&0x0800u16.to_be_bytes());
+        let len = SegmentBuilder::new(remote(50000), LOCAL)
+            .seq(REMOTE_ISN + 1 + 1_000_000)
+            .ack(iss.wrapping_add(6))
+            .flags(0x10)
+            .window(100)
+            .write(&mut frame[4..])
+            .unwrap();
+        frame.truncate(4 + len);
+        let replies = receive(&mut stack, &frame);
+
+        let tcb = &stack.connections[&quad];
+        assert_eq!((tcb.snd.una, tcb.snd.wnd), (una, wnd));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(sent(&replies[0]).acknowledge_number(), REMOTE_ISN + 1);
+    }
+
+    /// Listening stack on a clock the test moves by hand
+    fn clocked_stack() -> (Stack, ManualClock) {
+        let clock = ManualClock::new();
+        let mut stack = Stack::with_clock(clock.clone());
+        stack.set_tracer(Tracer::disabled());
+        stack.listen(LOCAL.1).unwrap();
+        (stack, clock)
+    }
+
+    #[test]
+    fn unacknowledged_data_is_retransmitted_once_the_clock_passes_the_rto() {
+        let (mut stack, clock) = clocked_stack();
+        let iss = establish(&mut stack, 50000);
+        let quad = stack.poll_accept(LOCAL.1, Waker::noop()).unwrap();
+        assert!(matches!(
+            stack.poll_write(quad, b"hello", Waker::noop()),
+            Poll::Ready(Ok(5))
+        ));
+        assert_eq!(transmit(&mut stack).len(), 1);
+
+        let rto = Duration::from_millis(stack.connections[&quad].timers.rto as u64);
+        let mut frames = Frames::new();
+        clock.advance(rto - Duration::from_millis(10));
+        stack.on_timer(&mut frames);
+        assert!(frames.is_empty());
+
+        clock.advance(Duration::from_millis(10));
+        stack.on_timer(&mut frames);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(sent(&frames[0]).sequence_number(), iss.wrapping_add(1));
+        assert_eq!(sent(&frames[0]).payload(), b"hello");
+    }
+
+    #[test]
+    fn time_wait_ends_after_twice_the_msl() {
+        let (mut stack, clock) = clocked_stack();
+        stack.set_msl(Duration::from_secs(1));
+        let quad = time_wait(&mut stack, 50000);
+
+        clock.advance(Duration::from_millis(1900));
+        stack.on_timer(&mut Frames::new());
+        assert!(stack.connections.contains_key(&quad));
+
+        clock.advance(Duration::from_millis(100));
+        stack.on_timer(&mut Frames::new());
+        assert!(!stack.connections.contains_key(&quad));
+        assert_eq!(stack.time_wait_count, 0);
+    }
+}
@@ -0,0 +1,227 @@
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant};
+
+use crate::tcb::Quad;
+
+// SYN cookie layout of the initial sequence number:
+//
+//      31      27 26  24 23                                     0
+//     +----------+------+----------------------------------------+
+//     | counter  | MSS  |        MAC(key, quad, ISN, counter, MSS)|
+//     +----------+------+----------------------------------------+
+//
+// The counter advances every 64 seconds; a cookie is accepted for up to two
+// periods, and only while cookies are being issued at all, so a forged one
+// has nothing to match outside a SYN flood. Window scale and SACK permitted go into the low bits of the
+// timestamp we echo, which the peer returns in TSecr.
+
+/// MSS values a cookie can carry, indexed by its 3-bit MSS field
+const MSS_TABLE: [u16; 8] = [216, 536, 1220, 1300, 1360, 1400, 1440, 1460];
+
+/// Seconds per counter period
+const PERIOD: u64 = 64;
+
+/// Low timestamp bits holding the options: window scale (4 bits, 0xF for
+/// none) and SACK permitted (1 bit)
+const TS_OPTION_BITS: u32 = 5;
+const TS_OPTION_MASK: u32 = (1 << TS_OPTION_BITS) - 1;
+const NO_WINDOW_SCALE: u32 = 0xF;
+
+/// Stateless SYN-ACK sequence numbers for when the half-open backlog is full
+/// (RFC 4987 Section 3.6)
+#[derive(Debug)]
+pub struct SynCookies {
+    /// Secret SipHash key
+    key: RandomState,
+    origin: Instant,
+
+    /// When the last cookie was sent
+    last_issued: Option<Instant>,
+}
+
+impl Default for SynCookies {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl SynCookies {
+    /// Cookies whose counter starts at `origin`
+    pub fn new(origin: Instant) -> Self {
+        Self {
+            key: RandomState::new(),
+            origin,
+            last_issued: None,
+        }
+    }
+
+    /// Initial sequence number for a SYN with sequence number `isn` from the
+    /// peer, and the MSS it encodes: the largest table entry not above `mss`
+    pub fn encode(&mut self, quad: Quad, isn: u32, mss: u16, now: Instant) -> (u32, u16) {
+        self.last_issued = Some(now);
+        let index = MSS_TABLE
+            .iter()
+            .rposition(|&entry| entry <= mss)
+            .unwrap_or(0) as u32;
+        let counter = self.counter(now);
+
+        let cookie = (counter << 27) | (index << 24) | self.mac(quad, isn, counter, index);
+        (cookie, MSS_TABLE[index as usize])
+    }
+
+    /// Check the cookie acknowledged by the ACK completing a handshake,
+    /// returning the MSS it encodes if it is genuine and recent
+    pub fn decode(&self, quad: Quad, isn: u32, cookie: u32, now: Instant) -> Option<u16> {
+        // No cookie sent within its lifetime can be acknowledged
+        let lifetime = Duration::from_secs(2 * PERIOD);
+        if self
+            .last_issued
+            .is_none_or(|issued| now.saturating_duration_since(issued) >= lifetime)
+        {
+            return None;
+        }
+
+        let counter = cookie >> 27;
+        let index = (cookie >> 24) & 0x7;
+
+        let age = self.counter(now).wrapping_sub(counter) & 0x1F;
+        if age > 1 || cookie & 0xFF_FFFF != self.mac(quad, isn, counter, index) {
+            return None;
+        }
+
+        Some(MSS_TABLE[index as usize])
+    }
+
+    /// Store window scale and SACK permitted in the low bits of `tsval`,
+    /// keeping it no later than `tsval` so later timestamps still grow
+    pub fn encode_options(tsval: u32, window_scale: Option<u8>, sack_permitted: bool) -> u32 {
+        let scale = window_scale.map_or(NO_WINDOW_SCALE, |shift| shift.min(14) as u32);
+        let bits = (scale << 1) | sack_permitted as u32;
+
+        let encoded = (tsval & !TS_OPTION_MASK) | bits;
+        if encoded > tsval {
+            encoded.wrapping_sub(TS_OPTION_MASK + 1)
+        } else {
+            encoded
+        }
+    }
+
+    /// Recover window scale and SACK permitted from the TSecr of the ACK
+    pub fn decode_options(tsecr: u32) -> (Option<u8>, bool) {
+        let scale = (tsecr & TS_OPTION_MASK) >> 1;
+        let window_scale = (scale != NO_WINDOW_SCALE).then_some(scale as u8);
+        (window_scale, tsecr & 1 != 0)
+    }
+
+    fn counter(&self, now: Instant) -> u32 {
+        ((now.saturating_duration_since(self.origin).as_secs() / PERIOD) & 0x1F) as u32
+    }
+
+    /// 24-bit keyed MAC over the connection and the cookie's other fields
+    fn mac(&self, quad: Quad, isn: u32, counter: u32, index: u32) -> u32 {
+        (self.key.hash_one((quad, isn, counter, index)) & 0xFF_FFFF) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const QUAD: Quad = Quad {
+        src: (Ipv4Addr::new(192, 0, 2, 1), 50000),
+        dst: (Ipv4Addr::new(192, 168, 0, 1), 8080),
+    };
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn cookie_round_trips_with_the_mss_rounded_down() {
+        let origin = Instant::now();
+        let mut cookies = SynCookies::new(origin);
+
+        for (offered, encoded) in [
+            (1460, 1460),
+            (1400, 1400),
+            (1000, 536),
+            (0, 216),
+            (9000, 1460),
+        ] {
+            let (cookie, mss) = cookies.encode(QUAD, 5000, offered, origin + secs(10));
+            assert_eq!(mss, encoded);
+            assert_eq!(
+                cookies.decode(QUAD, 5000, cookie, origin + secs(20)),
+                Some(encoded)
+            );
+        }
+    }
+
+    #[test]
+    fn options_round_trip_through_the_timestamp() {
+        for (window_scale, sack_permitted) in [
+            (Some(7), true),
+            (Some(0), false),
+            (None, true),
+            (None, false),
+        ] {
+            let tsval = SynCookies::encode_options(123_456, window_scale, sack_permitted);
+            assert!(tsval <= 123_456);
+            assert_eq!(
+                SynCookies::decode_options(tsval),
+                (window_scale, sack_permitted)
+            );
+        }
+
+        // Shifts above 14 are clamped as RFC 7323 requires
+        let tsval = SynCookies::encode_options(123_456, Some(20), false);
+        assert_eq!(SynCookies::decode_options(tsval), (Some(14), false));
+    }
+
+    #[test]
+    fn expired_counter_is_rejected() {
+        let origin = Instant::now();
+        let mut cookies = SynCookies::new(origin);
+        let (cookie, mss) = cookies.encode(QUAD, 5000, 1460, origin + secs(10));
+
+        // Keep issuing so only the cookie's own age matters
+        cookies.encode(QUAD, 6000, 1460, origin + secs(130));
+        assert_eq!(
+            cookies.decode(QUAD, 5000, cookie, origin + secs(127)),
+            Some(mss)
+        );
+        assert_eq!(cookies.decode(QUAD, 5000, cookie, origin + secs(128)), None);
+    }
+
+    #[test]
+    fn forged_mac_is_rejected() {
+        let origin = Instant::now();
+        let mut cookies = SynCookies::new(origin);
+        let (cookie, _) = cookies.encode(QUAD, 5000, 1460, origin);
+
+        assert_eq!(cookies.decode(QUAD, 5000, cookie ^ 1, origin), None);
+        assert_eq!(cookies.decode(QUAD, 5001, cookie, origin), None);
+        let other = Quad {
+            src: (QUAD.src.0, QUAD.src.1 + 1),
+            ..QUAD
+        };
+        assert_eq!(cookies.decode(other, 5000, cookie, origin), None);
+
+        // Another key produces other MACs
+        let mut others = SynCookies::new(origin);
+        others.encode(QUAD, 5000, 1460, origin);
+        assert_eq!(others.decode(QUAD, 5000, cookie, origin), None);
+    }
+
+    #[test]
+    fn cookies_are_only_checked_while_being_issued() {
+        let origin = Instant::now();
+        let mut cookies = SynCookies::new(origin);
+        let (cookie, _) = cookies.encode(QUAD, 5000, 1460, origin);
+
+        cookies.last_issued = None;
+        assert_eq!(cookies.decode(QUAD, 5000, cookie, origin), None);
+    }
+}
@@ -1,7 +1,15 @@
 use std::collections::VecDeque;
+use std::fmt;
+use std::io;
 use std::net::Ipv4Addr;
+use std::task::Waker;
 use std::time::{Duration, Instant};
 
+use crate::buffer::RingBuffer;
+use crate::options::{self, TcpOptions};
+use crate::parser::MAX_PAYLOAD;
+use crate::trace::{Event, EventKind};
+
 //                               +---------+ ---------\      active OPEN
 //                               |  CLOSED |            \    -----------
 //                               +---------+<---------\   \   create TCB
@@ -72,11 +80,47 @@ pub struct Tcb {
     /// Out-of-order segments waiting to be processed
     pub reassembly_queue: VecDeque<Segment>,
     
+    /// Data written by the application, from SND.UNA up to the last byte
+    /// written; space is released as it is acknowledged
+    pub send_buffer: RingBuffer,
+    
+    /// In-order data received but not yet read by the application
+    pub recv_buffer: RingBuffer,
+    
+    /// Receive buffer auto-tuning
+    pub autotune: RecvAutoTune,
+    
     /// Window management
     pub window: WindowManagement,
     
+    /// Options agreed in the handshake
+    pub options: ConnectionOptions,
+    
     /// Timers
     pub timers: TcpTimers,
+    
+    /// Application tasks waiting on this connection
+    pub wakers: Wakers,
+    
+    /// Why the connection was aborted; once set the connection is closed
+    /// and every read and write fails with it
+    pub error: Option<ConnectionError>,
+
+    /// Set once the connection is handed to the application, which is then
+    /// told of an abort even after the TCB is gone
+    pub has_handle: bool,
+
+    /// Set once our FIN has been sent; until then a connection the
+    /// application closed keeps sending the data it had queued
+    pub fin_sent: bool,
+
+    /// Set while the stack counts the connection against its TIME-WAIT
+    /// limit, which outlives the state if the connection is then aborted
+    pub time_wait_counted: bool,
+
+    /// Events raised while handling segments and timers, drained by the
+    /// stack into its tracer
+    pub events: Vec<Event>,
 }
 
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
@@ -102,10 +146,12 @@ pub enum TcpState {
 
 /// Send Sequence Space (RFC 793 Section 3.2)
 /// 
+/// ```text
 ///       1         2          3          4
 ///  ----------|----------|----------|----------
 ///         SND.UNA    SND.NXT    SND.UNA
 ///                              +SND.WND
+/// ```
 ///
 /// 1 - old sequence numbers which have been acknowledged
 /// 2 - sequence numbers of unacknowledged data
@@ -120,7 +166,7 @@ pub struct SendSequence {
     pub nxt: u32,
     
     /// send window - number of bytes remote side is willing to accept
-    pub wnd: u16,
+    pub wnd: u32,
     
     /// send urgent pointer
     pub up: u16,
@@ -137,10 +183,12 @@ pub struct SendSequence {
 
 /// Receive Sequence Space (RFC 793 Section 3.2)
 ///
+/// ```text
 ///       1          2          3
 ///  ----------|----------|----------
 ///         RCV.NXT    RCV.NXT
 ///                   +RCV.WND
+/// ```
 ///
 /// 1 - old sequence numbers which have been acknowledged
 /// 2 - sequence numbers allowed for new reception
@@ -151,13 +199,16 @@ pub struct ReceiveSequence {
     pub nxt: u32,
     
     /// receive window - number of bytes we are willing to accept
-    pub wnd: u16,
+    pub wnd: u32,
     
     /// receive urgent pointer
     pub up: u16,
     
     /// initial receive sequence number
     pub irs: u32,
+    
+    /// latest timestamp received from the peer (TS.Recent, RFC 7323)
+    pub ts_recent: Option<u32>,
 }
 
 #[derive(Debug, Clone)]
@@ -167,7 +218,7 @@ pub struct Segment {
     pub flags: u8,
     pub window: u16,
     pub data: Vec<u8>,
-    pub timestamp: Option<std::time::Instant>,
+    pub timestamp: Option<Instant>,
     /// Number of times this segment has been retransmitted
     pub retransmit_count: u32,
     /// When this segment should be retransmitted (if timestamp is set)
@@ -179,7 +230,8 @@ pub struct WindowManagement {
     /// Maximum segment size
     pub mss: u16,
     
-    /// Window scale factor (RFC 7323)
+    /// Shift applied to the windows we advertise (Rcv.Wind.Shift, RFC 7323),
+    /// zero unless both SYNs carried the window scale option
     pub scale: u8,
     
     /// Effective send window
@@ -190,6 +242,58 @@ pub struct WindowManagement {
     
     /// Slow start threshold
     pub ssthresh: u32,
+    
+    /// Largest window the peer has advertised (MAX(SND.WND))
+    pub max_snd_wnd: u32,
+    
+    /// Set when the SWS override timeout expires, allowing one small segment
+    pub sws_override: bool,
+    
+    /// Set when the application reopened a closed receive window and the
+    /// peer has to be told with a window update
+    pub window_update: bool,
+}
+
+/// Options the peer offered in its SYN. Timestamps are echoed and used on
+/// every segment, and `window_scale` is the shift the peer applies to its
+/// windows; SACK is recorded but not negotiated, so neither side uses it yet.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionOptions {
+    pub timestamps: bool,
+    pub sack_permitted: bool,
+    pub window_scale: Option<u8>,
+}
+
+/// Tasks blocked on a connection, woken when data arrives, send buffer
+/// space is released or the connection state changes
+#[derive(Debug, Clone, Default)]
+pub struct Wakers {
+    /// Tasks waiting for data to read
+    pub read: Vec<Waker>,
+    
+    /// Tasks waiting for send buffer space
+    pub write: Vec<Waker>,
+    
+    /// Readiness poller, woken on every change without being consumed
+    pub poll: Option<Waker>,
+}
+
+/// Add `waker` to `waiters` unless it wakes a task already waiting
+pub fn add_waiter(waiters: &mut Vec<Waker>, waker: &Waker) {
+    if !waiters.iter().any(|waiter| waiter.will_wake(waker)) {
+        waiters.push(waker.clone());
+    }
+}
+
+/// Receive buffer auto-tuning state: the buffer grows to hold what the
+/// application drains in two round trips, tracking the bandwidth-delay product
+#[derive(Debug, Clone, Copy)]
+pub struct RecvAutoTune {
+    /// Bytes read by the application in the current measurement period
+    pub copied: u32,
+    
+    /// Start of the current measurement period (one RTT long)
+    pub period_start: Option<Instant>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -206,6 +310,15 @@ pub struct TcpTimers {
     /// Time-Wait timer (2MSL)
     pub time_wait: Option<std::time::Instant>,
     
+    /// Maximum segment lifetime; TIME-WAIT lasts twice as long
+    pub msl: Duration,
+    
+    /// Connection establishment timer - when a half-open connection is abandoned
+    pub handshake: Option<Instant>,
+    
+    /// Origin of the TSval clock of the timestamps we send
+    pub timestamp_origin: Option<Instant>,
+    
     /// Last time data was sent
     pub last_send: Option<std::time::Instant>,
     
@@ -217,6 +330,46 @@ pub struct TcpTimers {
     
     /// Number of consecutive retransmission timeouts
     pub consecutive_timeouts: u32,
+    
+    /// Persist timer - when to send the next zero-window probe
+    pub persist_timer: Option<Instant>,
+    
+    /// Number of zero-window probes sent since the window closed
+    pub persist_backoff: u32,
+    
+    /// Delayed ACK timer - when received data must be acknowledged
+    pub delayed_ack: Option<Instant>,
+    
+    /// Keep-alive idle time; keep-alives are off when `None`
+    pub keepalive_idle: Option<Duration>,
+    
+    /// Keep-alive timer - when to send the next keep-alive probe
+    pub keepalive: Option<Instant>,
+    
+    /// Number of keep-alive probes sent without an answer
+    pub keepalive_probes: u32,
+}
+
+/// The timers a connection runs, each with at most one pending deadline
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum TimerKind {
+    Retransmit,
+    DelayedAck,
+    Persist,
+    Keepalive,
+    TimeWait,
+    Handshake,
+}
+
+impl TimerKind {
+    pub const ALL: [TimerKind; 6] = [
+        TimerKind::Retransmit,
+        TimerKind::DelayedAck,
+        TimerKind::Persist,
+        TimerKind::Keepalive,
+        TimerKind::TimeWait,
+        TimerKind::Handshake,
+    ];
 }
 
 impl Tcb {
@@ -236,35 +389,62 @@ impl Tcb {
             },
             rcv: ReceiveSequence {
                 nxt: 0,
-                wnd: 65535, // Default receive window
+                wnd: RECV_BUFFER_SIZE as u32,
                 up: 0,
                 irs: 0,
+                ts_recent: None,
             },
             retransmission_queue: VecDeque::new(),
             reassembly_queue: VecDeque::new(),
+            send_buffer: RingBuffer::with_capacity(SEND_BUFFER_SIZE),
+            recv_buffer: RingBuffer::with_capacity(RECV_BUFFER_SIZE),
+            autotune: RecvAutoTune {
+                copied: 0,
+                period_start: None,
+            },
             window: WindowManagement {
                 mss: 1460, // Standard MSS for Ethernet
                 scale: 0,
                 effective_wnd: 65535,
                 cwnd: 1460 * 10, // Initial cwnd = 10 * MSS (RFC 6928)
                 ssthresh: u32::MAX,
+                max_snd_wnd: 0,
+                sws_override: false,
+                window_update: false,
             },
             timers: TcpTimers {
                 rto: 1000, // Initial RTO = 1 second
                 srtt: 0,
                 rttvar: 0,
                 time_wait: None,
+                msl: MSL,
+                handshake: None,
+                timestamp_origin: None,
                 last_send: None,
                 last_ack: None,
                 retransmit_timer: None,
                 consecutive_timeouts: 0,
+                persist_timer: None,
+                persist_backoff: 0,
+                delayed_ack: None,
+                keepalive_idle: None,
+                keepalive: None,
+                keepalive_probes: 0,
             },
+            options: ConnectionOptions::default(),
+            wakers: Wakers::default(),
+            error: None,
+            has_handle: false,
+            fin_sent: false,
+            time_wait_counted: false,
+            events: Vec::new(),
         }
     }
     
     /// Initialize for active open (client)
-    pub fn active_open(&mut self, iss: u32) {
+    pub fn active_open(&mut self, iss: u32, now: Instant) {
         self.state = TcpState::SynSent;
+        self.timers.timestamp_origin = Some(now);
         self.snd.iss = iss;
         self.snd.nxt = iss;
         self.snd.una = iss;
@@ -276,10 +456,12 @@ impl Tcb {
     }
     
     /// Process received SYN
-    pub fn process_syn(&mut self, seq: u32, window: u16, iss: u32) {
+    pub fn process_syn(&mut self, seq: u32, window: u16, iss: u32, now: Instant) {
         self.rcv.irs = seq;
         self.rcv.nxt = seq.wrapping_add(1);
-        self.snd.wnd = window;
+        self.snd.wnd = window as u32;
+        self.snd.wl1 = seq;
+        self.window.max_snd_wnd = self.window.max_snd_wnd.max(window as u32);
         
         match self.state {
             TcpState::Listen => {
@@ -287,17 +469,61 @@ impl Tcb {
                 self.snd.nxt = iss;
                 self.snd.una = iss;
                 self.state = TcpState::SynRcvd;
+                self.timers.handshake = Some(now + HANDSHAKE_TIMEOUT);
+                self.timers.timestamp_origin = Some(now);
             }
             TcpState::SynSent => {
                 self.state = TcpState::Established;
+                self.wake_writer();
             }
             _ => {}
         }
     }
     
+    /// Apply the options of a received SYN: the peer's MSS bounds our
+    /// segments (536 if absent, RFC 9293 Section 3.7.1), and timestamps are
+    /// used if the peer sent them. Window scaling is in effect once the
+    /// peer's SYN carries it, as ours always offers it (RFC 7323 Section 2.2)
+    pub fn process_syn_options(&mut self, syn: &TcpOptions) {
+        self.window.mss = syn.mss.unwrap_or(DEFAULT_MSS).min(MAX_PAYLOAD as u16);
+        // Shifts above 14 are treated as 14 (RFC 7323 Section 2.3)
+        let window_scale = syn.window_scale.map(|shift| shift.min(MAX_WINDOW_SCALE));
+        self.window.scale = if window_scale.is_some() { WINDOW_SCALE } else { 0 };
+        self.options = ConnectionOptions {
+            timestamps: syn.timestamps.is_some(),
+            sack_permitted: syn.sack_permitted,
+            window_scale,
+        };
+    }
+    
+    /// Options to send on a segment with `flags`: our MSS and window scale on
+    /// a SYN, and timestamps on everything once they are in use. A SYN-ACK
+    /// only offers window scaling back to a peer that offered it
+    pub fn segment_options(&self, flags: u8, now: Instant) -> TcpOptions {
+        let origin = self.timers.timestamp_origin.unwrap_or(now);
+        let syn = flags & 0x02 != 0;
+        let ack = flags & 0x10 != 0;
+        TcpOptions {
+            mss: syn.then_some(MAX_PAYLOAD as u16),
+            window_scale: (syn && (!ack || self.options.window_scale.is_some())).then_some(WINDOW_SCALE),
+            timestamps: self
+                .options
+                .timestamps
+                .then(|| (options::timestamp(origin, now), self.rcv.ts_recent.unwrap_or(0))),
+            ..TcpOptions::default()
+        }
+    }
+    
+    /// Window field for a segment with `flags`: RCV.WND scaled down by our
+    /// shift, except on a SYN, whose window is never scaled (RFC 7323
+    /// Section 2.2)
+    pub fn advertised_window(&self, flags: u8) -> u16 {
+        let shift = if flags & 0x02 != 0 { 0 } else { self.window.scale };
+        (self.rcv.wnd >> shift).min(u16::MAX as u32) as u16
+    }
+    
     /// Add segment to retransmission queue
-    pub fn queue_for_retransmission(&mut self, seq: u32, flags: u8, data: Vec<u8>) {
-        let now = Instant::now();
+    pub fn queue_for_retransmission(&mut self, seq: u32, flags: u8, data: Vec<u8>, now: Instant) {
         let retransmit_at = now + Duration::from_millis(self.timers.rto as u64);
         
         let segment = Segment {
@@ -321,8 +547,7 @@ impl Tcb {
     }
     
     /// Check if retransmission timer has expired and return segments to retransmit
-    pub fn check_retransmission_timeout(&mut self) -> Vec<RetransmitAction> {
-        let now = Instant::now();
+    pub fn check_retransmission_timeout(&mut self, now: Instant) -> Vec<RetransmitAction> {
         let mut actions = Vec::new();
         
         // Check if retransmission timer has expired
@@ -389,6 +614,11 @@ impl Tcb {
         actions
     }
     
+    /// Queue an event about this connection for the stack's tracer
+    pub fn trace(&mut self, event: Event) {
+        self.events.push(event.connection(self.quad));
+    }
+    
     /// Handle retransmission timeout - update congestion control variables
     fn handle_timeout(&mut self) {
         // On timeout, set ssthresh to max(FlightSize/2, 2*MSS) (RFC 5681)
@@ -398,18 +628,23 @@ impl Tcb {
         // Set cwnd to 1 MSS (enter slow start)
         self.window.cwnd = self.window.mss as u32;
         
-        println!("Timeout! ssthresh={}, cwnd={}, consecutive_timeouts={}", 
-            self.window.ssthresh, 
-            self.window.cwnd,
-            self.timers.consecutive_timeouts);
+        self.trace(
+            Event::new(EventKind::Timeout)
+                .field("ssthresh", self.window.ssthresh)
+                .field("cwnd", self.window.cwnd)
+                .field("consecutive_timeouts", self.timers.consecutive_timeouts),
+        );
     }
     
     /// Process received ACK - enhanced with retransmission handling
-    pub fn process_ack(&mut self, ack: u32, window: u16) -> bool {
+    pub fn process_ack(&mut self, seq: u32, ack: u32, window: u16, now: Instant) -> bool {
+        // Any segment from the peer shows the connection is alive
+        self.restart_keepalive(now);
+        
         // Check if ACK is acceptable
         if !self.is_ack_acceptable(ack) {
             // Duplicate ACK handling
-            return self.handle_duplicate_ack(ack);
+            return self.handle_duplicate_ack(seq, ack, window, now);
         }
         
         // Calculate RTT if we can
@@ -419,26 +654,36 @@ impl Tcb {
                 .unwrap_or(false) 
             {
                 // Only measure RTT for non-retransmitted segments (Karn's Algorithm)
-                let rtt = last_send.elapsed().as_millis() as u32;
+                let rtt = now.saturating_duration_since(last_send).as_millis() as u32;
                 self.update_rtt(rtt);
             }
         }
         
         // Reset consecutive timeout counter on successful ACK
         self.timers.consecutive_timeouts = 0;
-        self.timers.last_ack = Some(Instant::now());
+        self.timers.last_ack = Some(now);
         
         // Update send window
-        self.snd.wnd = window;
+        self.update_send_window(seq, ack, window);
+        
+        // Release acknowledged data; the SYN occupies sequence space but
+        // never sat in the send buffer
+        let mut newly_acked = ack.wrapping_sub(self.snd.una) as usize;
+        if self.snd.una == self.snd.iss {
+            newly_acked -= 1;
+        }
+        if self.send_buffer.consume(newly_acked) > 0 {
+            self.wake_writer();
+        }
         
-        // Calculate how much new data was acknowledged
-        let newly_acked = ack.wrapping_sub(self.snd.una);
         self.snd.una = ack;
         
-        // Remove acknowledged segments from retransmission queue
+        // Remove acknowledged segments from retransmission queue; a SYN or
+        // FIN takes a sequence number of its own
         self.retransmission_queue.retain(|seg| {
-            let seg_end = seg.seq.wrapping_add(seg.data.len() as u32);
-            seg_end > ack
+            let control_len = (seg.flags & 0x03 != 0) as u32;
+            let seg_end = seg.seq.wrapping_add(seg.data.len() as u32 + control_len);
+            seq_lt(ack, seg_end)
         });
         
         // Update retransmission timer
@@ -447,7 +692,6 @@ impl Tcb {
             self.timers.retransmit_timer = None;
         } else {
             // Reset timer for remaining segments
-            let now = Instant::now();
             let next_timeout = now + Duration::from_millis(self.timers.rto as u64);
             self.timers.retransmit_timer = Some(next_timeout);
             
@@ -457,6 +701,8 @@ impl Tcb {
             }
         }
         
+        self.update_persist_timer(now);
+        
         // Update congestion window (TCP Reno)
         if self.window.cwnd < self.window.ssthresh {
             // Slow start: cwnd += MSS for each ACK
@@ -468,77 +714,371 @@ impl Tcb {
         }
         
         // Update state based on ACK
+        let previous_state = self.state;
         match self.state {
-            TcpState::SynRcvd => {
-                if ack == self.snd.nxt {
-                    self.state = TcpState::Established;
-                }
+            TcpState::SynRcvd if ack == self.snd.nxt => {
+                self.state = TcpState::Established;
             }
-            TcpState::FinWait1 => {
-                if ack == self.snd.nxt {
-                    self.state = TcpState::FinWait2;
-                }
+            TcpState::FinWait1 if ack == self.snd.nxt => {
+                self.state = TcpState::FinWait2;
             }
-            TcpState::Closing => {
-                if ack == self.snd.nxt {
-                    self.state = TcpState::TimeWait;
-                    self.start_time_wait();
+            TcpState::Closing if ack == self.snd.nxt => {
+                self.state = TcpState::TimeWait;
+                self.start_time_wait(now);
+            }
+            TcpState::LastAck if ack == self.snd.nxt => {
+                self.state = TcpState::Closed;
+            }
+            _ => {}
+        }
+        
+        if self.state != previous_state {
+            self.wake_reader();
+            self.wake_writer();
+        }
+        
+        true
+    }
+    
+    /// Process a received RST (RFC 793 Section 3.4, RFC 5961 Section 3.2).
+    ///
+    /// Outside SYN-SENT only a RST exactly at RCV.NXT aborts the connection;
+    /// one elsewhere in the window is answered with a challenge ACK, so a
+    /// blind attacker has to guess the exact sequence number.
+    pub fn process_rst(&mut self, seq: u32, ack: Option<u32>) -> RstOutcome {
+        match self.state {
+            TcpState::Closed | TcpState::Listen => RstOutcome::Ignored,
+            
+            // In SYN-SENT the RST is valid if it acknowledges our SYN
+            TcpState::SynSent => {
+                if ack != Some(self.snd.nxt) {
+                    return RstOutcome::Ignored;
                 }
+                self.abort(ConnectionError::Refused);
+                RstOutcome::Reset
             }
-            TcpState::LastAck => {
-                if ack == self.snd.nxt {
-                    self.state = TcpState::Closed;
+            
+            _ => {
+                if seq == self.rcv.nxt {
+                    self.abort(ConnectionError::ResetByPeer);
+                    RstOutcome::Reset
+                } else if self.is_segment_acceptable(seq, 0) {
+                    RstOutcome::ChallengeAck
+                } else {
+                    RstOutcome::Ignored
                 }
             }
-            _ => {}
+        }
+    }
+    
+    /// Process the FIN of a segment whose data ends at `fin_seq`, returning
+    /// true if it should be acknowledged (RFC 9293 Section 3.10.7.4). A FIN
+    /// beyond data still missing is dropped for the peer to retransmit.
+    pub fn process_fin(&mut self, fin_seq: u32, now: Instant) -> bool {
+        match self.state {
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent => return false,
+            // Our ACK of the FIN was lost, so it is sent again
+            TcpState::CloseWait | TcpState::Closing | TcpState::LastAck | TcpState::TimeWait => return true,
+            TcpState::SynRcvd | TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => {}
+        }
+        if fin_seq != self.rcv.nxt {
+            return false;
         }
         
+        self.rcv.nxt = self.rcv.nxt.wrapping_add(1);
+        self.timers.delayed_ack = None;
+        self.state = match self.state {
+            // Our FIN is still unacknowledged, or process_ack would have
+            // moved on to FIN-WAIT-2
+            TcpState::FinWait1 => TcpState::Closing,
+            TcpState::FinWait2 => {
+                self.start_time_wait(now);
+                TcpState::TimeWait
+            }
+            _ => TcpState::CloseWait,
+        };
+        
+        // Readers see the end of the stream
+        self.wake_reader();
+        true
+    }
+    
+    /// Close our side of the connection (RFC 9293 Section 3.10.4): data
+    /// already queued is still sent, followed by a FIN. A connection still
+    /// in SYN-SENT is simply dropped.
+    pub fn close(&mut self) {
+        self.state = match self.state {
+            TcpState::SynSent => TcpState::Closed,
+            TcpState::SynRcvd | TcpState::Established => TcpState::FinWait1,
+            TcpState::CloseWait => TcpState::LastAck,
+            state => state,
+        };
+        self.wake_writer();
+    }
+    
+    /// Whether the connection is in a synchronized state, where a SYN is
+    /// answered with a challenge ACK (RFC 5961 Section 4.2)
+    pub fn is_synchronized(&self) -> bool {
+        !matches!(
+            self.state,
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent | TcpState::SynRcvd
+        )
+    }
+    
+    /// ACK acceptability of RFC 5961 Section 5.2: SEG.ACK must lie between
+    /// SND.UNA - MAX.SND.WND and SND.NXT. Anything else is dropped and
+    /// answered with a challenge ACK.
+    pub fn is_ack_in_window(&self, ack: u32) -> bool {
+        let oldest = self.snd.una.wrapping_sub(self.window.max_snd_wnd);
+        seq_le(oldest, ack) && seq_le(ack, self.snd.nxt)
+    }
+    
+    /// Process an ICMP destination unreachable quoting a segment we sent
+    /// with sequence number `seq`, returning true if it aborted the connection.
+    ///
+    /// Only protocol and port unreachable are hard errors (RFC 1122 Section
+    /// 4.2.3.9), and the quoted sequence number must be in flight so a blind
+    /// attacker cannot abort connections (RFC 5927 Section 4.1).
+    pub fn process_icmp_unreachable(&mut self, code: u8, seq: u32) -> bool {
+        if !matches!(code, ICMP_PROTOCOL_UNREACHABLE | ICMP_PORT_UNREACHABLE) {
+            return false;
+        }
+        if self.state == TcpState::Closed || !(seq_le(self.snd.una, seq) && seq_lt(seq, self.snd.nxt)) {
+            return false;
+        }
+        
+        self.abort(ConnectionError::AbortedByIcmp { code });
         true
     }
     
+    /// Tear the connection down with `error`: nothing more is sent or
+    /// delivered, and blocked tasks are woken to observe the error
+    pub fn abort(&mut self, error: ConnectionError) {
+        self.error = Some(error);
+        self.state = TcpState::Closed;
+        self.retransmission_queue.clear();
+        self.reassembly_queue.clear();
+        self.send_buffer.consume(self.send_buffer.len());
+        self.recv_buffer.consume(self.recv_buffer.len());
+        self.timers.retransmit_timer = None;
+        self.timers.persist_timer = None;
+        self.timers.time_wait = None;
+        self.timers.delayed_ack = None;
+        self.timers.keepalive = None;
+        
+        self.wake_reader();
+        self.wake_writer();
+    }
+    
+    fn wake_reader(&mut self) {
+        for waker in self.wakers.read.drain(..) {
+            waker.wake();
+        }
+        if let Some(waker) = &self.wakers.poll {
+            waker.wake_by_ref();
+        }
+    }
+    
+    fn wake_writer(&mut self) {
+        for waker in self.wakers.write.drain(..) {
+            waker.wake();
+        }
+        if let Some(waker) = &self.wakers.poll {
+            waker.wake_by_ref();
+        }
+    }
+    
     /// Handle duplicate ACK (simplified fast retransmit)
-    fn handle_duplicate_ack(&mut self, _ack: u32) -> bool {
+    fn handle_duplicate_ack(&mut self, seq: u32, ack: u32, window: u16, now: Instant) -> bool {
+        // A duplicate ACK still carries a window update, e.g. the answer to
+        // a zero-window probe or a pure window update from the receiver
+        if ack == self.snd.una && self.update_send_window(seq, ack, window) {
+            self.update_persist_timer(now);
+            return true;
+        }
+        
         // Could implement fast retransmit here (after 3 duplicate ACKs)
-        // For now, just return false
         false
     }
     
+    /// Update the send window if the segment is newer than the one that last
+    /// updated it, so reordered old segments cannot shrink the window
+    /// (RFC 9293 Section 3.10.7.4). The window is scaled by the peer's shift
+    fn update_send_window(&mut self, seq: u32, ack: u32, window: u16) -> bool {
+        let newer = seq_lt(self.snd.wl1, seq)
+            || (self.snd.wl1 == seq && seq_le(self.snd.wl2, ack));
+        if !newer {
+            return false;
+        }
+        
+        let window = (window as u32) << self.options.window_scale.unwrap_or(0);
+        let changed = self.snd.wnd != window;
+        self.snd.wnd = window;
+        self.snd.wl1 = seq;
+        self.snd.wl2 = ack;
+        self.window.max_snd_wnd = self.window.max_snd_wnd.max(window);
+        changed
+    }
+    
     /// Check if ACK number is acceptable
     fn is_ack_acceptable(&self, ack: u32) -> bool {
         // ACK should be between SND.UNA and SND.NXT
-        self.snd.una < ack && ack <= self.snd.nxt
+        seq_lt(self.snd.una, ack) && seq_le(ack, self.snd.nxt)
     }
     
     /// Check if segment is acceptable (RFC 793 Section 3.3)
-    fn is_segment_acceptable(&self, seq: u32, len: u32) -> bool {
+    pub fn is_segment_acceptable(&self, seq: u32, len: u32) -> bool {
         if len == 0 && self.rcv.wnd == 0 {
             return seq == self.rcv.nxt;
         }
         
         if len == 0 && self.rcv.wnd > 0 {
-            return self.rcv.nxt <= seq && seq < self.rcv.nxt.wrapping_add(self.rcv.wnd as u32);
+            return seq_le(self.rcv.nxt, seq) && seq_lt(seq, self.rcv.nxt.wrapping_add(self.rcv.wnd));
         }
         
         if len > 0 && self.rcv.wnd > 0 {
             let seg_end = seq.wrapping_add(len - 1);
-            let wnd_end = self.rcv.nxt.wrapping_add(self.rcv.wnd as u32 - 1);
+            let wnd_end = self.rcv.nxt.wrapping_add(self.rcv.wnd - 1);
             
-            (self.rcv.nxt <= seq && seq <= wnd_end) ||
-            (self.rcv.nxt <= seg_end && seg_end <= wnd_end)
+            (seq_le(self.rcv.nxt, seq) && seq_le(seq, wnd_end)) ||
+            (seq_le(self.rcv.nxt, seg_end) && seq_le(seg_end, wnd_end))
         } else {
             false
         }
     }
     
+    /// Process the payload of a received segment, returning true if it
+    /// should be acknowledged
+    pub fn receive(&mut self, seq: u32, data: &[u8], now: Instant) -> bool {
+        if data.is_empty() {
+            return false;
+        }
+        
+        // Unacceptable segments are answered with an ACK and dropped
+        if !self.is_segment_acceptable(seq, data.len() as u32) {
+            return true;
+        }
+        
+        // Trim data already received and data beyond the window
+        let (seq, data) = if seq_lt(seq, self.rcv.nxt) {
+            let skip = self.rcv.nxt.wrapping_sub(seq) as usize;
+            (self.rcv.nxt, &data[skip..])
+        } else {
+            (seq, data)
+        };
+        let offset = seq.wrapping_sub(self.rcv.nxt) as usize;
+        let data = &data[..data.len().min(self.rcv.wnd as usize - offset)];
+        
+        if seq != self.rcv.nxt {
+            self.buffer_segment(seq, data, now);
+            return true;
+        }
+        
+        let filled_gap = !self.reassembly_queue.is_empty();
+        self.deliver(data);
+        while let Some(data) = self.get_next_buffered_segment() {
+            self.deliver(&data);
+        }
+        
+        // Acknowledge at least every second segment, and segments filling a
+        // gap at once; otherwise wait for data to piggyback the ACK on
+        // (RFC 1122 Section 4.2.3.2, RFC 5681 Section 4.2)
+        if filled_gap || self.timers.delayed_ack.is_some() {
+            self.timers.delayed_ack = None;
+            return true;
+        }
+        self.timers.delayed_ack = Some(now + DELAYED_ACK_TIMEOUT);
+        false
+    }
+    
+    /// Check if the delayed ACK timer has expired, returning true if an ACK
+    /// should be sent now
+    pub fn check_delayed_ack(&mut self, now: Instant) -> bool {
+        match self.timers.delayed_ack {
+            Some(timer) if now >= timer => {
+                self.timers.delayed_ack = None;
+                true
+            }
+            _ => false,
+        }
+    }
+    
+    /// Move in-order data to the receive buffer, consuming receive window
+    fn deliver(&mut self, data: &[u8]) {
+        let len = self.recv_buffer.write(data);
+        self.rcv.nxt = self.rcv.nxt.wrapping_add(len as u32);
+        self.rcv.wnd = self.rcv.wnd.saturating_sub(len as u32);
+        
+        if len > 0 {
+            self.wake_reader();
+        }
+    }
+    
+    /// Read received data into `buf`, returning the number of bytes copied
+    pub fn read(&mut self, buf: &mut [u8], now: Instant) -> usize {
+        let len = self.recv_buffer.read(buf);
+        
+        self.autotune_receive_buffer(len, now);
+        
+        // A window below one MSS has stalled the sender; tell it once it reopens
+        let was_closed = self.rcv.wnd < self.window.mss as u32;
+        if self.open_receive_window() && was_closed {
+            self.window.window_update = true;
+        }
+        
+        len
+    }
+    
+    /// Receiver side SWS avoidance (RFC 1122 Section 4.2.3.3): only move the
+    /// right window edge once it can advance by min(RCV.BUFF / 2, MSS). The
+    /// window never exceeds what the 16-bit field can carry at our shift
+    fn open_receive_window(&mut self) -> bool {
+        let max_window = (u16::MAX as usize) << self.window.scale;
+        let free = self.recv_buffer.free().min(max_window) as u32;
+        let threshold = (self.recv_buffer.capacity() as u32 / 2).min(self.window.mss as u32);
+        
+        if free.saturating_sub(self.rcv.wnd) >= threshold {
+            self.rcv.wnd = free;
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Dynamic right-sizing: once per RTT, grow the receive buffer to twice
+    /// what the application read during that RTT, so the advertised window
+    /// keeps up with the bandwidth-delay product
+    fn autotune_receive_buffer(&mut self, copied: usize, now: Instant) {
+        // Without an RTT sample there is no period to measure over
+        if self.timers.srtt == 0 {
+            return;
+        }
+        
+        let start = *self.autotune.period_start.get_or_insert(now);
+        self.autotune.copied = self.autotune.copied.saturating_add(copied as u32);
+        
+        if now.duration_since(start) < Duration::from_millis(self.timers.srtt as u64) {
+            return;
+        }
+        
+        let target = (2 * self.autotune.copied as usize).min(RECV_BUFFER_MAX);
+        if target > self.recv_buffer.capacity() {
+            self.recv_buffer.resize(target);
+        }
+        
+        self.autotune.copied = 0;
+        self.autotune.period_start = Some(now);
+    }
+    
     /// Buffer out-of-order segment
-    fn buffer_segment(&mut self, seq: u32, data: &[u8]) {
+    fn buffer_segment(&mut self, seq: u32, data: &[u8], now: Instant) {
         let segment = Segment {
             seq,
             ack: 0,
             flags: 0,
             window: 0,
             data: data.to_vec(),
-            timestamp: Some(std::time::Instant::now()),
+            timestamp: Some(now),
             retransmit_count: 0,
             retransmit_at: None,
         };
@@ -546,18 +1086,23 @@ impl Tcb {
         // Insert in order
         let pos = self.reassembly_queue
             .iter()
-            .position(|s| s.seq > seq)
+            .position(|s| seq_lt(seq, s.seq))
             .unwrap_or(self.reassembly_queue.len());
         
         self.reassembly_queue.insert(pos, segment);
     }
     
-    /// Get next buffered segment if it's in order
+    /// Get next buffered segment if it's in order, trimmed to start at RCV.NXT
     fn get_next_buffered_segment(&mut self) -> Option<Vec<u8>> {
-        if let Some(seg) = self.reassembly_queue.front() {
-            if seg.seq == self.rcv.nxt {
-                let segment = self.reassembly_queue.pop_front().unwrap();
-                self.rcv.nxt = self.rcv.nxt.wrapping_add(segment.data.len() as u32);
+        while let Some(seg) = self.reassembly_queue.front() {
+            if seq_lt(self.rcv.nxt, seg.seq) {
+                return None;
+            }
+            
+            let mut segment = self.reassembly_queue.pop_front().unwrap();
+            let skip = self.rcv.nxt.wrapping_sub(segment.seq) as usize;
+            if skip < segment.data.len() {
+                segment.data.drain(..skip);
                 return Some(segment.data);
             }
         }
@@ -565,26 +1110,328 @@ impl Tcb {
     }
     
     /// Start TIME-WAIT timer (2MSL)
-    fn start_time_wait(&mut self) {
-        self.timers.time_wait = Some(std::time::Instant::now());
+    fn start_time_wait(&mut self, now: Instant) {
+        self.timers.time_wait = Some(now);
     }
     
     /// Check if TIME-WAIT has expired (2MSL = 240 seconds typically)
-    pub fn is_time_wait_expired(&self) -> bool {
+    pub fn is_time_wait_expired(&self, now: Instant) -> bool {
         if let Some(start) = self.timers.time_wait {
-            start.elapsed().as_secs() >= 240
+            now.saturating_duration_since(start) >= 2 * self.timers.msl
         } else {
             false
         }
     }
     
+    /// Check if the peer abandoned the handshake: still in SYN-RECEIVED
+    /// when the connection establishment timer runs out
+    pub fn is_handshake_expired(&self, now: Instant) -> bool {
+        self.state == TcpState::SynRcvd
+            && self.timers.handshake.is_some_and(|deadline| now >= deadline)
+    }
+    
+    /// Remember the timestamp of a segment that is not ahead of what we
+    /// expect next as TS.Recent (RFC 7323 Section 4.3)
+    pub fn update_ts_recent(&mut self, seq: u32, timestamps: Option<(u32, u32)>) {
+        let Some((tsval, _)) = timestamps else {
+            return;
+        };
+        
+        let newer = self.rcv.ts_recent.is_none_or(|recent| seq_le(recent, tsval));
+        if newer && seq_le(seq, self.rcv.nxt) {
+            self.rcv.ts_recent = Some(tsval);
+        }
+    }
+    
+    /// Whether a SYN may open a new incarnation of this connection while it
+    /// is in TIME-WAIT (RFC 6191): its timestamp must be newer than the last
+    /// one seen if both incarnations use timestamps, otherwise its sequence
+    /// number must lie beyond everything the old incarnation received
+    pub fn accepts_reincarnation(&self, seq: u32, timestamps: Option<(u32, u32)>) -> bool {
+        match (self.rcv.ts_recent, timestamps) {
+            (Some(recent), Some((tsval, _))) => seq_lt(recent, tsval),
+            _ => seq_lt(self.rcv.nxt, seq),
+        }
+    }
+    
+    /// Enable keep-alive probes after the connection has been idle for
+    /// `idle`, or disable them (RFC 1122 Section 4.2.3.6)
+    pub fn set_keepalive(&mut self, idle: Option<Duration>, now: Instant) {
+        self.timers.keepalive_idle = idle;
+        self.restart_keepalive(now);
+    }
+    
+    fn restart_keepalive(&mut self, now: Instant) {
+        self.timers.keepalive_probes = 0;
+        self.timers.keepalive = self.timers.keepalive_idle.map(|idle| now + idle);
+    }
+    
+    /// Check if the keep-alive timer has expired and build a probe: an ACK
+    /// one byte before SND.UNA that the peer must answer
+    pub fn check_keepalive_timeout(&mut self, now: Instant) -> Option<RetransmitAction> {
+        match self.timers.keepalive {
+            Some(timer) if now >= timer => {}
+            _ => return None,
+        }
+        
+        // Outstanding data is already covered by the retransmission timer
+        if !self.retransmission_queue.is_empty()
+            || !matches!(self.state, TcpState::Established | TcpState::CloseWait)
+        {
+            self.restart_keepalive(now);
+            return None;
+        }
+        
+        if self.timers.keepalive_probes >= KEEPALIVE_PROBES {
+            self.timers.keepalive = None;
+            return Some(RetransmitAction::GiveUp {
+                seq: self.snd.una,
+                reason: "Keep-alive probes unanswered".to_string(),
+            });
+        }
+        
+        self.timers.keepalive_probes += 1;
+        self.timers.keepalive = Some(now + KEEPALIVE_INTERVAL);
+        
+        Some(RetransmitAction::KeepAlive {
+            seq: self.snd.una.wrapping_sub(1),
+            attempt: self.timers.keepalive_probes,
+        })
+    }
+    
+    /// Deadline of one of the connection's timers
+    pub fn deadline(&self, kind: TimerKind) -> Option<Instant> {
+        match kind {
+            TimerKind::Retransmit => self.timers.retransmit_timer,
+            TimerKind::DelayedAck => self.timers.delayed_ack,
+            TimerKind::Persist => self.timers.persist_timer,
+            TimerKind::Keepalive => self.timers.keepalive,
+            TimerKind::TimeWait => self.timers.time_wait.map(|start| start + 2 * self.timers.msl),
+            TimerKind::Handshake if self.state == TcpState::SynRcvd => self.timers.handshake,
+            TimerKind::Handshake => None,
+        }
+    }
+    
     /// Calculate available send window
     pub fn available_window(&self) -> u32 {
         let in_flight = self.snd.nxt.wrapping_sub(self.snd.una);
-        let wnd = std::cmp::min(self.snd.wnd as u32, self.window.cwnd);
+        let wnd = std::cmp::min(self.snd.wnd, self.window.cwnd);
         wnd.saturating_sub(in_flight)
     }
     
+    /// Queue application data for transmission, returning how much fitted in
+    /// the send buffer or `WouldBlock` if it is full
+    pub fn write(&mut self, data: &[u8], now: Instant) -> io::Result<usize> {
+        if !data.is_empty() && self.send_buffer.free() == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        
+        let len = self.send_buffer.write(data);
+        self.update_persist_timer(now);
+        Ok(len)
+    }
+    
+    /// Resize the send buffer; it never shrinks below the data it holds.
+    /// A writer waiting for space is woken if there now is some.
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.send_buffer.resize(size);
+        if self.send_buffer.free() > 0 {
+            self.wake_writer();
+        }
+    }
+    
+    /// Bytes in the send buffer that have not been transmitted yet
+    fn unsent_len(&self) -> usize {
+        self.send_buffer.len().saturating_sub(self.unsent_offset())
+    }
+    
+    /// Offset of SND.NXT into the send buffer, which starts at SND.UNA
+    fn unsent_offset(&self) -> usize {
+        self.snd.nxt.wrapping_sub(self.snd.una) as usize
+    }
+    
+    /// Take as much queued data as the send window allows, returning the
+    /// segments (sequence number, flags, payload) that should be transmitted,
+    /// followed by our FIN once the application closed and everything
+    /// queued has gone out. An empty ACK is a pure window update.
+    pub fn poll_transmit(&mut self, now: Instant) -> Vec<(u32, u8, Vec<u8>)> {
+        let mut segments = Vec::new();
+        
+        if matches!(
+            self.state,
+            TcpState::Established | TcpState::CloseWait | TcpState::FinWait1 | TcpState::LastAck
+        ) {
+            self.transmit_queued(&mut segments, now);
+        }
+        
+        let closed = matches!(self.state, TcpState::FinWait1 | TcpState::LastAck);
+        if closed && !self.fin_sent && self.unsent_len() == 0 {
+            let seq = self.snd.nxt;
+            self.snd.nxt = seq.wrapping_add(1);
+            self.fin_sent = true;
+            self.queue_for_retransmission(seq, 0x11, Vec::new(), now); // FIN-ACK
+            segments.push((seq, 0x11, Vec::new()));
+        }
+        
+        // Data segments already carry the new window
+        if std::mem::take(&mut self.window.window_update) && segments.is_empty() {
+            segments.push((self.snd.nxt, 0x10, Vec::new()));
+        }
+        
+        // Every segment carries the latest ACK
+        if !segments.is_empty() {
+            self.timers.delayed_ack = None;
+        }
+        
+        segments
+    }
+    
+    fn transmit_queued(&mut self, segments: &mut Vec<(u32, u8, Vec<u8>)>, now: Instant) {
+        loop {
+            let len = if std::mem::take(&mut self.window.sws_override) {
+                (self.available_window() as usize).min(self.unsent_len())
+            } else {
+                self.sendable_len()
+            };
+            if len == 0 {
+                break;
+            }
+            
+            let mut data = vec![0u8; len];
+            self.send_buffer.peek(self.unsent_offset(), &mut data);
+            let seq = self.snd.nxt;
+            self.snd.nxt = seq.wrapping_add(len as u32);
+            self.queue_for_retransmission(seq, 0x18, data.clone(), now); // PSH-ACK
+            segments.push((seq, 0x18, data));
+        }
+        
+        self.update_persist_timer(now);
+    }
+    
+    /// Effective send MSS (RFC 9293 Section 3.7.1): the MSS counts only a
+    /// bare 20-byte header, so options sent on every segment come out of it
+    fn segment_size(&self) -> usize {
+        let options_len = if self.options.timestamps { TIMESTAMPS_LEN } else { 0 };
+        (self.window.mss as usize).saturating_sub(options_len).max(1)
+    }
+    
+    /// Sender side SWS avoidance (RFC 1122 Section 4.2.3.4): the length of the
+    /// next segment, or 0 unless it is full sized, empties the send queue, or
+    /// fills at least half of the largest window the peer has offered
+    fn sendable_len(&self) -> usize {
+        let len = (self.available_window() as usize)
+            .min(self.segment_size())
+            .min(self.unsent_len());
+        
+        if len == self.segment_size()
+            || len == self.unsent_len()
+            || len as u32 >= self.window.max_snd_wnd / 2
+        {
+            len
+        } else {
+            0
+        }
+    }
+    
+    /// Arm the persist timer when a zero window, or a window too small to use
+    /// without SWS, is blocking queued data, and disarm it once the window
+    /// reopens (RFC 9293 Section 3.8.6.1)
+    fn update_persist_timer(&mut self, now: Instant) {
+        if self.snd.wnd > 0 && (self.unsent_len() == 0 || self.sendable_len() > 0) {
+            if self.timers.persist_timer.take().is_some() {
+                self.timers.persist_backoff = 0;
+                
+                // Hand an outstanding probe byte back to the retransmission timer
+                let retransmit_at = now + Duration::from_millis(self.timers.rto as u64);
+                for segment in self.retransmission_queue.iter_mut() {
+                    if segment.retransmit_at.is_none() {
+                        segment.retransmit_at = Some(retransmit_at);
+                        self.timers.retransmit_timer.get_or_insert(retransmit_at);
+                    }
+                }
+            }
+            return;
+        }
+        
+        // Anything already in flight is covered by the retransmission timer
+        if self.unsent_len() == 0 || self.timers.retransmit_timer.is_some() {
+            return;
+        }
+        
+        if self.timers.persist_timer.is_none() {
+            self.timers.persist_timer = Some(now + self.persist_interval());
+        }
+    }
+    
+    /// Probe interval: RTO doubled for every unanswered probe, capped at 60 seconds
+    fn persist_interval(&self) -> Duration {
+        let backoff_multiplier = 2u32.pow(self.timers.persist_backoff.min(6));
+        Duration::from_millis((self.timers.rto * backoff_multiplier).min(60000) as u64)
+    }
+    
+    /// Check if the persist timer has expired and build a one-byte window probe
+    pub fn check_persist_timeout(&mut self, now: Instant) -> Option<RetransmitAction> {
+        match self.timers.persist_timer {
+            Some(timer) if now >= timer => {}
+            _ => return None,
+        }
+        
+        // A usable but small window only needs the SWS override timeout:
+        // let the next transmit send whatever fits
+        let probe_outstanding = self.retransmission_queue.front()
+            .map(|seg| seg.retransmit_at.is_none())
+            .unwrap_or(false);
+        if self.snd.wnd > 0 && !probe_outstanding {
+            self.timers.persist_timer = None;
+            self.timers.persist_backoff = 0;
+            self.window.sws_override = true;
+            return None;
+        }
+        
+        // Reuse the probe byte already in flight, otherwise send the next queued byte
+        let (seq, data) = match self.retransmission_queue.front() {
+            Some(segment) if segment.retransmit_at.is_none() => {
+                (segment.seq, segment.data.clone())
+            }
+            Some(_) => return None,
+            None => {
+                let mut byte = 0u8;
+                if self.send_buffer.peek(self.unsent_offset(), std::slice::from_mut(&mut byte)) == 0 {
+                    return None;
+                }
+                let seq = self.snd.nxt;
+                self.snd.nxt = seq.wrapping_add(1);
+                self.retransmission_queue.push_back(Segment {
+                    seq,
+                    ack: 0,
+                    flags: 0x18,
+                    window: 0,
+                    data: vec![byte],
+                    timestamp: Some(now),
+                    retransmit_count: 0,
+                    retransmit_at: None, // owned by the persist timer
+                });
+                (seq, vec![byte])
+            }
+        };
+        
+        self.timers.persist_backoff += 1;
+        self.timers.persist_timer = Some(now + self.persist_interval());
+        
+        Some(RetransmitAction::WindowProbe {
+            seq,
+            data,
+            attempt: self.timers.persist_backoff,
+        })
+    }
+    
+    /// Get time until the persist timer fires (for select/poll)
+    pub fn time_until_persist(&self, now: Instant) -> Option<Duration> {
+        self.timers
+            .persist_timer
+            .map(|timer| timer.saturating_duration_since(now))
+    }
+    
     /// Update RTT measurements (RFC 6298) - enhanced
     pub fn update_rtt(&mut self, measured_rtt: u32) {
         if self.timers.srtt == 0 {
@@ -598,11 +1445,7 @@ impl Tcb {
             // SRTT = (1 - alpha) * SRTT + alpha * R'
             // where alpha = 1/8, beta = 1/4
             
-            let diff = if self.timers.srtt > measured_rtt {
-                self.timers.srtt - measured_rtt
-            } else {
-                measured_rtt - self.timers.srtt
-            };
+            let diff = self.timers.srtt.abs_diff(measured_rtt);
             
             self.timers.rttvar = (3 * self.timers.rttvar + diff) / 4;
             self.timers.srtt = (7 * self.timers.srtt + measured_rtt) / 8;
@@ -614,14 +1457,18 @@ impl Tcb {
         // Clamp RTO between 1 second and 60 seconds (RFC 6298)
         self.timers.rto = self.timers.rto.clamp(1000, 60000);
         
-        println!("RTT updated: measured={}ms, SRTT={}ms, RTTVAR={}ms, RTO={}ms",
-            measured_rtt, self.timers.srtt, self.timers.rttvar, self.timers.rto);
+        self.trace(
+            Event::new(EventKind::RttUpdate)
+                .field("measured_ms", measured_rtt)
+                .field("srtt_ms", self.timers.srtt)
+                .field("rttvar_ms", self.timers.rttvar)
+                .field("rto_ms", self.timers.rto),
+        );
     }
     
     /// Get time until next retransmission check (for select/poll)
-    pub fn time_until_retransmit(&self) -> Option<Duration> {
+    pub fn time_until_retransmit(&self, now: Instant) -> Option<Duration> {
         self.timers.retransmit_timer.map(|timer| {
-            let now = Instant::now();
             if timer > now {
                 timer.duration_since(now)
             } else {
@@ -631,6 +1478,56 @@ impl Tcb {
     }
 }
 
+/// Sequence number comparison modulo 2^32 (RFC 9293 Section 3.4)
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// Initial capacity of the receive buffer, which bounds the advertised window
+const RECV_BUFFER_SIZE: usize = 65535;
+
+/// Default capacity of the send buffer
+const SEND_BUFFER_SIZE: usize = 65535;
+
+/// Largest size receive buffer auto-tuning may grow to. Without window
+/// scaling only the first 64 KiB of it can be advertised
+const RECV_BUFFER_MAX: usize = 4 * 1024 * 1024;
+
+/// Shift we apply to our advertised windows, the smallest that lets the
+/// 16-bit window field cover `RECV_BUFFER_MAX`
+const WINDOW_SCALE: u8 = 7;
+
+/// Largest shift allowed by RFC 7323 Section 2.3
+const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Longest an ACK for received data is held back (RFC 1122 allows up to 500 ms)
+const DELAYED_ACK_TIMEOUT: Duration = Duration::from_millis(40);
+
+/// MSS assumed when the peer's SYN carries none
+const DEFAULT_MSS: u16 = 536;
+
+/// Bytes the timestamps option takes on each segment, NOP padding included
+const TIMESTAMPS_LEN: usize = 12;
+
+/// Default maximum segment lifetime (RFC 9293 Section 3.4.2)
+pub const MSL: Duration = Duration::from_secs(120);
+
+/// How long a half-open connection waits for the final ACK of the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(75);
+
+/// Interval between unanswered keep-alive probes, and how many are sent
+/// before the connection is dropped
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(75);
+const KEEPALIVE_PROBES: u32 = 9;
+
+/// ICMP destination unreachable codes that abort a connection
+const ICMP_PROTOCOL_UNREACHABLE: u8 = 2;
+const ICMP_PORT_UNREACHABLE: u8 = 3;
+
 impl Default for Tcb {
     fn default() -> Self {
         Self::new(Quad {
@@ -640,6 +1537,17 @@ impl Default for Tcb {
     }
 }
 
+/// What a received RST did to the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RstOutcome {
+    /// The connection was aborted
+    Reset,
+    /// In the window but not at RCV.NXT; the peer gets a challenge ACK
+    ChallengeAck,
+    /// Outside the window, or not for this connection's state
+    Ignored,
+}
+
 /// Actions to take after checking retransmission timer
 #[derive(Debug, Clone)]
 pub enum RetransmitAction {
@@ -653,6 +1561,225 @@ pub enum RetransmitAction {
         seq: u32,
         reason: String,
     },
+    WindowProbe {
+        seq: u32,
+        data: Vec<u8>,
+        attempt: u32,
+    },
+    KeepAlive {
+        seq: u32,
+        attempt: u32,
+    },
+}
+
+/// Why a connection was aborted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionError {
+    /// The peer sent an acceptable RST
+    ResetByPeer,
+    
+    /// Retransmissions went unacknowledged until the stack gave up
+    TimedOut,
+    
+    /// The peer answered our SYN with a RST
+    Refused,
+    
+    /// An ICMP destination unreachable with a hard error code was received
+    AbortedByIcmp { code: u8 },
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::ResetByPeer => write!(f, "connection reset by peer"),
+            ConnectionError::TimedOut => write!(f, "connection timed out"),
+            ConnectionError::Refused => write!(f, "connection refused"),
+            ConnectionError::AbortedByIcmp { code } => {
+                write!(f, "connection aborted by ICMP destination unreachable (code {})", code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+impl From<ConnectionError> for io::Error {
+    fn from(error: ConnectionError) -> Self {
+        let kind = match error {
+            ConnectionError::ResetByPeer => io::ErrorKind::ConnectionReset,
+            ConnectionError::TimedOut => io::ErrorKind::TimedOut,
+            ConnectionError::Refused => io::ErrorKind::ConnectionRefused,
+            ConnectionError::AbortedByIcmp { .. } => io::ErrorKind::ConnectionAborted,
+        };
+        io::Error::new(kind, error)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    const REMOTE_ISN: u32 = 5000;
+
+    fn quad() -> Quad {
+        Quad {
+            src: (Ipv4Addr::new(192, 0, 2, 1), 50000),
+            dst: (Ipv4Addr::new(192, 168, 0, 1), 8080),
+        }
+    }
+
+    /// Connection in SYN-RECEIVED that answered the peer's SYN with `iss`
+    fn syn_received(iss: u32, now: Instant) -> Tcb {
+        let mut tcb = Tcb::new(quad());
+        tcb.passive_open();
+        tcb.process_syn(REMOTE_ISN, 65535, iss, now);
+        tcb.snd.nxt = iss.wrapping_add(1);
+        tcb.queue_for_retransmission(iss, 0x12, vec![], now);
+        tcb
+    }
+
+    /// Established connection whose ISS is `iss`
+    fn established(iss: u32, now: Instant) -> Tcb {
+        let mut tcb = syn_received(iss, now);
+        tcb.process_ack(REMOTE_ISN + 1, iss.wrapping_add(1), 65535, now);
+        assert_eq!(tcb.state, TcpState::Established);
+        tcb
+    }
+
+    #[test]
+    fn handshake_completes_when_snd_nxt_wraps() {
+        let now = Instant::now();
+        let mut tcb = syn_received(u32::MAX, now);
+        tcb.process_ack(REMOTE_ISN + 1, 0, 65535, now);
+        assert_eq!(tcb.state, TcpState::Established);
+        assert_eq!(tcb.snd.una, 0);
+    }
+
+    #[test]
+    fn data_acknowledged_across_the_wrap_is_released() {
+        let now = Instant::now();
+        let mut tcb = established(u32::MAX - 10, now);
+        tcb.write(&[0; 100], now).unwrap();
+        let sent = tcb.poll_transmit(now);
+        assert_eq!(sent[0].0, u32::MAX - 9);
+
+        tcb.process_ack(REMOTE_ISN + 1, 90, 65535, now);
+        assert_eq!(tcb.snd.una, 90);
+        assert!(tcb.send_buffer.is_empty());
+        assert!(tcb.retransmission_queue.is_empty());
+    }
+
+    /// Established connection whose send window was last updated by a
+    /// segment with `wl1` and `wl2`
+    fn window_updated_by(wl1: u32, wl2: u32) -> Tcb {
+        let mut tcb = established(1000, Instant::now());
+        tcb.snd.wl1 = wl1;
+        tcb.snd.wl2 = wl2;
+        tcb.snd.wnd = 1000;
+        tcb
+    }
+
+    #[test]
+    fn older_segments_do_not_update_the_send_window() {
+        // Lower sequence number
+        let mut tcb = window_updated_by(100, 50);
+        assert!(!tcb.update_send_window(90, 60, 10));
+        assert_eq!((tcb.snd.wnd, tcb.snd.wl1, tcb.snd.wl2), (1000, 100, 50));
+
+        // Same sequence number, lower acknowledgment
+        assert!(!tcb.update_send_window(100, 40, 10));
+        assert_eq!((tcb.snd.wnd, tcb.snd.wl1, tcb.snd.wl2), (1000, 100, 50));
+
+        // Same sequence number and acknowledgment, or newer ones, do
+        assert!(tcb.update_send_window(100, 50, 2000));
+        assert_eq!(tcb.snd.wnd, 2000);
+        assert!(tcb.update_send_window(100, 60, 10));
+        assert_eq!((tcb.snd.wnd, tcb.snd.wl1, tcb.snd.wl2), (10, 100, 60));
+        assert!(tcb.update_send_window(110, 0, 20));
+        assert_eq!((tcb.snd.wnd, tcb.snd.wl1, tcb.snd.wl2), (20, 110, 0));
+    }
+
+    #[test]
+    fn send_window_updates_compare_across_the_wrap() {
+        let mut tcb = window_updated_by(u32::MAX - 5, u32::MAX - 5);
+        assert!(!tcb.update_send_window(u32::MAX - 10, u32::MAX, 10));
+        assert!(!tcb.update_send_window(u32::MAX - 5, u32::MAX - 10, 10));
+        assert_eq!(tcb.snd.wnd, 1000);
+
+        // Acknowledgment past the wrap with the same sequence number
+        assert!(tcb.update_send_window(u32::MAX - 5, 3, 10));
+        assert_eq!((tcb.snd.wnd, tcb.snd.wl2), (10, 3));
+        assert!(!tcb.update_send_window(u32::MAX - 5, u32::MAX, 20));
+
+        // Sequence number past the wrap
+        assert!(tcb.update_send_window(3, 0, 20));
+        assert_eq!((tcb.snd.wnd, tcb.snd.wl1), (20, 3));
+        assert!(!tcb.update_send_window(u32::MAX, 10, 30));
+        assert_eq!(tcb.snd.wnd, 20);
+    }
+
+    /// Fill the receive window with in-order data from the peer
+    fn fill_receive_window(tcb: &mut Tcb, now: Instant) {
+        while tcb.rcv.wnd > 0 {
+            let len = (tcb.rcv.wnd as usize).min(1000);
+            tcb.receive(tcb.rcv.nxt, &vec![0; len], now);
+        }
+        assert_eq!(tcb.recv_buffer.free(), 0);
+    }
+
+    #[test]
+    fn receive_window_reopens_by_at_least_one_mss() {
+        let now = Instant::now();
+        let mut tcb = established(1000, now);
+        fill_receive_window(&mut tcb, now);
+
+        tcb.read(&mut [0; 1000], now);
+        assert_eq!(tcb.rcv.wnd, 0);
+        assert!(tcb.poll_transmit(now).is_empty());
+
+        tcb.read(&mut [0; 460], now);
+        assert_eq!(tcb.rcv.wnd, 1460);
+        let update = tcb.poll_transmit(now);
+        assert_eq!(update, vec![(tcb.snd.nxt, 0x10, Vec::new())]);
+    }
+
+    #[test]
+    fn small_receive_buffer_reopens_by_half() {
+        let now = Instant::now();
+        let mut tcb = established(1000, now);
+        tcb.recv_buffer = RingBuffer::with_capacity(1000);
+        tcb.rcv.wnd = 1000;
+        fill_receive_window(&mut tcb, now);
+
+        tcb.read(&mut [0; 400], now);
+        assert_eq!(tcb.rcv.wnd, 0);
+        tcb.read(&mut [0; 100], now);
+        assert_eq!(tcb.rcv.wnd, 500);
+    }
+
+    #[test]
+    fn sender_holds_small_segments_while_data_is_unacknowledged() {
+        let now = Instant::now();
+        let mut tcb = established(1000, now);
+        tcb.window.mss = 536;
+        tcb.process_ack(REMOTE_ISN + 1, 1001, 1200, now);
+        tcb.write(&[0; 3000], now).unwrap();
+
+        // Two full segments fit the window; the 128 bytes left of it do not
+        // make a segment worth sending
+        let lens: Vec<usize> = tcb.poll_transmit(now).iter().map(|(_, _, data)| data.len()).collect();
+        assert_eq!(lens, [536, 536]);
+        assert!(tcb.poll_transmit(now).is_empty());
+
+        // Acknowledging the first opens room for one more full segment only
+        tcb.process_ack(REMOTE_ISN + 1, 1001 + 536, 1200, now);
+        let lens: Vec<usize> = tcb.poll_transmit(now).iter().map(|(_, _, data)| data.len()).collect();
+        assert_eq!(lens, [536]);
+
+        // Once everything is acknowledged a short tail that empties the
+        // queue goes out
+        tcb.process_ack(REMOTE_ISN + 1, tcb.snd.nxt, 65535, now);
+        let lens: Vec<usize> = tcb.poll_transmit(now).iter().map(|(_, _, data)| data.len()).collect();
+        assert_eq!(lens, [536, 536, 320]);
+    }
+}
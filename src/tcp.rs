@@ -1,10 +1,11 @@
 use std::collections::HashMap;
-use crate::parser::IPHeader;
-use crate::parser::Packet;
-use crate::parser::TCPHeader;
-use crate::tcb::{Quad, Tcb, TcpState, RetransmitAction};
+use std::time::{Duration, Instant};
+use crate::options::TcpOptions;
+use crate::packet_sender::{BufferTooSmall, Frames, SegmentBuilder};
+use crate::parser::TcpView;
+use crate::tcb::{Quad, RstOutcome, Tcb, TcpState};
+use crate::trace::{Event, EventKind};
 
-#[allow(dead_code)]
 pub enum State {
     Closed,
     Listen,
@@ -13,13 +14,54 @@ pub enum State {
     Estab,
 }
 
-#[allow(dead_code)]
-pub struct Sequence {
-    snd_una: u32,
-    snd_nxt: u32,
-    seg_ack: u32,
-    seg_seq: u32,
-    seg_len: u32,
+/// Global rate limit on challenge ACKs (RFC 5961 Section 7), so an attacker
+/// cannot turn a flood of spoofed segments into a flood of replies
+#[derive(Debug)]
+pub struct ChallengeAckLimit {
+    /// Challenge ACKs allowed per second across all connections
+    limit: u32,
+    window_start: Option<Instant>,
+    sent: u32,
+}
+
+/// Default number of challenge ACKs sent per second
+pub const CHALLENGE_ACK_LIMIT: u32 = 1000;
+
+impl Default for ChallengeAckLimit {
+    fn default() -> Self {
+        Self::new(CHALLENGE_ACK_LIMIT)
+    }
+}
+
+impl ChallengeAckLimit {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            window_start: None,
+            sent: 0,
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: u32) {
+        self.limit = limit;
+    }
+
+    /// Take one challenge ACK from the current one-second budget
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.window_start {
+            Some(start) if now.saturating_duration_since(start) < Duration::from_secs(1) => {}
+            _ => {
+                self.window_start = Some(now);
+                self.sent = 0;
+            }
+        }
+
+        if self.sent >= self.limit {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
 }
 
 impl State {
@@ -40,13 +82,24 @@ impl State {
         }
         .to_string()
     }
+    /// Run `segment` through its connection's state machine, writing any
+    /// reply into `frame` and returning its length, or 0 if there is none
     pub fn tcp_connection(
         state: &String,
-        packet: &Packet,
+        segment: &TcpView,
         connections: &mut HashMap<Quad, Tcb>,
         quad: Quad,
-    ) -> [u8; 1504] {
+        challenge_acks: &mut ChallengeAckLimit,
+        now: Instant,
+        frame: &mut [u8],
+    ) -> usize {
         let raw_packet = if state == "SYN" {
+            // A SYN on a synchronized connection may be spoofed; only the
+            // real peer can act on the challenge ACK (RFC 5961 Section 4.2)
+            if let Some(tcb) = connections.get_mut(&quad).filter(|tcb| tcb.is_synchronized()) {
+                return Self::challenge_ack(&quad, tcb, challenge_acks, now, frame);
+            }
+
             let tcb = connections.entry(quad).or_insert_with(|| {
                 let mut tcb = Tcb::new(quad);
                 tcb.passive_open();
@@ -58,131 +111,261 @@ impl State {
             
             // Process the SYN
             tcb.process_syn(
-                packet.tcp_header.sequence_number,
-                packet.tcp_header.window,
+                segment.sequence_number(),
+                segment.window(),
                 isn,
+                now,
             );
+            let options = segment.options();
+            tcb.update_ts_recent(segment.sequence_number(), options.timestamps);
 
-            let ack_num = packet.tcp_header.sequence_number.wrapping_add(1);
-            println!("Sending SYN-ACK: SEQ={}, ACK={} (State: {:?})", isn, ack_num, tcb.state);
-
-            let response_packet = Packet {
-                ip_header: IPHeader {
-                    version: 4,
-                    ihl: 5,
-                    type_of_service: 0,
-                    total_len: 40,
-                    identification: packet.ip_header.identification,
-                    flags: 0x02,
-                    fragment_offset: 0,
-                    ttl: 64,
-                    protocol: 6,
-                    header_checksum: 0,
-                    source: packet.ip_header.destination,
-                    destination: packet.ip_header.source,
-                },
-                tcp_header: TCPHeader {
-                    source_port: packet.tcp_header.destination_port,
-                    destination_port: packet.tcp_header.source_port,
-                    sequence_number: isn,
-                    acknowledge_number: ack_num,
-                    data_offset: 5,
-                    reserved: 0,
-                    control_bit: 0x12,
-                    window: tcb.rcv.wnd,
-                    checksum: 0,
-                    urgent_pointer: 0,
-                },
-                data: [0u8; 500],
-            };
+            tcb.process_syn_options(&options);
+
+            let ack_num = segment.sequence_number().wrapping_add(1);
+            tcb.trace(Event::new(EventKind::SynAck).field("seq", isn).field("ack", ack_num));
 
             // Update send next and queue for retransmission
             tcb.snd.nxt = isn.wrapping_add(1);
-            tcb.queue_for_retransmission(isn, 0x12, vec![]); // SYN-ACK needs retransmission
+            tcb.queue_for_retransmission(isn, 0x12, vec![], now); // SYN-ACK needs retransmission
+
+            Self::transmit(&quad, isn, 0x12, &[], tcb, now, frame)
+        } else if state == "SYN-ACK"
+            && connections.get(&quad).is_some_and(|tcb| tcb.state == TcpState::SynSent)
+        {
+            let tcb = connections.get_mut(&quad).unwrap();
 
-            response_packet.create_packet()
-        } else if state == "ACK" {
+            // The SYN-ACK must acknowledge our SYN (RFC 9293 Section 3.10.7.3)
+            if segment.acknowledge_number() != tcb.snd.nxt {
+                return 0;
+            }
+
+            let iss = tcb.snd.iss;
+            tcb.process_syn(segment.sequence_number(), segment.window(), iss, now);
+            let options = segment.options();
+            tcb.update_ts_recent(segment.sequence_number(), options.timestamps);
+            // The SYN-ACK's own window is never scaled, so it is taken before
+            // the negotiated shift applies (RFC 7323 Section 2.2)
+            tcb.process_ack(
+                segment.sequence_number(),
+                segment.acknowledge_number(),
+                segment.window(),
+                now,
+            );
+            tcb.process_syn_options(&options);
+            Self::transmit(&quad, tcb.snd.nxt, 0x10, &[], tcb, now, frame)
+        } else if segment.control_bit() & 0x04 != 0 {
             if let Some(tcb) = connections.get_mut(&quad) {
-                // Process the ACK
-                if tcb.process_ack(
-                    packet.tcp_header.acknowledge_number,
-                    packet.tcp_header.window,
-                ) {
-                    println!("Connection established! State: {:?}", tcb.state);
-                    
-                    if tcb.state == TcpState::Established {
-                        println!("TCP handshake complete for {}:{} -> {}:{}",
-                            quad.src.0, quad.src.1, quad.dst.0, quad.dst.1);
-                    }
+                let ack = (segment.control_bit() & 0x10 != 0)
+                    .then_some(segment.acknowledge_number());
+
+                match tcb.process_rst(segment.sequence_number(), ack) {
+                    RstOutcome::Reset => tcb.trace(Event::new(EventKind::Reset)),
+                    RstOutcome::ChallengeAck => return Self::challenge_ack(&quad, tcb, challenge_acks, now, frame),
+                    RstOutcome::Ignored => {}
                 }
             }
-            [0u8; 1504]
+            0
+        } else if segment.control_bit() & 0x10 != 0 {
+            if let Some(tcb) = connections.get_mut(&quad) {
+                // Segments outside the receive window are only acknowledged,
+                // before their ACK can touch SND.UNA or the send window
+                // (RFC 9293 Section 3.10.7.4)
+                let fin = (segment.control_bit() & 0x01 != 0) as u32;
+                let len = segment.payload().len() as u32 + fin;
+                if tcb.state != TcpState::SynSent && !tcb.is_segment_acceptable(segment.sequence_number(), len) {
+                    return Self::limited_ack(&quad, tcb, challenge_acks, now, frame);
+                }
+
+                // Drop segments acknowledging data we never sent, or sent
+                // too long ago to be in flight (RFC 5961 Section 5.2)
+                if !tcb.is_ack_in_window(segment.acknowledge_number()) {
+                    return Self::challenge_ack(&quad, tcb, challenge_acks, now, frame);
+                }
+
+                tcb.update_ts_recent(segment.sequence_number(), segment.options().timestamps);
+                
+                // Process the ACK; the stack reports the state change
+                tcb.process_ack(
+                    segment.sequence_number(),
+                    segment.acknowledge_number(),
+                    segment.window(),
+                    now,
+                );
+                
+                // Acknowledge any data the segment carried, and the FIN that
+                // follows it
+                let mut ack_now = tcb.receive(segment.sequence_number(), segment.payload(), now);
+                if segment.control_bit() & 0x01 != 0 {
+                    let fin_seq = segment.sequence_number().wrapping_add(segment.payload().len() as u32);
+                    ack_now |= tcb.process_fin(fin_seq, now);
+                }
+                if ack_now {
+                    return Self::transmit(&quad, tcb.snd.nxt, 0x10, &[], tcb, now, frame);
+                }
+            }
+            0
         } else {
-            [0u8; 1504]
+            0
         };
         
         raw_packet
     }
     
-    /// Check for retransmissions across all connections
-    pub fn check_retransmissions(connections: &mut HashMap<Quad, Tcb>) -> Vec<(Quad, RetransmitAction)> {
-        let mut actions = Vec::new();
-        
-        for (quad, tcb) in connections.iter_mut() {
-            let tcb_actions = tcb.check_retransmission_timeout();
-            for action in tcb_actions {
-                actions.push((*quad, action));
-            }
+    /// ACK carrying our current SND.NXT and RCV.NXT, unless the global
+    /// challenge ACK budget is spent
+    fn challenge_ack(
+        quad: &Quad,
+        tcb: &mut Tcb,
+        challenge_acks: &mut ChallengeAckLimit,
+        now: Instant,
+        frame: &mut [u8],
+    ) -> usize {
+        if !challenge_acks.allow(now) {
+            return 0;
         }
-        
-        actions
+        tcb.trace(Event::new(EventKind::ChallengeAck));
+        Self::transmit(quad, tcb.snd.nxt, 0x10, &[], tcb, now, frame)
+    }
+
+    /// ACK of an unacceptable segment, drawn from the challenge ACK budget
+    /// so a flood of out-of-window segments cannot cause an ACK storm
+    fn limited_ack(
+        quad: &Quad,
+        tcb: &mut Tcb,
+        challenge_acks: &mut ChallengeAckLimit,
+        now: Instant,
+        frame: &mut [u8],
+    ) -> usize {
+        if !challenge_acks.allow(now) {
+            return 0;
+        }
+        Self::transmit(quad, tcb.snd.nxt, 0x10, &[], tcb, now, frame)
     }
     
+    /// Transmit queued application data on the given connections, appending
+    /// a frame to `packets` for each segment
+    pub fn poll_transmit(connections: &mut HashMap<Quad, Tcb>, quads: &[Quad], now: Instant, packets: &mut Frames) {
+        for quad in quads {
+            let Some(tcb) = connections.get_mut(quad) else {
+                continue;
+            };
+            for (seq, flags, data) in tcb.poll_transmit(now) {
+                packets.push_with(|frame| Self::transmit(quad, seq, flags, &data, tcb, now, frame));
+            }
+        }
+    }
+
+    /// Write a segment of `tcb`'s connection into `frame`, returning its
+    /// length. A segment that does not fit is reported on the connection's
+    /// trace and dropped, leaving 0.
+    pub fn transmit(
+        quad: &Quad,
+        seq: u32,
+        flags: u8,
+        data: &[u8],
+        tcb: &mut Tcb,
+        now: Instant,
+        frame: &mut [u8],
+    ) -> usize {
+        match Self::create_retransmit_packet(quad, seq, flags, data, tcb, now, frame) {
+            Ok(len) => len,
+            Err(error) => {
+                let event = Event::new(EventKind::TransmitError).field("seq", seq);
+                tcb.trace(event.field("error", error.to_string()));
+                0
+            }
+        }
+    }
+
     /// Create retransmission packet
     pub fn create_retransmit_packet(
         quad: &Quad,
         seq: u32,
         flags: u8,
-        data: Vec<u8>,
+        data: &[u8],
         tcb: &Tcb,
-    ) -> [u8; 1504] {
-        let ack_num = tcb.rcv.nxt;
-        
-        let packet = Packet {
-            ip_header: IPHeader {
-                version: 4,
-                ihl: 5,
-                type_of_service: 0,
-                total_len: 40 + data.len() as u16,
-                identification: 0,
-                flags: 0x02,
-                fragment_offset: 0,
-                ttl: 64,
-                protocol: 6,
-                header_checksum: 0,
-                source: quad.dst.0,
-                destination: quad.src.0,
-            },
-            tcp_header: TCPHeader {
-                source_port: quad.dst.1,
-                destination_port: quad.src.1,
-                sequence_number: seq,
-                acknowledge_number: ack_num,
-                data_offset: 5,
-                reserved: 0,
-                control_bit: flags,
-                window: tcb.rcv.wnd,
-                checksum: 0,
-                urgent_pointer: 0,
-            },
-            data: {
-                let mut arr = [0u8; 500];
-                let len = data.len().min(500);
-                arr[..len].copy_from_slice(&data[..len]);
-                arr
-            },
+        now: Instant,
+        frame: &mut [u8],
+    ) -> Result<usize, BufferTooSmall> {
+        Self::create_segment(quad, seq, flags, data, tcb, tcb.segment_options(flags, now), frame)
+    }
+
+    /// Write a segment of `tcb`'s connection carrying `options` into the tun
+    /// frame `frame`, returning the frame's length
+    pub fn create_segment(
+        quad: &Quad,
+        seq: u32,
+        flags: u8,
+        data: &[u8],
+        tcb: &Tcb,
+        options: TcpOptions,
+        frame: &mut [u8],
+    ) -> Result<usize, BufferTooSmall> {
+        if frame.len() < 4 {
+            return Err(BufferTooSmall {
+                needed: 4,
+                available: frame.len(),
+            });
+        }
+        frame[..2].fill(0);
+        frame[2..4].copy_from_slice(&0x0800u16.to_be_bytes());
+
+        let len = SegmentBuilder::new(quad.dst, quad.src)
+            .seq(seq)
+            .ack(tcb.rcv.nxt)
+            .flags(flags)
+            .window(tcb.advertised_window(flags))
+            .options(&options)
+            .payload(data)
+            .write(&mut frame[4..])
+            .map_err(|error| BufferTooSmall {
+                needed: error.needed + 4,
+                available: frame.len(),
+            })?;
+
+        Ok(len + 4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::parser::Ipv4View;
+
+    fn connection() -> (Quad, Tcb) {
+        let quad = Quad {
+            src: (Ipv4Addr::new(192, 0, 2, 1), 50000),
+            dst: (Ipv4Addr::new(192, 168, 0, 1), 8080),
         };
-        
-        packet.create_packet()
+        let mut tcb = Tcb::new(quad);
+        tcb.active_open(1000, Instant::now());
+        (quad, tcb)
+    }
+
+    #[test]
+    fn segment_is_written_into_the_frame() {
+        let (quad, tcb) = connection();
+        let mut frame = [0xFFu8; 1504];
+        let len = State::create_segment(&quad, 1000, 0x18, b"hello", &tcb, TcpOptions::default(), &mut frame).unwrap();
+
+        assert_eq!(&frame[..4], &[0, 0, 0x08, 0x00]);
+        let ip = Ipv4View::new(&frame[4..len]).unwrap();
+        assert_eq!(ip.total_len() as usize, len - 4);
+        assert_eq!(&frame[len - 5..len], b"hello");
+    }
+
+    #[test]
+    fn oversized_segment_is_an_error() {
+        let (quad, mut tcb) = connection();
+        let mut frame = [0u8; 64];
+        let error = State::create_segment(&quad, 1000, 0x18, &[0; 100], &tcb, TcpOptions::default(), &mut frame)
+            .unwrap_err();
+        assert_eq!(error, BufferTooSmall { needed: 144, available: 64 });
+
+        // Reported on the connection's trace and dropped
+        assert_eq!(State::transmit(&quad, 1000, 0x18, &[0; 100], &mut tcb, Instant::now(), &mut frame), 0);
+        assert!(tcb.events.iter().any(|event| event.kind == EventKind::TransmitError));
     }
 }
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Slots per level; each level's slot spans a whole lower level
+const SLOTS: usize = 64;
+const SLOT_BITS: u32 = 6;
+
+/// Four levels of 64 slots at 1 ms resolution reach about 4.6 hours
+const LEVELS: usize = 4;
+
+/// Hierarchical timer wheel (Varghese and Lauck) with 1 ms ticks.
+///
+/// Scheduling, cancelling and firing a timer cost O(1), and finding the
+/// next deadline scans a fixed number of slots, so none of them depend on
+/// how many timers are pending. Deadlines are rounded up to the next tick,
+/// so timers never fire early.
+///
+/// Cancelling or rescheduling a key only updates its live deadline; the old
+/// entry stays in its slot and is discarded when the wheel reaches it.
+#[derive(Debug)]
+pub struct TimerWheel<K> {
+    /// Time of tick 0
+    origin: Instant,
+
+    /// Last tick processed by `advance`
+    elapsed: u64,
+
+    /// `levels[l][s]` holds entries expiring in slot `s` of level `l`
+    levels: Vec<Vec<Vec<Entry<K>>>>,
+
+    /// Entries beyond the top level, rehomed when it wraps around
+    overflow: Vec<Entry<K>>,
+
+    /// Live deadline (in ticks) of every scheduled key
+    deadlines: HashMap<K, u64>,
+}
+
+impl<K: Copy + Eq + Hash> Default for TimerWheel<K> {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry<K> {
+    key: K,
+    tick: u64,
+}
+
+impl<K: Copy + Eq + Hash> TimerWheel<K> {
+    pub fn new(origin: Instant) -> Self {
+        Self {
+            origin,
+            elapsed: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            overflow: Vec::new(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// Number of scheduled timers
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    /// Schedule `key` to fire at `deadline`, replacing any deadline it had
+    pub fn schedule(&mut self, key: K, deadline: Instant) {
+        let tick = self.tick_at(deadline).max(self.elapsed + 1);
+        if self.deadlines.insert(key, tick) == Some(tick) {
+            return;
+        }
+        self.place(Entry { key, tick });
+    }
+
+    /// Schedule `key` at `deadline`, or cancel it if `deadline` is `None`
+    pub fn set(&mut self, key: K, deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => self.schedule(key, deadline),
+            None => self.cancel(key),
+        }
+    }
+
+    pub fn cancel(&mut self, key: K) {
+        self.deadlines.remove(&key);
+    }
+
+    /// Advance the wheel to `now`, returning the keys whose deadline passed
+    pub fn advance(&mut self, now: Instant) -> Vec<K> {
+        let target = now.saturating_duration_since(self.origin).as_millis() as u64;
+        let mut expired = Vec::new();
+
+        // Jump from one occupied slot to the next rather than tick by tick
+        loop {
+            let next = match self.next_tick() {
+                Some(next) if next <= target => next,
+                Some(_) => {
+                    self.elapsed = self.elapsed.max(target);
+                    break;
+                }
+                None => {
+                    // Only stale entries are left
+                    self.elapsed = self.elapsed.max(target);
+                    self.levels.iter_mut().flatten().for_each(Vec::clear);
+                    self.overflow.clear();
+                    break;
+                }
+            };
+
+            self.elapsed = next;
+            self.cascade();
+
+            let slot = (self.elapsed as usize) & (SLOTS - 1);
+            for entry in std::mem::take(&mut self.levels[0][slot]) {
+                if self.deadlines.get(&entry.key) != Some(&entry.tick) {
+                    continue; // Cancelled or rescheduled
+                }
+                if entry.tick > self.elapsed {
+                    self.place(entry);
+                    continue;
+                }
+                self.deadlines.remove(&entry.key);
+                expired.push(entry.key);
+            }
+        }
+
+        expired
+    }
+
+    /// Lower bound on the earliest pending deadline. It may be earlier than
+    /// the real deadline when that sits on a higher level, in which case
+    /// `advance` at that time only moves it down the wheel.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.next_tick()
+            .map(|tick| self.origin + Duration::from_millis(tick))
+    }
+
+    /// First tick at which an occupied slot fires or cascades
+    fn next_tick(&self) -> Option<u64> {
+        if self.deadlines.is_empty() {
+            return None;
+        }
+
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            let current = ((self.elapsed >> shift) as usize) & (SLOTS - 1);
+
+            if let Some(slot) =
+                (current + 1..SLOTS).find(|&slot| !self.levels[level][slot].is_empty())
+            {
+                let base = (self.elapsed >> (shift + SLOT_BITS)) << (shift + SLOT_BITS);
+                return Some(base + ((slot as u64) << shift));
+            }
+        }
+
+        // Everything left is in the overflow list, which is rehomed when
+        // the top level wraps around
+        let span = 1u64 << (SLOT_BITS * LEVELS as u32);
+        Some((self.elapsed / span + 1) * span)
+    }
+
+    /// Move the entries of every higher level slot that starts at the
+    /// current tick down towards level 0
+    fn cascade(&mut self) {
+        let span = 1u64 << (SLOT_BITS * LEVELS as u32);
+        if self.elapsed.is_multiple_of(span) {
+            for entry in std::mem::take(&mut self.overflow) {
+                self.replace(entry);
+            }
+        }
+
+        for level in (1..LEVELS).rev() {
+            let shift = SLOT_BITS * level as u32;
+            if self.elapsed & ((1 << shift) - 1) != 0 {
+                continue;
+            }
+
+            let slot = ((self.elapsed >> shift) as usize) & (SLOTS - 1);
+            for entry in std::mem::take(&mut self.levels[level][slot]) {
+                self.replace(entry);
+            }
+        }
+    }
+
+    /// Re-place an entry unless it was cancelled or rescheduled meanwhile
+    fn replace(&mut self, entry: Entry<K>) {
+        if self.deadlines.get(&entry.key) == Some(&entry.tick) {
+            self.place(entry);
+        }
+    }
+
+    /// Put an entry on the level whose slot width matches how far away it is
+    fn place(&mut self, entry: Entry<K>) {
+        // The highest bit in which the deadline differs from now picks the level
+        let tick = entry.tick.max(self.elapsed);
+        let differing = (tick ^ self.elapsed) | (SLOTS as u64 - 1);
+        let level = ((63 - differing.leading_zeros()) / SLOT_BITS) as usize;
+
+        if level >= LEVELS {
+            self.overflow.push(entry);
+            return;
+        }
+
+        let slot = ((tick >> (SLOT_BITS * level as u32)) as usize) & (SLOTS - 1);
+        self.levels[level][slot].push(entry);
+    }
+
+    /// Tick containing `time`, rounded up
+    fn tick_at(&self, time: Instant) -> u64 {
+        let since = time.saturating_duration_since(self.origin);
+        since.as_nanos().div_ceil(1_000_000) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    /// Advance through every reported deadline up to `end`, returning each
+    /// key with the time it fired at
+    fn run_until(
+        wheel: &mut TimerWheel<u32>,
+        origin: Instant,
+        end: Duration,
+    ) -> Vec<(u32, Duration)> {
+        let mut fired = Vec::new();
+        while let Some(deadline) = wheel.next_deadline().filter(|&d| d <= origin + end) {
+            for key in wheel.advance(deadline) {
+                fired.push((key, deadline - origin));
+            }
+        }
+        fired
+    }
+
+    #[test]
+    fn fires_at_deadline_rounded_up_to_a_tick() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::new(origin);
+        wheel.schedule(1, origin + Duration::from_micros(2500));
+
+        assert!(wheel.advance(origin + ms(2)).is_empty());
+        assert_eq!(wheel.advance(origin + ms(3)), vec![1]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn higher_levels_cascade_down_to_the_exact_tick() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::new(origin);
+
+        // One deadline on each level and one past the top in the overflow list
+        let deadlines = [(1, 10), (2, 100), (3, 5_000), (4, 300_000), (5, 20_000_000)];
+        for (key, deadline) in deadlines {
+            wheel.schedule(key, origin + ms(deadline));
+        }
+
+        let fired = run_until(&mut wheel, origin, ms(20_000_000));
+        let expected: Vec<_> = deadlines
+            .iter()
+            .map(|&(key, deadline)| (key, ms(deadline)))
+            .collect();
+        assert_eq!(fired, expected);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn advancing_past_several_deadlines_fires_them_all() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::new(origin);
+        for key in 0..200 {
+            wheel.schedule(key, origin + ms(1 + key as u64 * 37));
+        }
+
+        let mut fired = wheel.advance(origin + ms(10_000));
+        fired.sort_unstable();
+        assert_eq!(fired, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cancelled_and_rescheduled_timers_leave_no_stale_expiry() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::new(origin);
+        wheel.schedule(1, origin + ms(100));
+        wheel.schedule(2, origin + ms(100));
+        wheel.cancel(1);
+        wheel.schedule(2, origin + ms(5_000));
+        wheel.set(3, Some(origin + ms(200)));
+        wheel.set(3, None);
+
+        assert_eq!(
+            run_until(&mut wheel, origin, ms(10_000)),
+            vec![(2, ms(5_000))]
+        );
+        assert!(wheel.next_deadline().is_none());
+    }
+
+    #[test]
+    fn past_deadlines_fire_on_the_next_tick() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::new(origin);
+        wheel.advance(origin + ms(50));
+        wheel.schedule(1, origin + ms(10));
+
+        assert_eq!(wheel.next_deadline(), Some(origin + ms(51)));
+        assert_eq!(wheel.advance(origin + ms(51)), vec![1]);
+    }
+}
@@ -0,0 +1,590 @@
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::tcb::Quad;
+
+/// How much an event matters. A `Tracer` passes on events at or above its level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    /// Every frame sent or received
+    Trace,
+    /// Per-segment protocol detail: RTT samples, probes, challenge ACKs
+    Debug,
+    /// Connection lifecycle: state changes, retransmissions, resets
+    Info,
+    /// Connections given up on and datagrams dropped as invalid
+    Warn,
+    /// Failures of the device or of the stack's own output
+    Error,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "trace" => Ok(Level::Trace),
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(format!("unknown level '{}'", name)),
+        }
+    }
+}
+
+/// What an event reports; each kind has a fixed level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// A frame was received or sent: `direction`, `decode`
+    Packet,
+    /// A segment reached the state machine: `flags`, `seq`, `ack`
+    Segment,
+    /// A SYN-ACK answered a SYN: `seq`, `ack`
+    SynAck,
+    /// A challenge ACK (RFC 5961) was sent
+    ChallengeAck,
+    /// A new round-trip time sample: `measured_ms`, `srtt_ms`, `rttvar_ms`, `rto_ms`
+    RttUpdate,
+    /// A zero window probe was sent: `seq`, `attempt`
+    WindowProbe,
+    /// A keep-alive probe was sent: `seq`, `attempt`
+    KeepAlive,
+    /// The connection moved between states: `from`, `to`
+    StateChange,
+    /// A segment was retransmitted: `seq`, `attempt`
+    Retransmit,
+    /// The retransmission timer expired: `ssthresh`, `cwnd`, `consecutive_timeouts`
+    Timeout,
+    /// The peer reset the connection
+    Reset,
+    /// An ICMP destination unreachable aborted the connection: `code`
+    Unreachable,
+    /// The SYN backlog was full and a SYN was answered with a cookie
+    SynCookieSent,
+    /// An ACK carried a valid SYN cookie: `mss`
+    SynCookieAccepted,
+    /// A SYN reopened a connection in TIME-WAIT
+    TimeWaitReused,
+    /// A connection was dropped from a full TIME-WAIT table
+    TimeWaitEvicted,
+    /// The connection was removed from the table: `state`, and `error` if aborted
+    Closed,
+    /// Retransmissions or probes went unanswered: `seq`, `reason`
+    GiveUp,
+    /// A datagram failed its checksum: `layer`
+    ChecksumError,
+    /// A datagram's headers or lengths were invalid: `error`
+    Malformed,
+    /// The capture file could not be written and was closed: `error`
+    CaptureError,
+    /// The device failed to send or receive: `operation`, `error`
+    DeviceError,
+    /// A segment did not fit the transmit buffer and was dropped: `seq`, `error`
+    TransmitError,
+}
+
+impl EventKind {
+    pub fn level(&self) -> Level {
+        match self {
+            EventKind::Packet => Level::Trace,
+            EventKind::Segment
+            | EventKind::SynAck
+            | EventKind::ChallengeAck
+            | EventKind::RttUpdate
+            | EventKind::WindowProbe
+            | EventKind::KeepAlive => Level::Debug,
+            EventKind::StateChange
+            | EventKind::Retransmit
+            | EventKind::Timeout
+            | EventKind::Reset
+            | EventKind::Unreachable
+            | EventKind::SynCookieSent
+            | EventKind::SynCookieAccepted
+            | EventKind::TimeWaitReused
+            | EventKind::TimeWaitEvicted
+            | EventKind::Closed => Level::Info,
+            EventKind::GiveUp | EventKind::ChecksumError | EventKind::Malformed => Level::Warn,
+            EventKind::CaptureError | EventKind::DeviceError | EventKind::TransmitError => {
+                Level::Error
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Packet => "packet",
+            EventKind::Segment => "segment",
+            EventKind::SynAck => "syn_ack",
+            EventKind::ChallengeAck => "challenge_ack",
+            EventKind::RttUpdate => "rtt_update",
+            EventKind::WindowProbe => "window_probe",
+            EventKind::KeepAlive => "keepalive",
+            EventKind::StateChange => "state_change",
+            EventKind::Retransmit => "retransmit",
+            EventKind::Timeout => "timeout",
+            EventKind::Reset => "reset",
+            EventKind::Unreachable => "unreachable",
+            EventKind::SynCookieSent => "syn_cookie_sent",
+            EventKind::SynCookieAccepted => "syn_cookie_accepted",
+            EventKind::TimeWaitReused => "time_wait_reused",
+            EventKind::TimeWaitEvicted => "time_wait_evicted",
+            EventKind::Closed => "closed",
+            EventKind::GiveUp => "give_up",
+            EventKind::ChecksumError => "checksum_error",
+            EventKind::Malformed => "malformed",
+            EventKind::CaptureError => "capture_error",
+            EventKind::DeviceError => "device_error",
+            EventKind::TransmitError => "transmit_error",
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// Value of an event field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Unsigned(u64),
+    Bool(bool),
+    Text(String),
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        Value::Unsigned(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::Unsigned(value as u64)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Self {
+        Value::Unsigned(value as u64)
+    }
+}
+
+impl From<u8> for Value {
+    fn from(value: u8) -> Self {
+        Value::Unsigned(value as u64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Self {
+        Value::Unsigned(value as u64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+/// Something the stack did or saw, with the connection it concerns and
+/// named fields
+///
+/// ```text
+/// Event::new(EventKind::Retransmit).connection(quad).field("seq", seq).field("attempt", attempt)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub connection: Option<Quad>,
+    pub fields: Vec<(&'static str, Value)>,
+}
+
+impl Event {
+    pub fn new(kind: EventKind) -> Self {
+        Self {
+            kind,
+            connection: None,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn connection(mut self, quad: Quad) -> Self {
+        self.connection = Some(quad);
+        self
+    }
+
+    pub fn field(mut self, name: &'static str, value: impl Into<Value>) -> Self {
+        self.fields.push((name, value.into()));
+        self
+    }
+
+    pub fn level(&self) -> Level {
+        self.kind.level()
+    }
+}
+
+/// Where a `Tracer` sends events
+pub trait Sink: fmt::Debug + Send {
+    /// Write one event that happened at `time`
+    fn record(&mut self, time: SystemTime, event: &Event) -> io::Result<()>;
+}
+
+/// Passes the events at or above a level to a sink.
+///
+/// Events are stamped with wall-clock time derived from the stack's clock,
+/// as captures are, so traces of virtual-time runs keep their spacing.
+#[derive(Debug)]
+pub struct Tracer {
+    sink: Option<Box<dyn Sink>>,
+    level: Level,
+    origin: Option<(Instant, SystemTime)>,
+}
+
+impl Default for Tracer {
+    /// Human-readable lines on stdout at `Info`
+    fn default() -> Self {
+        Self::new(TextSink::stdout(), Level::Info)
+    }
+}
+
+impl Tracer {
+    pub fn new(sink: impl Sink + 'static, level: Level) -> Self {
+        Self {
+            sink: Some(Box::new(sink)),
+            level,
+            origin: None,
+        }
+    }
+
+    /// A tracer that drops every event
+    pub fn disabled() -> Self {
+        Self {
+            sink: None,
+            level: Level::Error,
+            origin: None,
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: Level) {
+        self.level = level;
+    }
+
+    /// Whether events at `level` reach the sink; check before building
+    /// costly events
+    pub fn enabled(&self, level: Level) -> bool {
+        self.sink.is_some() && level >= self.level
+    }
+
+    /// Send an event that happened at `now` to the sink, if its level
+    /// passes. A sink that fails to write is dropped and its error
+    /// returned, so tracing stops without failing the stack.
+    pub fn emit(&mut self, now: Instant, event: Event) -> io::Result<()> {
+        if !self.enabled(event.level()) {
+            return Ok(());
+        }
+        let Some(sink) = &mut self.sink else {
+            return Ok(());
+        };
+
+        let (origin, time) = *self.origin.get_or_insert_with(|| (now, SystemTime::now()));
+        let time = match now.checked_duration_since(origin) {
+            Some(elapsed) => time + elapsed,
+            None => time - origin.duration_since(now),
+        };
+        let result = sink.record(time, &event);
+        if result.is_err() {
+            self.sink = None;
+        }
+        result
+    }
+}
+
+/// Writes events as human-readable lines:
+///
+/// ```text
+/// info  retransmit local=10.0.0.2:80 remote=10.0.0.1:5000 seq=1000 attempt=1
+/// ```
+///
+/// Packet events print their tcpdump-style decode as is.
+pub struct TextSink {
+    writer: Box<dyn Write + Send>,
+}
+
+impl fmt::Debug for TextSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TextSink").finish_non_exhaustive()
+    }
+}
+
+impl TextSink {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+        }
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl Sink for TextSink {
+    fn record(&mut self, _time: SystemTime, event: &Event) -> io::Result<()> {
+        let mut line = String::new();
+        if let (EventKind::Packet, Some((_, Value::Text(decode)))) = (
+            event.kind,
+            event.fields.iter().find(|(name, _)| *name == "decode"),
+        ) {
+            line.push_str(decode);
+        } else {
+            let _ = write!(line, "{:<5} {}", event.level(), event.kind);
+            if let Some(quad) = event.connection {
+                let _ = write!(
+                    line,
+                    " local={}:{} remote={}:{}",
+                    quad.dst.0, quad.dst.1, quad.src.0, quad.src.1
+                );
+            }
+            for (name, value) in &event.fields {
+                let _ = match value {
+                    Value::Unsigned(value) => write!(line, " {}={}", name, value),
+                    Value::Bool(value) => write!(line, " {}={}", name, value),
+                    Value::Text(value)
+                        if value.contains(char::is_whitespace) || value.is_empty() =>
+                    {
+                        write!(line, " {}={:?}", name, value)
+                    }
+                    Value::Text(value) => write!(line, " {}={}", name, value),
+                };
+            }
+        }
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())
+    }
+}
+
+/// Writes each event as one JSON object per line, for post-processing
+/// with `jq` and the like:
+///
+/// ```text
+/// {"time":1792349686.858312,"level":"info","kind":"retransmit","local":"10.0.0.2:80","remote":"10.0.0.1:5000","seq":1000,"attempt":1}
+/// ```
+pub struct JsonSink {
+    writer: Box<dyn Write + Send>,
+}
+
+impl fmt::Debug for JsonSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonSink").finish_non_exhaustive()
+    }
+}
+
+impl JsonSink {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+        }
+    }
+
+    /// Write events to a file, replacing it
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl Sink for JsonSink {
+    fn record(&mut self, time: SystemTime, event: &Event) -> io::Result<()> {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        let mut line = format!(
+            "{{\"time\":{}.{:06},\"level\":\"{}\",\"kind\":\"{}\"",
+            time.as_secs(),
+            time.subsec_micros(),
+            event.level(),
+            event.kind
+        );
+        if let Some(quad) = event.connection {
+            let _ = write!(
+                line,
+                ",\"local\":\"{}:{}\",\"remote\":\"{}:{}\"",
+                quad.dst.0, quad.dst.1, quad.src.0, quad.src.1
+            );
+        }
+        for (name, value) in &event.fields {
+            let _ = write!(line, ",\"{}\":", name);
+            match value {
+                Value::Unsigned(value) => {
+                    let _ = write!(line, "{}", value);
+                }
+                Value::Bool(value) => {
+                    let _ = write!(line, "{}", value);
+                }
+                Value::Text(value) => push_json_string(&mut line, value),
+            }
+        }
+        line.push_str("}\n");
+
+        // Flushed per event so a crash leaves every line before it readable
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.flush()
+    }
+}
+
+fn push_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::pcap::tests::SharedFile;
+    use crate::stack::Stack;
+
+    fn quad() -> Quad {
+        Quad {
+            src: (Ipv4Addr::new(10, 0, 0, 1), 5000),
+            dst: (Ipv4Addr::new(10, 0, 0, 2), 80),
+        }
+    }
+
+    fn written(file: &SharedFile) -> String {
+        String::from_utf8(file.0.lock().unwrap().clone()).unwrap()
+    }
+
+    /// Fails every write
+    #[derive(Debug)]
+    struct BrokenSink;
+
+    impl Sink for BrokenSink {
+        fn record(&mut self, _time: SystemTime, _event: &Event) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+    }
+
+    #[test]
+    fn json_sink_writes_one_object_per_line() {
+        let file = SharedFile::default();
+        let mut sink = JsonSink::new(file.clone());
+        let time = UNIX_EPOCH + Duration::from_micros(1_792_349_686_858_312);
+
+        let retransmit = Event::new(EventKind::Retransmit)
+            .connection(quad())
+            .field("seq", 1000u32)
+            .field("attempt", 1u32);
+        sink.record(time, &retransmit).unwrap();
+        let malformed = Event::new(EventKind::Malformed)
+            .field("error", "bad \"header\"\n\t\\\u{1}")
+            .field("dropped", true);
+        sink.record(time, &malformed).unwrap();
+
+        assert_eq!(
+            written(&file),
+            concat!(
+                r#"{"time":1792349686.858312,"level":"info","kind":"retransmit","local":"10.0.0.2:80","remote":"10.0.0.1:5000","seq":1000,"attempt":1}"#,
+                "\n",
+                r#"{"time":1792349686.858312,"level":"warn","kind":"malformed","error":"bad \"header\"\n\t\\\u0001","dropped":true}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn tracer_passes_events_at_or_above_its_level() {
+        let file = SharedFile::default();
+        let mut tracer = Tracer::new(TextSink::new(file.clone()), Level::Warn);
+        let now = Instant::now();
+        assert!(!tracer.enabled(Level::Info));
+        assert!(tracer.enabled(Level::Warn));
+
+        tracer.emit(now, Event::new(EventKind::Retransmit)).unwrap();
+        tracer.emit(now, Event::new(EventKind::GiveUp)).unwrap();
+        tracer
+            .emit(now, Event::new(EventKind::DeviceError))
+            .unwrap();
+        assert_eq!(written(&file), "warn  give_up\nerror device_error\n");
+
+        tracer.set_level(Level::Debug);
+        tracer.emit(now, Event::new(EventKind::RttUpdate)).unwrap();
+        tracer
+            .emit(now, Event::new(EventKind::Packet).field("decode", "in"))
+            .unwrap();
+        assert!(written(&file).ends_with("error device_error\ndebug rtt_update\n"));
+
+        assert!(!Tracer::disabled().enabled(Level::Error));
+    }
+
+    #[test]
+    fn failing_sink_is_dropped_and_its_error_returned() {
+        let mut tracer = Tracer::new(BrokenSink, Level::Trace);
+        let now = Instant::now();
+        let error = tracer.emit(now, Event::new(EventKind::Reset)).unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+        assert!(!tracer.enabled(Level::Error));
+        assert!(tracer.emit(now, Event::new(EventKind::Reset)).is_ok());
+
+        // The stack keeps the error until asked for it
+        let mut stack = Stack::new();
+        stack.set_tracer(Tracer::new(BrokenSink, Level::Trace));
+        stack.trace(Event::new(EventKind::Reset));
+        stack.trace(Event::new(EventKind::Reset));
+        assert_eq!(stack.take_trace_error().unwrap().to_string(), "disk full");
+        assert!(stack.take_trace_error().is_none());
+    }
+}
@@ -0,0 +1,266 @@
+//! Property tests checking our framing against etherparse: frames built by
+//! `Packet::create_packet` must decode to the same fields with valid
+//! checksums, and `parser` must agree with etherparse on frames it builds.
+
+use std::net::Ipv4Addr;
+
+use etherparse::{
+    IpFragOffset, IpHeaders, IpNumber, Ipv4Dscp, Ipv4Ecn, Ipv4Header, PacketBuilder, TcpHeader,
+    TcpOptionElement,
+};
+use proptest::prelude::*;
+use tcp::options::TcpOptions;
+use tcp::packet_sender::MAX_FRAME;
+use tcp::parser::{self, IPHeader, Ipv4View, Packet, TCPHeader, TcpView, MAX_PAYLOAD};
+
+/// Options that fit in the 40 bytes of option space
+fn tcp_options() -> impl Strategy<Value = TcpOptions> {
+    (
+        any::<Option<u16>>(),
+        proptest::option::of(0u8..=14),
+        any::<bool>(),
+        proptest::collection::vec(any::<(u32, u32)>(), 0..=4),
+        any::<Option<(u32, u32)>>(),
+    )
+        .prop_map(
+            |(mss, window_scale, sack_permitted, sack_blocks, timestamps)| TcpOptions {
+                mss,
+                window_scale,
+                sack_permitted,
+                sack_blocks,
+                timestamps,
+            },
+        )
+        .prop_filter("options exceed 40 bytes", |options| {
+            options.encode().len() <= 40
+        })
+}
+
+prop_compose! {
+    fn packet()(
+        type_of_service: u8,
+        identification: u16,
+        flags in 0u8..4,
+        fragment_offset in 0u16..0x2000,
+        ttl: u8,
+        source: [u8; 4],
+        destination: [u8; 4],
+        source_port: u16,
+        destination_port: u16,
+        sequence_number: u32,
+        acknowledge_number: u32,
+        control_bit: u8,
+        window: u16,
+        urgent_pointer: u16,
+        options in tcp_options(),
+        payload in proptest::collection::vec(any::<u8>(), 0..=MAX_PAYLOAD - 40),
+    ) -> (Packet, Vec<u8>) {
+        let options_len = options.encode().len();
+        let mut data = [0u8; MAX_PAYLOAD];
+        data[..payload.len()].copy_from_slice(&payload);
+
+        let packet = Packet {
+            ip_header: IPHeader {
+                version: 4,
+                ihl: 5,
+                type_of_service,
+                total_len: (40 + options_len + payload.len()) as u16,
+                identification,
+                flags,
+                fragment_offset,
+                ttl,
+                protocol: 6,
+                header_checksum: 0,
+                source: Ipv4Addr::from(source),
+                destination: Ipv4Addr::from(destination),
+            },
+            tcp_header: TCPHeader {
+                source_port,
+                destination_port,
+                sequence_number,
+                acknowledge_number,
+                data_offset: (5 + options_len / 4) as u8,
+                reserved: 0,
+                control_bit,
+                window,
+                checksum: 0,
+                urgent_pointer,
+                options,
+            },
+            data,
+        };
+        (packet, payload)
+    }
+}
+
+/// Control bits of an etherparse header in the layout of byte 13
+fn control_bits(tcp: &TcpHeader) -> u8 {
+    [
+        tcp.fin, tcp.syn, tcp.rst, tcp.psh, tcp.ack, tcp.urg, tcp.ece, tcp.cwr,
+    ]
+    .iter()
+    .enumerate()
+    .map(|(bit, &set)| (set as u8) << bit)
+    .sum()
+}
+
+/// Our view of the options etherparse decoded
+fn decoded_options(tcp: &TcpHeader) -> TcpOptions {
+    let mut options = TcpOptions::default();
+    for element in tcp.options_iterator() {
+        match element.expect("etherparse rejected the options") {
+            TcpOptionElement::Noop => {}
+            TcpOptionElement::MaximumSegmentSize(mss) => options.mss = Some(mss),
+            TcpOptionElement::WindowScale(shift) => options.window_scale = Some(shift),
+            TcpOptionElement::SelectiveAcknowledgementPermitted => options.sack_permitted = true,
+            TcpOptionElement::SelectiveAcknowledgement(first, rest) => {
+                options.sack_blocks = std::iter::once(first)
+                    .chain(rest.into_iter().flatten())
+                    .collect();
+            }
+            TcpOptionElement::Timestamp(tsval, tsecr) => options.timestamps = Some((tsval, tsecr)),
+        }
+    }
+    options
+}
+
+/// Options in the form etherparse builds them
+fn option_elements(options: &TcpOptions) -> Vec<TcpOptionElement> {
+    let mut elements = Vec::new();
+    if let Some(mss) = options.mss {
+        elements.push(TcpOptionElement::MaximumSegmentSize(mss));
+    }
+    if let Some(shift) = options.window_scale {
+        elements.push(TcpOptionElement::WindowScale(shift));
+    }
+    if options.sack_permitted {
+        elements.push(TcpOptionElement::SelectiveAcknowledgementPermitted);
+    }
+    if let Some((&first, rest)) = options.sack_blocks.split_first() {
+        let mut more = [None; 3];
+        for (slot, &block) in more.iter_mut().zip(rest) {
+            *slot = Some(block);
+        }
+        elements.push(TcpOptionElement::SelectiveAcknowledgement(first, more));
+    }
+    if let Some((tsval, tsecr)) = options.timestamps {
+        elements.push(TcpOptionElement::Timestamp(tsval, tsecr));
+    }
+    elements
+}
+
+proptest! {
+    #[test]
+    fn create_packet_decodes_with_etherparse((packet, payload) in packet()) {
+        let mut frame = [0u8; MAX_FRAME];
+        let len = packet.create_packet(&mut frame).unwrap();
+        prop_assert_eq!(len, 4 + packet.ip_header.total_len as usize);
+        prop_assert_eq!(&frame[..4], &[0, 0, 0x08, 0x00]);
+
+        let datagram = &frame[4..len];
+        let (ip, segment) = Ipv4Header::from_slice(datagram).unwrap();
+        let (tcp, data) = TcpHeader::from_slice(segment).unwrap();
+
+        let ours = &packet.ip_header;
+        prop_assert_eq!(ip.dscp.value(), ours.type_of_service >> 2);
+        prop_assert_eq!(ip.ecn.value(), ours.type_of_service & 0x03);
+        prop_assert_eq!(ip.total_len, ours.total_len);
+        prop_assert_eq!(ip.identification, ours.identification);
+        prop_assert_eq!(ip.dont_fragment, ours.flags & 0x02 != 0);
+        prop_assert_eq!(ip.more_fragments, ours.flags & 0x01 != 0);
+        prop_assert_eq!(ip.fragment_offset.value(), ours.fragment_offset);
+        prop_assert_eq!(ip.time_to_live, ours.ttl);
+        prop_assert_eq!(ip.protocol, IpNumber::TCP);
+        prop_assert_eq!(Ipv4Addr::from(ip.source), ours.source);
+        prop_assert_eq!(Ipv4Addr::from(ip.destination), ours.destination);
+        prop_assert_eq!(ip.header_checksum, ip.calc_header_checksum());
+
+        let ours = &packet.tcp_header;
+        prop_assert_eq!(tcp.source_port, ours.source_port);
+        prop_assert_eq!(tcp.destination_port, ours.destination_port);
+        prop_assert_eq!(tcp.sequence_number, ours.sequence_number);
+        prop_assert_eq!(tcp.acknowledgment_number, ours.acknowledge_number);
+        prop_assert_eq!(tcp.header_len(), ours.data_offset as usize * 4);
+        prop_assert_eq!(control_bits(&tcp), ours.control_bit);
+        prop_assert_eq!(tcp.window_size, ours.window);
+        prop_assert_eq!(tcp.urgent_pointer, ours.urgent_pointer);
+        prop_assert_eq!(decoded_options(&tcp), ours.options.clone());
+        prop_assert_eq!(tcp.checksum, tcp.calc_checksum_ipv4(&ip, data).unwrap());
+        prop_assert_eq!(data, &payload[..]);
+    }
+
+    #[test]
+    fn parser_agrees_with_etherparse_frames(
+        (packet, payload) in packet(),
+        ns: bool,
+    ) {
+        let ours = &packet.ip_header;
+        let ip = Ipv4Header {
+            dscp: Ipv4Dscp::try_new(ours.type_of_service >> 2).unwrap(),
+            ecn: Ipv4Ecn::try_new(ours.type_of_service & 0x03).unwrap(),
+            identification: ours.identification,
+            dont_fragment: ours.flags & 0x02 != 0,
+            more_fragments: ours.flags & 0x01 != 0,
+            fragment_offset: IpFragOffset::try_new(ours.fragment_offset).unwrap(),
+            time_to_live: ours.ttl,
+            source: ours.source.octets(),
+            destination: ours.destination.octets(),
+            ..Default::default()
+        };
+
+        let ours = &packet.tcp_header;
+        let mut tcp = TcpHeader::new(ours.source_port, ours.destination_port, ours.sequence_number, ours.window);
+        tcp.acknowledgment_number = ours.acknowledge_number;
+        tcp.ns = ns;
+        tcp.fin = ours.control_bit & 0x01 != 0;
+        tcp.syn = ours.control_bit & 0x02 != 0;
+        tcp.rst = ours.control_bit & 0x04 != 0;
+        tcp.psh = ours.control_bit & 0x08 != 0;
+        tcp.ack = ours.control_bit & 0x10 != 0;
+        tcp.urg = ours.control_bit & 0x20 != 0;
+        tcp.ece = ours.control_bit & 0x40 != 0;
+        tcp.cwr = ours.control_bit & 0x80 != 0;
+        tcp.urgent_pointer = ours.urgent_pointer;
+        tcp.set_options(&option_elements(&ours.options)).unwrap();
+
+        let mut datagram = Vec::new();
+        PacketBuilder::ip(IpHeaders::Ipv4(ip.clone(), Default::default()))
+            .tcp_header(tcp.clone())
+            .write(&mut datagram, &payload)
+            .unwrap();
+
+        prop_assert_eq!(parser::verify_checksums(&datagram), Ok(()));
+
+        let parsed = parser::parser(&datagram).unwrap();
+        prop_assert_eq!(parsed.ip_header.version, 4);
+        prop_assert_eq!(parsed.ip_header.type_of_service, packet.ip_header.type_of_service);
+        prop_assert_eq!(parsed.ip_header.total_len as usize, datagram.len());
+        prop_assert_eq!(parsed.ip_header.identification, packet.ip_header.identification);
+        prop_assert_eq!(parsed.ip_header.flags, packet.ip_header.flags);
+        prop_assert_eq!(parsed.ip_header.fragment_offset, packet.ip_header.fragment_offset);
+        prop_assert_eq!(parsed.ip_header.ttl, packet.ip_header.ttl);
+        prop_assert_eq!(parsed.ip_header.protocol, 6);
+        prop_assert_eq!(parsed.ip_header.source, packet.ip_header.source);
+        prop_assert_eq!(parsed.ip_header.destination, packet.ip_header.destination);
+
+        prop_assert_eq!(parsed.tcp_header.source_port, ours.source_port);
+        prop_assert_eq!(parsed.tcp_header.destination_port, ours.destination_port);
+        prop_assert_eq!(parsed.tcp_header.sequence_number, ours.sequence_number);
+        prop_assert_eq!(parsed.tcp_header.acknowledge_number, ours.acknowledge_number);
+        prop_assert_eq!(parsed.tcp_header.data_offset as usize * 4, tcp.header_len());
+        prop_assert_eq!(parsed.tcp_header.reserved, ns as u8);
+        prop_assert_eq!(parsed.tcp_header.control_bit, ours.control_bit);
+        prop_assert_eq!(parsed.tcp_header.window, ours.window);
+        prop_assert_eq!(parsed.tcp_header.checksum, tcp.calc_checksum_ipv4(&ip, &payload).unwrap());
+        prop_assert_eq!(parsed.tcp_header.urgent_pointer, ours.urgent_pointer);
+        prop_assert_eq!(&parsed.tcp_header.options, &ours.options);
+        prop_assert_eq!(parsed.payload(), &payload[..]);
+
+        // The views read the same fields in place
+        let view = Ipv4View::new(&datagram).unwrap();
+        let segment = TcpView::new(view.payload()).unwrap();
+        prop_assert_eq!(segment.sequence_number(), ours.sequence_number);
+        prop_assert_eq!(segment.options(), ours.options.clone());
+        prop_assert_eq!(segment.payload(), &payload[..]);
+    }
+}
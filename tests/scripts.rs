@@ -0,0 +1,34 @@
+//! Runs the packetdrill-style scripts in `tests/scripts` against the stack
+
+use std::fs;
+use std::path::Path;
+
+use tcp::script;
+
+#[test]
+fn scripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "pkt"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scripts in {}", dir.display());
+
+    let failures: Vec<String> = paths
+        .iter()
+        .filter_map(|path| {
+            let text = fs::read_to_string(path).unwrap();
+            script::run(&text)
+                .err()
+                .map(|error| format!("{}: {}", path.display(), error))
+        })
+        .collect();
+    assert!(
+        failures.is_empty(),
+        "{} script(s) failed:\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}